@@ -2,7 +2,7 @@ extern crate ntcp;
 
 use std::collections::HashMap;
 use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::net::{Shutdown, TcpListener, TcpStream};
 use std::sync::mpsc;
 use std::thread;
 
@@ -21,7 +21,6 @@ fn create_client(mut tcp: TcpStream, tx: mpsc::Sender<Vec<u8>>, rx: mpsc::Receiv
         .connect(tcp::Endpoint::new(ipv4::Address::from_bytes(&[127, 0, 0, 1]), 6969))
         .unwrap();
 
-    let endpoint = socket.endpoint;
     let (socket_tx, socket_rx) = socket.to_tx_rx();
 
     {
@@ -30,18 +29,21 @@ fn create_client(mut tcp: TcpStream, tx: mpsc::Sender<Vec<u8>>, rx: mpsc::Receiv
                           let mut buf = [0; 17000];
                           let len = tcp.read(&mut buf).unwrap();
                           if len == 0 {
+                              socket_tx.send(PacketBuffer::fin(&[])).unwrap();
                               break;
                           }
                           println!("Sent: {:?}", len);
-                          socket_tx
-                              .send((endpoint, PacketBuffer::new(&buf[..len])))
-                              .unwrap();
+                          socket_tx.send(PacketBuffer::new(&buf[..len])).unwrap();
                       });
     }
     thread::spawn(move || loop {
                       let packet = socket_rx.recv().unwrap();
                       println!("Recieved: {:?}", packet.payload.len());
                       tcp.write_all(&(*packet.payload)).unwrap();
+                      if packet.fin {
+                          tcp.shutdown(Shutdown::Write).unwrap();
+                          break;
+                      }
                   });
 }
 
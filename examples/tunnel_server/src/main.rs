@@ -2,7 +2,7 @@ extern crate ntcp;
 
 use std::io;
 use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::net::{Shutdown, TcpStream};
 use std::sync::mpsc;
 use std::thread;
 
@@ -15,7 +15,7 @@ fn create_server() -> thread::JoinHandle<()> {
     let raw = RawSocket::new().unwrap();
     let endpoint = tcp::Endpoint::new(ipv4::Address::default(), 6969);
     let mut interface = SocketInterface::new(endpoint, raw);
-    let (tx, rx) = mpsc::channel();
+    let (tx, rx) = mpsc::sync_channel(128);
     interface.listen(tx);
 
     thread::spawn(move || {
@@ -27,7 +27,6 @@ fn create_server() -> thread::JoinHandle<()> {
                 Err(_) => break,
             };
             println!("Connection established with: {:?}", socket.endpoint);
-            let endpoint = socket.endpoint;
             let (tx, rx) = socket.to_tx_rx();
 
             let mut tcp = TcpStream::connect("127.0.0.1:25565").unwrap();
@@ -41,6 +40,10 @@ fn create_server() -> thread::JoinHandle<()> {
 
                                   println!("Server recieved: {:?}", buf.payload.len());
                                   tcp.write_all(&*buf.payload).unwrap();
+                                  if buf.fin {
+                                      tcp.shutdown(Shutdown::Write).unwrap();
+                                      break;
+                                  }
                               });
             }
             {
@@ -49,8 +52,13 @@ fn create_server() -> thread::JoinHandle<()> {
                                   let mut buf = [0; 17000];
                                   loop {
                                       let len = tcp.read(&mut buf).unwrap();
+                                      if len == 0 {
+                                          // Upstream EOF, half-close towards the client
+                                          tx.send(PacketBuffer::fin(&[])).unwrap();
+                                          break;
+                                      }
                                       println!("Server sent: {:?}", len);
-                                      tx.send((endpoint, PacketBuffer::new(&buf[..len])));
+                                      tx.send(PacketBuffer::new(&buf[..len]));
                                   }
                               });
             }
@@ -31,7 +31,7 @@ fn create_client(raw: platform::RawSocket) {
 fn create_server(raw: platform::RawSocket) -> thread::JoinHandle<()> {
     let endpoint = tcp::Endpoint::new(ipv4::Address::default(), 6969);
     let mut interface = socket::SocketInterface::new(endpoint, raw);
-    let (tx, rx) = mpsc::channel();
+    let (tx, rx) = mpsc::sync_channel(128);
     interface.listen(tx);
 
     thread::spawn(move || {
@@ -6,10 +6,16 @@ pub enum Error {
     Fragmented,
     UnknownProtocol,
     Checksum,
+    /// TCP MD5 signature (RFC 2385) or TCP-AO MAC (RFC 5925) missing, unexpected or wrong
+    Signature,
 }
 
 #[derive(Debug)]
 pub enum SocketError {
     Closed,
     Timeout,
+    /// The 4-tuple is still taken, for example by a connection in TIME-WAIT
+    InUse,
+    /// The memory budget of the interface is exhausted
+    NoMemory,
 }
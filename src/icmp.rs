@@ -0,0 +1,144 @@
+use super::error::Error;
+use super::ipv4;
+
+use std::fmt;
+
+use byteorder::{ByteOrder, NetworkEndian};
+
+const TYPE_DST_UNREACHABLE: u8 = 3;
+
+/// Destination Unreachable code for "Fragmentation Needed and DF Set"
+pub const CODE_FRAG_NEEDED: u8 = 4;
+
+pub struct Packet<T: AsRef<[u8]>> {
+    buffer: T,
+}
+
+mod field {
+    type Field = ::core::ops::Range<usize>;
+
+    pub const TYPE: usize = 0;
+    pub const CODE: usize = 1;
+    pub const CHECKSUM: Field = 2..4;
+    pub const NEXT_HOP_MTU: Field = 6..8;
+}
+
+impl<T: AsRef<[u8]>> Packet<T> {
+    pub fn new(buffer: T) -> Result<Self, Error> {
+        let len = buffer.as_ref().len();
+        if len < field::NEXT_HOP_MTU.end {
+            Err(Error::Truncated)
+        } else {
+            Ok(Packet { buffer })
+        }
+    }
+
+    #[inline]
+    pub fn msg_type(&self) -> u8 {
+        let buf = self.buffer.as_ref();
+        buf[field::TYPE]
+    }
+
+    #[inline]
+    pub fn msg_code(&self) -> u8 {
+        let buf = self.buffer.as_ref();
+        buf[field::CODE]
+    }
+
+    #[inline]
+    pub fn checksum(&self) -> u16 {
+        let buf = self.buffer.as_ref();
+        NetworkEndian::read_u16(&buf[field::CHECKSUM])
+    }
+
+    /// Next-hop MTU of a Fragmentation Needed message (RFC 1191), zero from old routers
+    #[inline]
+    pub fn next_hop_mtu(&self) -> u16 {
+        let buf = self.buffer.as_ref();
+        NetworkEndian::read_u16(&buf[field::NEXT_HOP_MTU])
+    }
+
+    pub fn checksum_valid(&self) -> bool {
+        use ipv4::checksum;
+        let buf = self.buffer.as_ref();
+        checksum::compute(buf, 0) == 0
+    }
+}
+
+impl<'a, T: AsRef<[u8]> + ?Sized> Packet<&'a T> {
+    /// The leading part of the datagram that triggered an error message
+    #[inline]
+    pub fn payload(&self) -> &'a [u8] {
+        let buf = self.buffer.as_ref();
+        &buf[field::NEXT_HOP_MTU.end..]
+    }
+}
+
+impl<T: AsRef<[u8]>> fmt::Debug for Packet<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("IcmpPacket")
+            .field("type", &self.msg_type())
+            .field("code", &self.msg_code())
+            .field("checksum", &self.checksum())
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub enum Repr<'a> {
+    DstUnreachable {
+        code: u8,
+        next_hop_mtu: u16,
+        /// Header of the datagram that could not be delivered
+        header: ipv4::Repr,
+        /// Length of that header, options included
+        header_len: usize,
+        /// Leading bytes of that datagram's payload, at least 8 per RFC 792
+        data: &'a [u8],
+    },
+    Other { msg_type: u8, code: u8 },
+}
+
+impl<'a> Repr<'a> {
+    pub fn parse<T: AsRef<[u8]> + ?Sized>(packet: &Packet<&'a T>) -> Result<Self, Error> {
+        if !packet.checksum_valid() {
+            return Err(Error::Checksum);
+        }
+
+        if packet.msg_type() != TYPE_DST_UNREACHABLE {
+            return Ok(Repr::Other {
+                          msg_type: packet.msg_type(),
+                          code: packet.msg_code(),
+                      });
+        }
+
+        // The embedded datagram is truncated, so its header is read field by field
+        // instead of going through ipv4::Repr::parse
+        let payload = packet.payload();
+        if payload.len() < 20 {
+            return Err(Error::Truncated);
+        }
+        let ip = ipv4::Packet::new(payload)?;
+        let header_len = ip.header_len() as usize;
+        if ip.version() != 4 || header_len < 20 || (ip.total_len() as usize) < header_len {
+            return Err(Error::Malformed);
+        }
+        if payload.len() < header_len + 8 {
+            return Err(Error::Truncated);
+        }
+
+        Ok(Repr::DstUnreachable {
+               code: packet.msg_code(),
+               next_hop_mtu: packet.next_hop_mtu(),
+               header: ipv4::Repr {
+                   src_addr: ip.src_addr(),
+                   dst_addr: ip.dst_addr(),
+                   protocol: ipv4::Protocol::from(ip.protocol()),
+                   payload_len: ip.total_len() as usize - header_len,
+                   ecn: ip.ecn(),
+               },
+               header_len,
+               data: &payload[header_len..],
+           })
+    }
+}
@@ -4,8 +4,41 @@ use std::fmt;
 
 use byteorder::{ByteOrder, NetworkEndian};
 
+const ICMP_PROTOCOL: u8 = 1;
 const TCP_PROTOCOL: u8 = 6;
 
+/// ECN codepoints (RFC 3168 section 5)
+pub const ECN_NOT_ECT: u8 = 0b00;
+pub const ECN_ECT0: u8 = 0b10;
+pub const ECN_CE: u8 = 0b11;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Protocol {
+    Icmp,
+    Tcp,
+    Unknown(u8),
+}
+
+impl From<u8> for Protocol {
+    fn from(value: u8) -> Self {
+        match value {
+            ICMP_PROTOCOL => Protocol::Icmp,
+            TCP_PROTOCOL => Protocol::Tcp,
+            other => Protocol::Unknown(other),
+        }
+    }
+}
+
+impl From<Protocol> for u8 {
+    fn from(value: Protocol) -> Self {
+        match value {
+            Protocol::Icmp => ICMP_PROTOCOL,
+            Protocol::Tcp => TCP_PROTOCOL,
+            Protocol::Unknown(other) => other,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
 pub struct Address([u8; 4]);
 
@@ -46,12 +79,12 @@ mod field {
 
 impl<T: AsRef<[u8]>> Packet<T> {
     pub fn new(buffer: T) -> Result<Self, Error> {
-        return Ok(Packet { buffer: buffer });
+        return Ok(Packet { buffer });
         let len = buffer.as_ref().len();
         if len < field::DST_ADDR.end {
             Err(Error::Truncated)
         } else {
-            let packet = Packet { buffer: buffer };
+            let packet = Packet { buffer };
             if len < packet.header_len() as usize {
                 Err(Error::Truncated)
             } else {
@@ -275,7 +308,10 @@ impl<T: AsRef<[u8]>> fmt::Debug for Packet<T> {
 pub struct Repr {
     pub src_addr: Address,
     pub dst_addr: Address,
+    pub protocol: Protocol,
     pub payload_len: usize,
+    /// ECN codepoint
+    pub ecn: u8,
 }
 
 impl Repr {
@@ -284,7 +320,8 @@ impl Repr {
             return Err(Error::Malformed);
         }
 
-        if packet.protocol() != TCP_PROTOCOL {
+        let protocol = Protocol::from(packet.protocol());
+        if let Protocol::Unknown(_) = protocol {
             return Err(Error::UnknownProtocol);
         }
 
@@ -308,7 +345,9 @@ impl Repr {
         Ok(Repr {
                src_addr: packet.src_addr(),
                dst_addr: packet.dst_addr(),
-               payload_len: payload_len,
+               protocol,
+               payload_len,
+               ecn: packet.ecn(),
            })
     }
 
@@ -316,14 +355,14 @@ impl Repr {
         packet.set_version(4);
         packet.set_header_len(field::DST_ADDR.end as u8);
         packet.set_dscp(0);
-        packet.set_ecn(0);
+        packet.set_ecn(self.ecn);
         let len = packet.header_len() as u16 + self.payload_len as u16;
         packet.set_total_len(len);
         packet.set_identification(0);
         packet.set_flag_df(true);
         packet.set_flag_mf(false);
         packet.set_ttl(64);
-        packet.set_protocol(TCP_PROTOCOL);
+        packet.set_protocol(self.protocol.into());
         packet.set_src_addr(&self.src_addr);
         packet.set_dst_addr(&self.dst_addr);
     }
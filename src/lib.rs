@@ -1,9 +1,19 @@
+extern crate aes;
 extern crate byteorder;
+extern crate cmac;
 extern crate core;
+extern crate getrandom;
+extern crate hmac;
 extern crate libc;
+extern crate md5;
+extern crate sha1;
+extern crate sha2;
 
 pub mod error;
+pub mod icmp;
 pub mod ipv4;
+pub mod mptcp;
 pub mod tcp;
+pub mod tcp_ao;
 pub mod platform;
 pub mod socket;
@@ -0,0 +1,428 @@
+use super::error::Error;
+use super::ipv4;
+
+use byteorder::{ByteOrder, NetworkEndian};
+use getrandom;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+const SUBTYPE_CAPABLE: u8 = 0;
+const SUBTYPE_JOIN: u8 = 1;
+const SUBTYPE_DSS: u8 = 2;
+const SUBTYPE_ADD_ADDR: u8 = 3;
+
+/// Protocol version spoken, MPTCP v1 (RFC 8684)
+pub const VERSION: u8 = 1;
+
+/// MP_CAPABLE flag asking for DSS checksums (A)
+pub const FLAG_CHECKSUM: u8 = 0x80;
+/// MP_CAPABLE flag selecting HMAC-SHA256 (H)
+pub const FLAG_HMAC_SHA256: u8 = 0x01;
+
+const DSS_DATA_FIN: u8 = 0x10;
+const DSS_DSN64: u8 = 0x08;
+const DSS_MAPPING: u8 = 0x04;
+const DSS_ACK64: u8 = 0x02;
+const DSS_ACK: u8 = 0x01;
+
+const ADD_ADDR_ECHO: u8 = 0x01;
+
+/// Length of the truncated HMAC of an MP_JOIN third ACK
+pub const JOIN_ACK_HMAC_LEN: usize = 20;
+
+/// Data sequence number as carried in a DSS option, possibly only its low 32 bits
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DataSeq {
+    Full(u64),
+    Low(u32),
+}
+
+impl DataSeq {
+    /// The full number, taking the one closest to `near` for truncated numbers
+    pub fn expand(self, near: u64) -> u64 {
+        match self {
+            DataSeq::Full(seq) => seq,
+            DataSeq::Low(low) => {
+                let diff = low.wrapping_sub(near as u32) as i32;
+                near.wrapping_add(diff as i64 as u64)
+            }
+        }
+    }
+
+    fn buffer_len(&self) -> usize {
+        match *self {
+            DataSeq::Full(_) => 8,
+            DataSeq::Low(_) => 4,
+        }
+    }
+
+    fn emit(&self, buffer: &mut [u8]) -> usize {
+        match *self {
+            DataSeq::Full(seq) => NetworkEndian::write_u64(buffer, seq),
+            DataSeq::Low(seq) => NetworkEndian::write_u32(buffer, seq),
+        }
+        self.buffer_len()
+    }
+}
+
+/// Data Sequence Mapping of a DSS option (RFC 8684 section 3.3.1)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mapping {
+    pub data_seq: DataSeq,
+    /// Subflow sequence number relative to the subflow's initial sequence number
+    pub subflow_seq: u32,
+    /// Data-level length, including a DATA_FIN
+    pub len: u16,
+}
+
+/// MPTCP option of the subtypes ntcp understands, others are left as unknown options
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MptcpOption {
+    /// MP_CAPABLE, without keys on a SYN, with the sender's on a SYN-ACK and with both on
+    /// the third ACK
+    Capable {
+        version: u8,
+        flags: u8,
+        sender_key: Option<u64>,
+        receiver_key: Option<u64>,
+    },
+    /// MP_JOIN of a SYN, naming the connection by the receiver's token
+    JoinSyn {
+        backup: bool,
+        address_id: u8,
+        token: u32,
+        nonce: u32,
+    },
+    /// MP_JOIN of a SYN-ACK
+    JoinSynAck {
+        backup: bool,
+        address_id: u8,
+        hmac: u64,
+        nonce: u32,
+    },
+    /// MP_JOIN of the third ACK
+    JoinAck { hmac: [u8; JOIN_ACK_HMAC_LEN] },
+    /// Data Sequence Signal
+    Dss {
+        data_ack: Option<DataSeq>,
+        mapping: Option<Mapping>,
+        data_fin: bool,
+    },
+    /// ADD_ADDR of an IPv4 address, an echo carrying no HMAC
+    AddAddr {
+        echo: bool,
+        address_id: u8,
+        addr: ipv4::Address,
+        port: Option<u16>,
+        hmac: Option<u64>,
+    },
+}
+
+impl MptcpOption {
+    /// Parses the option data following the kind and length, `None` for subtypes and
+    /// address families that aren't supported
+    pub fn parse(data: &[u8]) -> Result<Option<MptcpOption>, Error> {
+        if data.len() < 2 {
+            return Err(Error::Malformed);
+        }
+        let subtype = data[0] >> 4;
+        let len = data.len() + 2;
+
+        let option = match (subtype, len) {
+            (SUBTYPE_CAPABLE, 4) | (SUBTYPE_CAPABLE, 12) | (SUBTYPE_CAPABLE, 20) |
+            (SUBTYPE_CAPABLE, 22) | (SUBTYPE_CAPABLE, 24) => {
+                MptcpOption::Capable {
+                    version: data[0] & 0x0f,
+                    flags: data[1],
+                    sender_key: data.get(2..10).map(NetworkEndian::read_u64),
+                    receiver_key: data.get(10..18).map(NetworkEndian::read_u64),
+                }
+            }
+            (SUBTYPE_JOIN, 12) => {
+                MptcpOption::JoinSyn {
+                    backup: data[0] & 0x01 != 0,
+                    address_id: data[1],
+                    token: NetworkEndian::read_u32(&data[2..6]),
+                    nonce: NetworkEndian::read_u32(&data[6..10]),
+                }
+            }
+            (SUBTYPE_JOIN, 16) => {
+                MptcpOption::JoinSynAck {
+                    backup: data[0] & 0x01 != 0,
+                    address_id: data[1],
+                    hmac: NetworkEndian::read_u64(&data[2..10]),
+                    nonce: NetworkEndian::read_u32(&data[10..14]),
+                }
+            }
+            (SUBTYPE_JOIN, 24) => {
+                let mut hmac = [0; JOIN_ACK_HMAC_LEN];
+                hmac.copy_from_slice(&data[2..]);
+                MptcpOption::JoinAck { hmac }
+            }
+            (SUBTYPE_DSS, _) => Self::parse_dss(data)?,
+            (SUBTYPE_ADD_ADDR, 8) | (SUBTYPE_ADD_ADDR, 10) | (SUBTYPE_ADD_ADDR, 16) |
+            (SUBTYPE_ADD_ADDR, 18) => {
+                let echo = data[0] & ADD_ADDR_ECHO != 0;
+                // Without the echo flag the option ends in a truncated HMAC
+                let (addr_len, hmac) = if echo {
+                    (len - 4, None)
+                } else {
+                    (len - 12, Some(NetworkEndian::read_u64(&data[len - 10..])))
+                };
+                if addr_len != 4 && addr_len != 6 {
+                    return Ok(None);
+                }
+                MptcpOption::AddAddr {
+                    echo,
+                    address_id: data[1],
+                    addr: ipv4::Address::from_bytes(&data[2..6]),
+                    port: if addr_len == 6 {
+                        Some(NetworkEndian::read_u16(&data[6..8]))
+                    } else {
+                        None
+                    },
+                    hmac,
+                }
+            }
+            (SUBTYPE_CAPABLE, _) | (SUBTYPE_JOIN, _) => return Err(Error::Malformed),
+            _ => return Ok(None),
+        };
+
+        Ok(Some(option))
+    }
+
+    fn parse_dss(data: &[u8]) -> Result<MptcpOption, Error> {
+        let flags = data[1];
+        let mut offset = 2;
+        let mut read_seq = |wide: bool| -> Result<DataSeq, Error> {
+            let len = if wide { 8 } else { 4 };
+            let field = data.get(offset..offset + len).ok_or(Error::Malformed)?;
+            offset += len;
+            Ok(if wide {
+                   DataSeq::Full(NetworkEndian::read_u64(field))
+               } else {
+                   DataSeq::Low(NetworkEndian::read_u32(field))
+               })
+        };
+
+        let data_ack = if flags & DSS_ACK != 0 {
+            Some(read_seq(flags & DSS_ACK64 != 0)?)
+        } else {
+            None
+        };
+        let data_seq = if flags & DSS_MAPPING != 0 {
+            Some(read_seq(flags & DSS_DSN64 != 0)?)
+        } else {
+            None
+        };
+        let mapping = match data_seq {
+            Some(data_seq) => {
+                // A checksum may follow when the peer negotiated them
+                let field = data.get(offset..offset + 6).ok_or(Error::Malformed)?;
+                if data.len() != offset + 6 && data.len() != offset + 8 {
+                    return Err(Error::Malformed);
+                }
+                Some(Mapping {
+                         data_seq,
+                         subflow_seq: NetworkEndian::read_u32(&field[..4]),
+                         len: NetworkEndian::read_u16(&field[4..]),
+                     })
+            }
+            None => None,
+        };
+
+        Ok(MptcpOption::Dss {
+               data_ack,
+               mapping,
+               data_fin: flags & DSS_DATA_FIN != 0,
+           })
+    }
+
+    /// Length of the whole option including kind and length
+    pub fn buffer_len(&self) -> usize {
+        match *self {
+            MptcpOption::Capable {
+                sender_key,
+                receiver_key,
+                ..
+            } => 4 + 8 * (sender_key.is_some() as usize + receiver_key.is_some() as usize),
+            MptcpOption::JoinSyn { .. } => 12,
+            MptcpOption::JoinSynAck { .. } => 16,
+            MptcpOption::JoinAck { .. } => 24,
+            MptcpOption::Dss {
+                data_ack,
+                mapping,
+                ..
+            } => {
+                4 + data_ack.map_or(0, |ack| ack.buffer_len()) +
+                mapping.map_or(0, |mapping| mapping.data_seq.buffer_len() + 6)
+            }
+            MptcpOption::AddAddr { port, hmac, .. } => {
+                8 + 2 * port.is_some() as usize + 8 * hmac.is_some() as usize
+            }
+        }
+    }
+
+    /// Writes the option data following the kind and length
+    pub fn emit(&self, data: &mut [u8]) {
+        match *self {
+            MptcpOption::Capable {
+                version,
+                flags,
+                sender_key,
+                receiver_key,
+            } => {
+                data[0] = SUBTYPE_CAPABLE << 4 | version;
+                data[1] = flags;
+                if let Some(key) = sender_key {
+                    NetworkEndian::write_u64(&mut data[2..10], key);
+                }
+                if let Some(key) = receiver_key {
+                    NetworkEndian::write_u64(&mut data[10..18], key);
+                }
+            }
+            MptcpOption::JoinSyn {
+                backup,
+                address_id,
+                token,
+                nonce,
+            } => {
+                data[0] = SUBTYPE_JOIN << 4 | backup as u8;
+                data[1] = address_id;
+                NetworkEndian::write_u32(&mut data[2..6], token);
+                NetworkEndian::write_u32(&mut data[6..10], nonce);
+            }
+            MptcpOption::JoinSynAck {
+                backup,
+                address_id,
+                hmac,
+                nonce,
+            } => {
+                data[0] = SUBTYPE_JOIN << 4 | backup as u8;
+                data[1] = address_id;
+                NetworkEndian::write_u64(&mut data[2..10], hmac);
+                NetworkEndian::write_u32(&mut data[10..14], nonce);
+            }
+            MptcpOption::JoinAck { ref hmac } => {
+                data[0] = SUBTYPE_JOIN << 4;
+                data[1] = 0;
+                data[2..].copy_from_slice(hmac);
+            }
+            MptcpOption::Dss {
+                data_ack,
+                mapping,
+                data_fin,
+            } => {
+                let mut flags = 0;
+                let mut offset = 2;
+                if let Some(ack) = data_ack {
+                    flags |= DSS_ACK;
+                    if let DataSeq::Full(_) = ack {
+                        flags |= DSS_ACK64;
+                    }
+                    offset += ack.emit(&mut data[offset..]);
+                }
+                if let Some(mapping) = mapping {
+                    flags |= DSS_MAPPING;
+                    if let DataSeq::Full(_) = mapping.data_seq {
+                        flags |= DSS_DSN64;
+                    }
+                    offset += mapping.data_seq.emit(&mut data[offset..]);
+                    NetworkEndian::write_u32(&mut data[offset..offset + 4], mapping.subflow_seq);
+                    NetworkEndian::write_u16(&mut data[offset + 4..offset + 6], mapping.len);
+                }
+                if data_fin {
+                    flags |= DSS_DATA_FIN;
+                }
+                data[0] = SUBTYPE_DSS << 4;
+                data[1] = flags;
+            }
+            MptcpOption::AddAddr {
+                echo,
+                address_id,
+                addr,
+                port,
+                hmac,
+            } => {
+                data[0] = SUBTYPE_ADD_ADDR << 4 | if echo { ADD_ADDR_ECHO } else { 0 };
+                data[1] = address_id;
+                data[2..6].copy_from_slice(addr.as_bytes());
+                let mut offset = 6;
+                if let Some(port) = port {
+                    NetworkEndian::write_u16(&mut data[6..8], port);
+                    offset += 2;
+                }
+                if let Some(hmac) = hmac {
+                    NetworkEndian::write_u64(&mut data[offset..offset + 8], hmac);
+                }
+            }
+        }
+    }
+}
+
+/// Random key for the MP_CAPABLE handshake
+pub fn new_key() -> u64 {
+    let mut buf = [0; 8];
+    getrandom::getrandom(&mut buf).expect("system random number generator");
+    NetworkEndian::read_u64(&buf)
+}
+
+/// Random nonce for the MP_JOIN handshake
+pub fn new_nonce() -> u32 {
+    let mut buf = [0; 4];
+    getrandom::getrandom(&mut buf).expect("system random number generator");
+    NetworkEndian::read_u32(&buf)
+}
+
+/// Token by which MP_JOIN names the connection of the host holding `key`
+pub fn token(key: u64) -> u32 {
+    NetworkEndian::read_u32(&key_hash(key)[..4])
+}
+
+/// Initial data sequence number of the host holding `key`
+pub fn idsn(key: u64) -> u64 {
+    NetworkEndian::read_u64(&key_hash(key)[24..])
+}
+
+fn key_hash(key: u64) -> [u8; 32] {
+    let mut buf = [0; 8];
+    NetworkEndian::write_u64(&mut buf, key);
+    Sha256::digest(buf).into()
+}
+
+/// HMAC proving knowledge of both keys in MP_JOIN (RFC 8684 section 3.2)
+///
+/// SYN-ACKs carry the leftmost 64 bits, third ACKs the leftmost 160 bits.
+pub fn join_hmac(sender_key: u64,
+                 receiver_key: u64,
+                 sender_nonce: u32,
+                 receiver_nonce: u32)
+                 -> [u8; 32] {
+    let mut msg = [0; 8];
+    NetworkEndian::write_u32(&mut msg[..4], sender_nonce);
+    NetworkEndian::write_u32(&mut msg[4..], receiver_nonce);
+    hmac_sha256(sender_key, receiver_key, &msg)
+}
+
+/// Truncated HMAC of an ADD_ADDR option (RFC 8684 section 3.4.1)
+pub fn add_addr_hmac(sender_key: u64,
+                     receiver_key: u64,
+                     address_id: u8,
+                     addr: ipv4::Address,
+                     port: Option<u16>)
+                     -> u64 {
+    let mut msg = [0; 7];
+    msg[0] = address_id;
+    msg[1..5].copy_from_slice(addr.as_bytes());
+    NetworkEndian::write_u16(&mut msg[5..], port.unwrap_or(0));
+    NetworkEndian::read_u64(&hmac_sha256(sender_key, receiver_key, &msg)[24..])
+}
+
+fn hmac_sha256(key_a: u64, key_b: u64, msg: &[u8]) -> [u8; 32] {
+    let mut key = [0; 16];
+    NetworkEndian::write_u64(&mut key[..8], key_a);
+    NetworkEndian::write_u64(&mut key[8..], key_b);
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key).expect("HMAC accepts keys of any length");
+    mac.update(msg);
+    mac.finalize().into_bytes().into()
+}
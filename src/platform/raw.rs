@@ -74,10 +74,10 @@ impl RawSocket {
         let ring = try!(Self::init_ringbuffer(recvfd));
 
         Ok(RawSocket {
-               recvfd: recvfd,
-               sendfd: sendfd,
+               recvfd,
+               sendfd,
                block_size: 524288,
-               ring: ring,
+               ring,
                ring_offset: 0,
            })
     }
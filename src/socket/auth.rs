@@ -0,0 +1,347 @@
+use std::collections::HashMap;
+
+use error::Error;
+use tcp;
+use tcp_ao::{self, MasterKey, TrafficKey};
+
+/// MD5 signature option padded to a multiple of 4 bytes
+const MD5_OPTION_LEN: usize = 20;
+
+/// TCP-AO option with a 96 bit MAC
+const AO_OPTION_LEN: usize = 4 + tcp_ao::MAC_LEN;
+
+/// Authentication keys per remote endpoint
+///
+/// Keys configured for port 0 apply to every port of the address, so that passive opens
+/// from ephemeral ports find them.
+#[derive(Debug, Default)]
+pub struct KeyChain {
+    md5: HashMap<tcp::Endpoint, Vec<u8>>,
+    ao: HashMap<tcp::Endpoint, Vec<MasterKey>>,
+}
+
+impl KeyChain {
+    /// Whether keys configured for `pattern` apply to `remote`
+    pub fn matches(pattern: tcp::Endpoint, remote: tcp::Endpoint) -> bool {
+        pattern == remote || (pattern.port == 0 && pattern.addr == remote.addr)
+    }
+
+    pub fn set_md5_key(&mut self, remote: tcp::Endpoint, key: &[u8]) {
+        self.md5.insert(remote, key.to_vec());
+    }
+
+    pub fn remove_md5_key(&mut self, remote: tcp::Endpoint) {
+        self.md5.remove(&remote);
+    }
+
+    /// Adds a master key, replacing one with the same KeyIDs
+    pub fn add_ao_key(&mut self, remote: tcp::Endpoint, key: MasterKey) {
+        let keys = self.ao.entry(remote).or_default();
+        keys.retain(|other| other.send_id != key.send_id || other.recv_id != key.recv_id);
+        keys.push(key);
+    }
+
+    pub fn remove_ao_key(&mut self, remote: tcp::Endpoint, send_id: u8, recv_id: u8) {
+        let empty = match self.ao.get_mut(&remote) {
+            Some(keys) => {
+                keys.retain(|key| key.send_id != send_id || key.recv_id != recv_id);
+                keys.is_empty()
+            }
+            None => return,
+        };
+        if empty {
+            self.ao.remove(&remote);
+        }
+    }
+
+    /// Authentication of a new connection with `remote`, TCP-AO taking precedence over MD5
+    pub fn auth(&self, remote: tcp::Endpoint, iss: u32, irs: Option<u32>) -> Option<Auth> {
+        if let Some(keys) = Self::lookup(&self.ao, remote) {
+            return Some(Auth::Ao(Ao::new(keys.clone(), iss, irs)));
+        }
+        Self::lookup(&self.md5, remote).map(|key| Auth::Md5(key.clone()))
+    }
+
+    /// Entry for `remote`, preferring one configured for its exact port
+    fn lookup<T>(map: &HashMap<tcp::Endpoint, T>, remote: tcp::Endpoint) -> Option<&T> {
+        map.get(&remote)
+            .or_else(|| map.get(&tcp::Endpoint::new(remote.addr, 0)))
+    }
+}
+
+/// How the segments of a connection are authenticated
+#[derive(Debug)]
+pub enum Auth {
+    Md5(Vec<u8>),
+    Ao(Ao),
+}
+
+impl Auth {
+    /// Length of the options that every segment carries
+    pub fn options_len(&self) -> usize {
+        match *self {
+            Auth::Md5(_) => MD5_OPTION_LEN,
+            Auth::Ao(_) => AO_OPTION_LEN,
+        }
+    }
+
+    /// Keys for the segments sent next, `syn` for a SYN without ACK
+    pub fn signer(&mut self,
+                  local: tcp::Endpoint,
+                  remote: tcp::Endpoint,
+                  syn: bool,
+                  snd_nxt: u32)
+                  -> Signer {
+        match *self {
+            Auth::Md5(ref key) => Signer::Md5(key.clone()),
+            Auth::Ao(ref mut ao) => ao.signer(local, remote, syn, snd_nxt),
+        }
+    }
+
+    /// Key to check a received segment against
+    pub fn verifier(&self,
+                    local: tcp::Endpoint,
+                    remote: tcp::Endpoint,
+                    packet: &tcp::Packet<&[u8]>)
+                    -> Result<Signer, Error> {
+        match *self {
+            Auth::Md5(ref key) => Ok(Signer::Md5(key.clone())),
+            Auth::Ao(ref ao) => ao.verifier(local, remote, packet),
+        }
+    }
+
+    /// Takes note of a segment that passed verification
+    pub fn on_authenticated(&mut self, repr: &tcp::Repr) {
+        if let Auth::Ao(ref mut ao) = *self {
+            ao.on_authenticated(repr);
+        }
+    }
+}
+
+/// Key material for signing or verifying segments
+#[derive(Debug)]
+pub enum Signer {
+    Md5(Vec<u8>),
+    Ao {
+        key: TrafficKey,
+        key_id: u8,
+        rnext_key_id: u8,
+        sne: Sne,
+    },
+}
+
+impl Signer {
+    /// Key for the segment starting at `seq`
+    pub fn signing_key(&self, seq: u32) -> tcp::SigningKey<'_> {
+        match *self {
+            Signer::Md5(ref key) => tcp::SigningKey::Md5(key),
+            Signer::Ao {
+                ref key,
+                key_id,
+                rnext_key_id,
+                sne,
+            } => {
+                tcp::SigningKey::Ao {
+                    key,
+                    sne: sne.get(seq),
+                    key_id,
+                    rnext_key_id,
+                }
+            }
+        }
+    }
+}
+
+/// Sequence Number Extension, the high half of a 64 bit sequence number (RFC 5925
+/// section 6.2)
+#[derive(Clone, Copy, Debug)]
+pub struct Sne {
+    sne: u32,
+    /// Highest sequence number seen so far
+    seq: u32,
+}
+
+impl Sne {
+    fn new(seq: u32) -> Self {
+        Sne { sne: 0, seq }
+    }
+
+    /// SNE of a segment starting at `seq`, which may lie on either side of a wrap
+    fn get(&self, seq: u32) -> u32 {
+        if seq < self.seq && tcp::seq_lt(self.seq, seq) {
+            self.sne.wrapping_add(1)
+        } else if seq > self.seq && tcp::seq_lt(seq, self.seq) {
+            self.sne.wrapping_sub(1)
+        } else {
+            self.sne
+        }
+    }
+
+    fn update(&mut self, seq: u32) {
+        if tcp::seq_lt(self.seq, seq) {
+            self.sne = self.get(seq);
+            self.seq = seq;
+        }
+    }
+}
+
+/// TCP-AO state of a connection (RFC 5925 section 3.2)
+#[derive(Debug)]
+pub struct Ao {
+    keys: Vec<MasterKey>,
+    /// Initial sequence numbers of both ends, the peer's unknown in SYN-SENT
+    iss: u32,
+    irs: Option<u32>,
+    /// SendID of the key used for sending (current_key)
+    current: u8,
+    /// RecvID of the key the peer is asked to use (RNext_key)
+    rnext: u8,
+    snd_sne: Sne,
+    rcv_sne: Sne,
+}
+
+impl Ao {
+    /// `keys` must not be empty, the first one is used until the peer asks for another
+    pub fn new(keys: Vec<MasterKey>, iss: u32, irs: Option<u32>) -> Self {
+        let (current, rnext) = (keys[0].send_id, keys[0].recv_id);
+        Ao {
+            keys,
+            iss,
+            irs,
+            current,
+            rnext,
+            snd_sne: Sne::new(iss),
+            rcv_sne: Sne::new(irs.unwrap_or(0)),
+        }
+    }
+
+    pub fn add_key(&mut self, key: MasterKey) {
+        self.keys
+            .retain(|other| other.send_id != key.send_id || other.recv_id != key.recv_id);
+        self.keys.push(key);
+    }
+
+    /// Removes a key unless it is the last one, moving off it if it is in use
+    pub fn remove_key(&mut self, send_id: u8, recv_id: u8) {
+        if self.keys.len() == 1 {
+            return;
+        }
+        self.keys
+            .retain(|key| key.send_id != send_id || key.recv_id != recv_id);
+        if !self.keys.iter().any(|key| key.send_id == self.current) {
+            self.current = self.keys[0].send_id;
+        }
+        if !self.keys.iter().any(|key| key.recv_id == self.rnext) {
+            self.rnext = self.keys[0].recv_id;
+        }
+    }
+
+    /// Asks the peer to switch to the key with `recv_id` (RFC 5925 section 7.5.2)
+    pub fn set_rnext(&mut self, recv_id: u8) {
+        if self.keys.iter().any(|key| key.recv_id == recv_id) {
+            self.rnext = recv_id;
+        }
+    }
+
+    fn signer(&mut self,
+              local: tcp::Endpoint,
+              remote: tcp::Endpoint,
+              syn: bool,
+              snd_nxt: u32)
+              -> Signer {
+        self.snd_sne.update(snd_nxt);
+
+        let current = self.current;
+        let master = self.keys
+            .iter()
+            .find(|key| key.send_id == current)
+            .unwrap_or(&self.keys[0]);
+        let dst_isn = if syn { 0 } else { self.irs.unwrap_or(0) };
+        Signer::Ao {
+            key: TrafficKey::derive(master, local, remote, self.iss, dst_isn),
+            key_id: master.send_id,
+            rnext_key_id: self.rnext,
+            sne: self.snd_sne,
+        }
+    }
+
+    fn verifier(&self,
+                local: tcp::Endpoint,
+                remote: tcp::Endpoint,
+                packet: &tcp::Packet<&[u8]>)
+                -> Result<Signer, Error> {
+        let key_id = packet
+            .option_iter()
+            .filter_map(|option| match option {
+                            Ok(tcp::TcpOption::Authentication { key_id, .. }) => Some(key_id),
+                            _ => None,
+                        })
+            .next()
+            .ok_or(Error::Signature)?;
+        let master = self.keys
+            .iter()
+            .find(|key| key.recv_id == key_id)
+            .ok_or(Error::Signature)?;
+
+        // A SYN carries the peer's ISN, and ours if it is a SYN-ACK
+        let (src_isn, dst_isn, sne) = if packet.flag_syn() {
+            let dst_isn = if packet.flags().ack() { self.iss } else { 0 };
+            (packet.seq_num(), dst_isn, Sne::new(packet.seq_num()))
+        } else {
+            (self.irs.unwrap_or(0), self.iss, self.rcv_sne)
+        };
+        Ok(Signer::Ao {
+               key: TrafficKey::derive(master, remote, local, src_isn, dst_isn),
+               key_id,
+               rnext_key_id: self.rnext,
+               sne,
+           })
+    }
+
+    fn on_authenticated(&mut self, repr: &tcp::Repr) {
+        if repr.flags.syn() {
+            self.irs = Some(repr.seq);
+            self.rcv_sne = Sne::new(repr.seq);
+        } else {
+            self.rcv_sne.update(repr.seq);
+        }
+
+        // Switch to the key the peer asks for, if we have it
+        for option in &repr.options {
+            if let tcp::TcpOption::Authentication { rnext_key_id, .. } = *option {
+                if rnext_key_id != self.current &&
+                   self.keys.iter().any(|key| key.send_id == rnext_key_id) {
+                    self.current = rnext_key_id;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sne_wraps() {
+        let mut sne = Sne::new(0xffff_fff0);
+        assert_eq!(sne.get(0xffff_fffa), 0);
+        assert_eq!(sne.get(0x10), 1);
+
+        sne.update(0x10);
+        assert_eq!(sne.sne, 1);
+        // Segments from before the wrap, retransmitted or reordered, keep the old SNE
+        assert_eq!(sne.get(0xffff_fff8), 0);
+        assert_eq!(sne.get(0x20), 1);
+
+        sne.update(0xffff_fff8);
+        assert_eq!((sne.sne, sne.seq), (1, 0x10));
+
+        // And so on at the next wrap
+        sne.update(0x8000_0000);
+        sne.update(0xffff_0000);
+        assert_eq!(sne.get(0x100), 2);
+        sne.update(0x100);
+        assert_eq!(sne.sne, 2);
+    }
+}
@@ -0,0 +1,323 @@
+use std::cmp;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use super::memory::Memory;
+
+/// Receive buffer of new connections, the window advertised so far
+pub const DEFAULT_RCVBUF: usize = 43690;
+pub const DEFAULT_RCVBUF_MAX: usize = 6 * 1024 * 1024;
+
+/// Send buffer of new connections, as Linux' `tcp_wmem`
+pub const DEFAULT_SNDBUF: usize = 16 * 1024;
+pub const DEFAULT_SNDBUF_MAX: usize = 4 * 1024 * 1024;
+
+/// Largest shift of the window scale option (RFC 7323 section 2.3)
+pub const MAX_WINDOW_SHIFT: u8 = 14;
+
+/// Buffer accounting shared by a connection and its socket
+#[derive(Debug)]
+pub struct Buffers {
+    /// Bytes handed to the socket so far
+    delivered: AtomicUsize,
+    /// Bytes the application read so far
+    read: AtomicUsize,
+    /// Bytes written and not acknowledged yet
+    queued: Mutex<usize>,
+    /// Signalled when acknowledgments free room in the send buffer
+    freed: Condvar,
+    sndbuf: AtomicUsize,
+    /// The connection is gone, writes stop waiting for room
+    closed: AtomicBool,
+    /// The socket's channels were taken over, reads can't be accounted for anymore
+    detached: AtomicBool,
+    /// Budget of the interface, charged for unread and unacknowledged data
+    memory: Arc<Memory>,
+}
+
+impl Buffers {
+    pub fn new(sndbuf: usize, memory: Arc<Memory>) -> Self {
+        Buffers {
+            delivered: AtomicUsize::new(0),
+            read: AtomicUsize::new(0),
+            queued: Mutex::new(0),
+            freed: Condvar::new(),
+            sndbuf: AtomicUsize::new(sndbuf),
+            closed: AtomicBool::new(false),
+            detached: AtomicBool::new(false),
+            memory,
+        }
+    }
+
+    pub fn on_delivered(&self, len: usize) {
+        if self.detached.load(Ordering::Relaxed) {
+            return;
+        }
+        self.delivered.fetch_add(len, Ordering::Relaxed);
+        self.memory.charge(len);
+    }
+
+    pub fn on_read(&self, len: usize) {
+        self.read.fetch_add(len, Ordering::Relaxed);
+        self.memory.uncharge(len);
+    }
+
+    pub fn read(&self) -> usize {
+        self.read.load(Ordering::Relaxed)
+    }
+
+    /// Bytes handed to the socket that the application hasn't read yet
+    pub fn unread(&self) -> usize {
+        self.delivered
+            .load(Ordering::Relaxed)
+            .wrapping_sub(self.read())
+    }
+
+    /// Waits for room in the send buffer and takes up to `len` bytes of it, `None` once the
+    /// connection is gone
+    pub fn reserve(&self, len: usize) -> Option<usize> {
+        let mut queued = self.queued.lock().unwrap();
+        loop {
+            if self.closed.load(Ordering::Relaxed) {
+                return None;
+            }
+            let mut room = self.sndbuf.load(Ordering::Relaxed).saturating_sub(*queued);
+            // Without memory left, only what's queued already has to drain first
+            if self.memory.exhausted() && *queued > 0 {
+                room = 0;
+            }
+            if room > 0 {
+                let len = cmp::min(len, room);
+                *queued += len;
+                self.memory.charge(len);
+                return Some(len);
+            }
+            queued = self.freed.wait(queued).unwrap();
+        }
+    }
+
+    /// Gives back room taken by data that got acknowledged
+    pub fn release(&self, len: usize) {
+        let mut queued = self.queued.lock().unwrap();
+        let len = cmp::min(len, *queued);
+        *queued -= len;
+        self.memory.uncharge(len);
+        self.freed.notify_all();
+    }
+
+    pub fn sndbuf(&self) -> usize {
+        self.sndbuf.load(Ordering::Relaxed)
+    }
+
+    /// Grows the send buffer to `size`, it never shrinks
+    pub fn grow_sndbuf(&self, size: usize) {
+        if self.sndbuf.fetch_max(size, Ordering::Relaxed) < size {
+            let _queued = self.queued.lock().unwrap();
+            self.freed.notify_all();
+        }
+    }
+
+    /// Stops accounting for received data, which the socket won't read anymore: its
+    /// channels were taken over or its read half shut down
+    pub fn detach(&self) {
+        if !self.detached.swap(true, Ordering::Relaxed) {
+            self.on_read(self.unread());
+        }
+    }
+
+    pub fn memory(&self) -> &Arc<Memory> {
+        &self.memory
+    }
+
+    pub fn close(&self) {
+        let _queued = self.queued.lock().unwrap();
+        self.closed.store(true, Ordering::Relaxed);
+        self.freed.notify_all();
+    }
+}
+
+impl Drop for Buffers {
+    fn drop(&mut self) {
+        // Neither the socket nor the connection are left to read or send the data
+        let queued = *self.queued.lock().unwrap();
+        self.memory.uncharge(queued + self.unread());
+    }
+}
+
+/// Sizing of a connection's buffers by what it actually uses
+///
+/// The receive buffer follows the rate the application reads at, twice what it read in a
+/// round trip (dynamic right-sizing, as Linux' `tcp_rcv_space_adjust`). The send buffer
+/// follows the congestion window, twice the bandwidth-delay product it estimates.
+#[derive(Debug)]
+pub struct Autotune {
+    pub rcvbuf: usize,
+    /// Receive buffer the connection started with, and shrinks back to under memory pressure
+    rcvbuf_initial: usize,
+    rcvbuf_max: usize,
+    sndbuf_max: usize,
+    /// Most bytes read in a round trip so far
+    space: usize,
+    /// Start of the current round trip and the bytes read by then
+    measured: Option<(Instant, usize)>,
+}
+
+impl Autotune {
+    pub fn new(rcvbuf: usize, rcvbuf_max: usize, sndbuf_max: usize) -> Self {
+        Autotune {
+            rcvbuf,
+            rcvbuf_initial: rcvbuf,
+            rcvbuf_max: cmp::max(rcvbuf, rcvbuf_max),
+            sndbuf_max,
+            space: 0,
+            measured: None,
+        }
+    }
+
+    /// Shift of the window scale option, enough for the largest receive buffer
+    pub fn window_shift(&self) -> u8 {
+        let mut shift = 0;
+        while self.rcvbuf_max >> shift > u16::MAX as usize && shift < MAX_WINDOW_SHIFT {
+            shift += 1;
+        }
+        shift
+    }
+
+    /// Room left in the receive buffer, none once the interface runs out of memory
+    pub fn window(&self, buffers: &Buffers) -> usize {
+        let window = self.rcvbuf.saturating_sub(buffers.unread());
+        cmp::min(window, buffers.memory.room())
+    }
+
+    /// Shrinks the receive buffer back towards its initial size, keeping what's unread
+    /// (as Linux' `tcp_clamp_window`)
+    pub fn clamp(&mut self, buffers: &Buffers) {
+        let rcvbuf = cmp::max(buffers.unread(), self.rcvbuf_initial);
+        self.rcvbuf = cmp::min(self.rcvbuf, rcvbuf);
+    }
+
+    /// Grows the receive buffer once a round trip of `srtt` is over
+    pub fn on_timer(&mut self, buffers: &Buffers, srtt: Duration, now: Instant) {
+        let read = buffers.read();
+        let (start, read_before) = match self.measured {
+            Some(measured) => measured,
+            None => {
+                self.measured = Some((now, read));
+                return;
+            }
+        };
+        if now.duration_since(start) < srtt {
+            return;
+        }
+        self.measured = Some((now, read));
+
+        let copied = read.wrapping_sub(read_before);
+        if copied <= self.space {
+            return;
+        }
+        self.space = copied;
+        self.rcvbuf = cmp::min(cmp::max(self.rcvbuf, 2 * copied), self.rcvbuf_max);
+    }
+
+    /// Send buffer for a congestion window of `cwnd`
+    pub fn sndbuf(&self, cwnd: usize) -> usize {
+        cmp::min(2 * cwnd, self.sndbuf_max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffers(sndbuf: usize, limit: usize) -> Buffers {
+        Buffers::new(sndbuf, Arc::new(Memory::new(limit)))
+    }
+
+    fn read(buffers: &Buffers, len: usize) {
+        buffers.on_delivered(len);
+        buffers.on_read(len);
+    }
+
+    #[test]
+    fn receive_buffer_growth() {
+        let now = Instant::now();
+        let srtt = Duration::from_millis(100);
+        let buffers = buffers(DEFAULT_SNDBUF, 1 << 20);
+        let mut autotune = Autotune::new(1000, 10_000, DEFAULT_SNDBUF_MAX);
+
+        // Twice what was read in a round trip
+        autotune.on_timer(&buffers, srtt, now);
+        read(&buffers, 3000);
+        autotune.on_timer(&buffers, srtt, now + srtt / 2);
+        assert_eq!(autotune.rcvbuf, 1000);
+        autotune.on_timer(&buffers, srtt, now + srtt);
+        assert_eq!(autotune.rcvbuf, 6000);
+
+        // Never beyond the maximum
+        read(&buffers, 8000);
+        autotune.on_timer(&buffers, srtt, now + srtt * 2);
+        assert_eq!(autotune.rcvbuf, 10_000);
+
+        // Nor shrinking when the application slows down
+        read(&buffers, 100);
+        autotune.on_timer(&buffers, srtt, now + srtt * 3);
+        assert_eq!(autotune.rcvbuf, 10_000);
+    }
+
+    #[test]
+    fn receive_window() {
+        let buffers = buffers(DEFAULT_SNDBUF, 1 << 20);
+        let mut autotune = Autotune::new(10_000, 10_000, DEFAULT_SNDBUF_MAX);
+        assert_eq!(autotune.window_shift(), 0);
+        assert_eq!(Autotune::new(10_000, DEFAULT_RCVBUF_MAX, 0).window_shift(), 7);
+
+        buffers.on_delivered(4000);
+        assert_eq!(autotune.window(&buffers), 6000);
+
+        // Clamped back to the initial size, but not below what's unread
+        autotune.rcvbuf = 20_000;
+        autotune.rcvbuf_initial = 1000;
+        autotune.clamp(&buffers);
+        assert_eq!(autotune.rcvbuf, 4000);
+
+        // Closed once memory runs out
+        buffers.memory().charge(1 << 20);
+        assert_eq!(autotune.window(&buffers), 0);
+    }
+
+    #[test]
+    fn send_buffer_bound() {
+        let autotune = Autotune::new(DEFAULT_RCVBUF, DEFAULT_RCVBUF_MAX, 100_000);
+        assert_eq!(autotune.sndbuf(10_000), 20_000);
+        assert_eq!(autotune.sndbuf(60_000), 100_000);
+
+        let buffers = buffers(1000, 1 << 20);
+        assert_eq!(buffers.reserve(1500), Some(1000));
+        buffers.grow_sndbuf(500);
+        assert_eq!(buffers.sndbuf(), 1000);
+        buffers.grow_sndbuf(2000);
+        assert_eq!(buffers.reserve(1500), Some(1000));
+    }
+
+    #[test]
+    fn memory_accounting() {
+        let buffers = buffers(10_000, 1 << 20);
+        let memory = buffers.memory().clone();
+
+        assert_eq!(buffers.reserve(3000), Some(3000));
+        buffers.on_delivered(2000);
+        assert_eq!(memory.used(), 5000);
+        buffers.release(3000);
+        buffers.on_read(500);
+        assert_eq!(memory.used(), 1500);
+
+        // Unread data is given back on detaching, and no longer charged after
+        buffers.detach();
+        buffers.on_delivered(2000);
+        assert_eq!(memory.used(), 0);
+
+        buffers.close();
+        assert_eq!(buffers.reserve(1000), None);
+    }
+}
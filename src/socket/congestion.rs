@@ -0,0 +1,13 @@
+/// How a sender sizes its congestion window
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CongestionControl {
+    /// Slow start and congestion avoidance with NewReno fast recovery (RFC 5681, RFC 6582)
+    #[default]
+    NewReno,
+    /// Yields to other traffic by keeping the queueing delay low, for background transfers
+    /// (LEDBAT, RFC 6817)
+    Ledbat,
+    /// Reduces the window in proportion to the ECN marks of a round trip, for datacenter
+    /// networks marking early (DCTCP, RFC 8257)
+    Dctcp,
+}
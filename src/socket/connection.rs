@@ -0,0 +1,1376 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, mpsc};
+use std::time::{Duration, Instant};
+
+use super::{CongestionControl, Linger, PacketBuffer, SocketState};
+use super::auth::{Auth, Signer};
+use super::buffers::{self, Autotune, Buffers};
+use super::dctcp::Dctcp;
+use super::ledbat::{self, Ledbat};
+use super::mptcp::{self, Subflow};
+use super::plpmtud::Plpmtud;
+use super::rack::Rack;
+use super::stats::Stats;
+use super::uto::UserTimeout;
+use tcp;
+
+/// MSS assumed when the peer doesn't announce one (RFC 1122 section 4.2.2.6)
+pub const DEFAULT_MSS: usize = 536;
+
+/// Length of the IPv4 and TCP headers without options
+pub const HEADER_LEN: usize = 40;
+
+/// Initial congestion window in segments (RFC 6928)
+const INITIAL_WINDOW: usize = 10;
+
+/// Duplicate ACKs that trigger a fast retransmit (RFC 5681 section 3.2)
+const DUP_ACK_THRESHOLD: u32 = 3;
+
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+
+/// Lower bound of the RTO, as Linux' `TCP_RTO_MIN` rather than the 1s of RFC 6298
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(60);
+
+/// Consecutive timeouts after which PLPMTUD suspects a black hole
+const BLACK_HOLE_TIMEOUTS: u32 = 2;
+
+/// Pacing rate relative to cwnd/SRTT in slow start and congestion avoidance, in percent,
+/// ahead of the window so that it can still grow (as Linux' `tcp_pacing_ss_ratio` and
+/// `tcp_pacing_ca_ratio`)
+const PACING_SS_RATIO: u64 = 200;
+const PACING_CA_RATIO: u64 = 120;
+
+/// Room taken by the timestamps option, padded to a multiple of 4
+const TIMESTAMPS_OPTION_LEN: usize = 12;
+
+/// New segments sent on the first ACK after a timeout to probe whether it was spurious
+/// (RFC 5682 section 2.1)
+const FRTO_NEW_SEGMENTS: u32 = 2;
+
+/// Maximum segment lifetime, 30s as on Linux rather than the 2 minutes of RFC 793
+const MSL: Duration = Duration::from_secs(30);
+
+/// SYNs or SYN-ACKs retransmitted before a handshake is given up, as Linux'
+/// `tcp_synack_retries`
+const SYN_RETRIES: u32 = 5;
+
+/// RTO once data flows after a lost SYN or SYN-ACK and without an RTT sample
+/// (RFC 6298 section 5.7)
+const SYN_LOSS_RTO: Duration = Duration::from_secs(3);
+
+/// A sent segment kept until it is acknowledged
+#[derive(Debug)]
+pub struct Segment {
+    pub seq: u32,
+    pub payload: Vec<u8>,
+    pub fin: bool,
+    pub sent: Instant,
+    /// Sent more than once, so it can't give an RTT sample (Karn's algorithm)
+    pub retransmitted: bool,
+    /// Considered lost and waiting to be retransmitted
+    pub lost: bool,
+    /// Reported received by a SACK block
+    pub sacked: bool,
+    /// PLPMTUD probe, its loss is not a congestion signal
+    pub probe: bool,
+    /// Data sequence number of the payload on an MPTCP subflow
+    pub data_seq: Option<u64>,
+}
+
+impl Segment {
+    /// Sequence space occupied by the segment
+    pub fn len(&self) -> u32 {
+        self.payload.len() as u32 + self.fin as u32
+    }
+
+    pub fn end(&self) -> u32 {
+        self.seq.wrapping_add(self.len())
+    }
+
+    pub fn flags(&self) -> tcp::Flags {
+        if self.fin {
+            tcp::Flags::FIN | tcp::Flags::ACK
+        } else {
+            tcp::Flags::ACK
+        }
+    }
+}
+
+/// Round-trip time estimation (RFC 6298)
+#[derive(Debug)]
+pub struct RttEstimator {
+    pub srtt: Option<Duration>,
+    pub rttvar: Duration,
+    pub rto: Duration,
+}
+
+impl RttEstimator {
+    pub fn new() -> Self {
+        RttEstimator {
+            srtt: None,
+            rttvar: Duration::from_secs(0),
+            rto: INITIAL_RTO,
+        }
+    }
+
+    pub fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                self.rttvar = self.rttvar * 3 / 4 + delta / 4;
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
+        }
+        let rto = self.srtt.unwrap() + cmp::max(self.rttvar * 4, Duration::from_millis(10));
+        self.rto = cmp::min(cmp::max(rto, MIN_RTO), MAX_RTO);
+    }
+
+    pub fn backoff(&mut self) {
+        self.rto = cmp::min(self.rto * 2, MAX_RTO);
+    }
+}
+
+/// Check of a retransmission timeout for being spurious, by F-RTO (RFC 5682) and, with
+/// timestamps, the Eifel detection algorithm (RFC 3522)
+#[derive(Debug)]
+pub struct Frto {
+    /// SND.NXT when the timer expired
+    recover: u32,
+    /// Timestamp value of the retransmission
+    retransmit_ts: Option<u32>,
+    /// New segments were sent after the first ACK, the next ACK decides (step 3)
+    probing: bool,
+    /// New segments that may still be sent past the congestion window
+    allowance: u32,
+    /// Congestion state before the timeout, restored when it was spurious
+    cwnd: usize,
+    ssthresh: usize,
+}
+
+pub struct Connection {
+    pub state: SocketState,
+    /// Our initial sequence number (ISS)
+    pub iss: u32,
+    /// Next sequence number to send (SND.NXT)
+    pub seq: u32,
+    /// Next sequence number expected from the peer (RCV.NXT)
+    pub ack: u32,
+    /// Maximum segment size announced by the peer
+    pub mss: usize,
+    /// Both ends sent SACK-permitted
+    pub sack: bool,
+    /// Both ends sent the timestamps option (RFC 7323), only offered so far during the
+    /// handshake
+    pub timestamps: bool,
+    /// Both ends agreed on ECN (RFC 3168), only offered so far during the handshake
+    pub ecn: bool,
+    /// The latest segment received was marked CE, echoed with ECE in our ACKs
+    pub ce: bool,
+    /// Origin of our timestamp values
+    ts_base: Instant,
+    /// TCP MD5 or TCP-AO authentication of every segment
+    pub auth: Option<Auth>,
+    /// Role in an MPTCP connection, if the connection is a subflow of one
+    pub mptcp: Option<Subflow>,
+    /// Path MTU learned from the network
+    pub path_mtu: usize,
+    pub rx: Option<mpsc::Sender<PacketBuffer>>,
+    /// Accounting of the data held for the socket, shared with it
+    pub buffers: Arc<Buffers>,
+    pub autotune: Autotune,
+    /// Shift of our window (RFC 7323), offered so far during the handshake
+    pub rcv_shift: Option<u8>,
+    /// Shift of the peer's window
+    pub snd_shift: u8,
+    /// Window advertised last
+    last_window: usize,
+
+    /// Data written by the socket but not sent yet
+    pub unsent: VecDeque<u8>,
+    /// A FIN is to follow the unsent data
+    pub fin_queued: bool,
+    /// Unacknowledged segments in sequence order
+    pub retransmit: VecDeque<Segment>,
+
+    /// Send window announced by the peer (SND.WND)
+    pub window: usize,
+    pub cwnd: usize,
+    pub ssthresh: usize,
+    /// End of the window being recovered after a fast retransmit (NewReno)
+    pub recover: Option<u32>,
+    pub dup_acks: u32,
+
+    pub rtt: RttEstimator,
+    /// Expiry of the retransmission timer
+    pub rto_deadline: Option<Instant>,
+    /// Timeouts since the last acknowledgment of new data
+    pub timeouts: u32,
+    /// Options of our SYN or SYN-ACK, kept to retransmit it until the handshake completes
+    pub syn_options: Vec<tcp::TcpOption<'static>>,
+    /// Retransmission of the SYN or SYN-ACK
+    pub syn_deadline: Option<Instant>,
+    pub syn_retries: u32,
+    /// Data that came with the peer's SYN, held until the socket is handed over
+    pub syn_data: Vec<u8>,
+    pub user_timeout: UserTimeout,
+    /// Since when sent data has gone unacknowledged
+    pub stalled_since: Option<Instant>,
+    /// Check timeouts for being spurious (F-RTO)
+    pub frto: bool,
+    pub frto_check: Option<Frto>,
+
+    pub plpmtud: Option<Plpmtud>,
+    /// RACK-TLP loss detection, dup-ACK counting when absent
+    pub rack: Option<Rack>,
+    /// Delay-based window in place of slow start and congestion avoidance
+    pub ledbat: Option<Ledbat>,
+    /// Window reduced in proportion to the congestion marks echoed by the peer
+    pub dctcp: Option<Dctcp>,
+
+    /// Spread segments over the RTT instead of sending the window in one burst
+    pub pacing: bool,
+    /// Earliest time the next segment may be sent when pacing
+    pub next_send: Option<Instant>,
+
+    /// Latest timestamp value received from the peer
+    pub ts_recent: Option<u32>,
+    /// Expiry of TIME-WAIT
+    pub time_wait: Option<Instant>,
+    /// Linger policy of the socket
+    pub linger: Linger,
+    /// Reset the connection if data is still unacknowledged by then, once closed
+    pub linger_deadline: Option<Instant>,
+    /// Give up the handshake if it takes longer
+    pub half_open_timeout: Duration,
+    /// Reset the connection once nothing was received from the peer for this long
+    pub idle_timeout: Option<Duration>,
+    opened: Instant,
+    /// Arrival of the latest segment from the peer
+    pub last_received: Instant,
+
+    /// Counters kept as segments flow
+    pub stats: Stats,
+    /// Latest snapshot of `stats` for the socket
+    pub published: Arc<Mutex<Stats>>,
+}
+
+/// Time until a handshake is given up, all its retransmission timeouts backed off in turn
+pub fn handshake_timeout() -> Duration {
+    (0..SYN_RETRIES + 1)
+        .map(|retries| cmp::min(INITIAL_RTO * 2u32.pow(retries), MAX_RTO))
+        .sum()
+}
+
+impl Connection {
+    pub fn new(state: SocketState,
+               rx: Option<mpsc::Sender<PacketBuffer>>,
+               path_mtu: usize,
+               buffers: Arc<Buffers>)
+               -> Self {
+        Connection {
+            state,
+            iss: 0,
+            seq: 0,
+            ack: 0,
+            mss: DEFAULT_MSS,
+            sack: false,
+            timestamps: false,
+            ecn: false,
+            ce: false,
+            ts_base: Instant::now(),
+            auth: None,
+            mptcp: None,
+            path_mtu,
+            rx,
+            buffers,
+            autotune: Autotune::new(buffers::DEFAULT_RCVBUF,
+                                    buffers::DEFAULT_RCVBUF_MAX,
+                                    buffers::DEFAULT_SNDBUF_MAX),
+            rcv_shift: None,
+            snd_shift: 0,
+            last_window: 0,
+
+            unsent: VecDeque::new(),
+            fin_queued: false,
+            retransmit: VecDeque::new(),
+
+            window: 0,
+            cwnd: 0,
+            ssthresh: usize::MAX,
+            recover: None,
+            dup_acks: 0,
+
+            rtt: RttEstimator::new(),
+            rto_deadline: None,
+            timeouts: 0,
+            syn_options: Vec::new(),
+            syn_deadline: None,
+            syn_retries: 0,
+            syn_data: Vec::new(),
+            user_timeout: UserTimeout::new(None),
+            stalled_since: None,
+            frto: false,
+            frto_check: None,
+
+            plpmtud: None,
+            rack: None,
+            ledbat: None,
+            dctcp: None,
+
+            pacing: false,
+            next_send: None,
+
+            ts_recent: None,
+            time_wait: None,
+            linger: Linger::default(),
+            linger_deadline: None,
+            half_open_timeout: handshake_timeout(),
+            idle_timeout: None,
+            opened: Instant::now(),
+            last_received: Instant::now(),
+
+            stats: Stats::new(state),
+            published: Arc::new(Mutex::new(Stats::new(state))),
+        }
+    }
+
+    /// Completes the handshake with `seq` and `ack` as SND.NXT and RCV.NXT
+    pub fn establish(&mut self, seq: u32, ack: u32, window: usize) {
+        self.state = SocketState::Established;
+        self.seq = seq;
+        self.ack = ack;
+        self.window = window;
+        self.cwnd = INITIAL_WINDOW * self.send_mss();
+        self.syn_options = Vec::new();
+        self.syn_deadline = None;
+        if self.syn_retries > 0 && self.rtt.srtt.is_none() {
+            self.rtt.rto = SYN_LOSS_RTO;
+        }
+    }
+
+    /// Starts retransmitting our SYN or SYN-ACK, sent with `options` besides timestamps,
+    /// until the handshake completes
+    pub fn arm_syn_timer(&mut self, options: Vec<tcp::TcpOption<'static>>, now: Instant) {
+        self.syn_options = options;
+        self.syn_deadline = Some(now + self.rtt.rto);
+    }
+
+    /// Whether the SYN or SYN-ACK is due again, backing off its timer if so
+    pub fn syn_timer_expired(&mut self, now: Instant) -> bool {
+        match self.syn_deadline {
+            Some(deadline) if deadline <= now => (),
+            _ => return false,
+        }
+        self.rtt.backoff();
+        self.syn_retries += 1;
+        self.syn_deadline = Some(now + self.rtt.rto);
+        true
+    }
+
+    /// The handshake went unanswered through all retransmissions
+    pub fn syn_given_up(&self) -> bool {
+        self.syn_retries > SYN_RETRIES
+    }
+
+    pub fn set_congestion_control(&mut self, congestion_control: CongestionControl) {
+        match congestion_control {
+            CongestionControl::NewReno => {
+                self.ledbat = None;
+                self.dctcp = None;
+            }
+            CongestionControl::Ledbat => {
+                self.dctcp = None;
+                if self.ledbat.is_none() {
+                    self.ledbat = Some(Ledbat::new());
+                }
+            }
+            CongestionControl::Dctcp => {
+                self.ledbat = None;
+                if self.dctcp.is_none() {
+                    self.dctcp = Some(Dctcp::new());
+                }
+            }
+        }
+    }
+
+    /// Takes up the peer's window scale option, if any, only scaling windows when both ends
+    /// sent one
+    pub fn on_window_scale(&mut self, shift: Option<u8>) {
+        match shift {
+            Some(shift) if self.rcv_shift.is_some() => {
+                self.snd_shift = cmp::min(shift, buffers::MAX_WINDOW_SHIFT)
+            }
+            _ => self.rcv_shift = None,
+        }
+    }
+
+    pub fn window_scale_option(&self) -> Option<tcp::TcpOption<'static>> {
+        self.rcv_shift.map(tcp::TcpOption::WindowScale)
+    }
+
+    /// Room in the receive buffer that can be advertised
+    pub fn rcv_window(&self) -> usize {
+        let shift = self.rcv_shift.unwrap_or(0);
+        let window = cmp::min(self.autotune.window(&self.buffers), (u16::MAX as usize) << shift);
+        window >> shift << shift
+    }
+
+    /// Window field of a segment sent now
+    pub fn advertise(&mut self) -> u16 {
+        self.last_window = self.rcv_window();
+        (self.last_window >> self.rcv_shift.unwrap_or(0)) as u16
+    }
+
+    /// Whether the window opened far enough since it was last advertised to announce it
+    /// without waiting for data to carry it (RFC 1122 section 4.2.3.3)
+    pub fn window_update_due(&self) -> bool {
+        match self.state {
+            SocketState::Established | SocketState::FinWait1 | SocketState::FinWait2 => (),
+            _ => return false,
+        }
+        let threshold = cmp::min(self.autotune.rcvbuf / 2, self.mss);
+        self.rcv_window() >= self.last_window + cmp::max(threshold, 1)
+    }
+
+    /// Resizes the buffers to what the connection uses, or as memory allows
+    pub fn tune_buffers(&mut self, now: Instant) {
+        if self.buffers.memory().under_pressure() {
+            self.autotune.clamp(&self.buffers);
+            return;
+        }
+        if let Some(srtt) = self.rtt.srtt {
+            self.autotune.on_timer(&self.buffers, srtt, now);
+        }
+        self.buffers.grow_sndbuf(self.autotune.sndbuf(self.cwnd));
+    }
+
+    /// Publishes the current state and counters for `Socket::stats`
+    pub fn publish_stats(&mut self) {
+        self.stats.state = self.state;
+        self.stats.srtt = self.rtt.srtt;
+        self.stats.rttvar = self.rtt.rttvar;
+        self.stats.rto = self.rtt.rto;
+        self.stats.cwnd = self.cwnd;
+        self.stats.ssthresh = self.ssthresh;
+        self.stats.snd_wnd = self.window;
+        self.stats.rcv_wnd = self.rcv_window();
+        *self.published.lock().unwrap() = self.stats;
+    }
+
+    /// Data or a FIN still to be sent or acknowledged
+    pub fn outstanding(&self) -> bool {
+        !self.unsent.is_empty() || self.fin_queued || !self.retransmit.is_empty()
+    }
+
+    /// Whether the linger timeout ran out with data still unacknowledged
+    pub fn linger_expired(&self, now: Instant) -> bool {
+        expired(self.linger_deadline, now) && self.outstanding()
+    }
+
+    /// Whether sent data has gone unacknowledged for longer than the user timeout
+    pub fn user_timeout_expired(&self, now: Instant) -> bool {
+        self.user_timeout.expired(self.stalled_since, now)
+    }
+
+    /// Whether the handshake took longer than the half-open timeout
+    pub fn half_open_expired(&self, now: Instant) -> bool {
+        matches!(self.state, SocketState::SynSent | SocketState::SynReceived) &&
+        now.duration_since(self.opened) >= self.half_open_timeout
+    }
+
+    /// Whether an established connection heard nothing from the peer for longer than the
+    /// idle timeout
+    pub fn idle_expired(&self, now: Instant) -> bool {
+        match (self.idle_timeout, self.state) {
+            (None, _) |
+            (_, SocketState::SynSent) |
+            (_, SocketState::SynReceived) |
+            (_, SocketState::TimeWait) |
+            (_, SocketState::Closed) => false,
+            (Some(timeout), _) => now.duration_since(self.last_received) >= timeout,
+        }
+    }
+
+    /// Moves to TIME-WAIT, or restarts its 2MSL timer when already there
+    pub fn enter_time_wait(&mut self, now: Instant) {
+        self.state = SocketState::TimeWait;
+        self.time_wait = Some(now + 2 * MSL);
+    }
+
+    pub fn time_wait_expired(&self, now: Instant) -> bool {
+        expired(self.time_wait, now)
+    }
+
+    /// Whether a new SYN may take over the 4-tuple of this connection in TIME-WAIT, its
+    /// timestamp or else its ISN being beyond anything of the old connection (RFC 6191)
+    pub fn accepts_syn(&self, repr: &tcp::Repr) -> bool {
+        match (self.ts_recent, repr.timestamps()) {
+            (Some(ts_recent), Some((tsval, _))) => tcp::seq_lt(ts_recent, tsval),
+            _ => tcp::seq_lt(self.ack, repr.seq),
+        }
+    }
+
+    /// Whether a connection of ours with ISN `iss` may take over the 4-tuple of this
+    /// connection in TIME-WAIT, its timestamps or else its ISN being beyond anything of
+    /// the old connection (RFC 6191)
+    ///
+    /// The timestamps of the new connection go on from the clock of this one, see
+    /// `take_over`.
+    pub fn accepts_connect(&self, iss: u32) -> bool {
+        self.timestamps || tcp::seq_lt(self.seq, iss)
+    }
+
+    /// Goes on with the timestamps clock of the connection in TIME-WAIT this one replaces
+    pub fn take_over(&mut self, old: &Connection) {
+        self.ts_base = old.ts_base;
+    }
+
+    /// Takes up the timestamp of a received segment for echoing, unless it's older than
+    /// the one held or the segment lies beyond what we acknowledged (RFC 7323 section 4.3)
+    pub fn on_timestamp(&mut self, seq: u32, tsval: u32) {
+        let ts_recent = match self.ts_recent {
+            Some(ts_recent) if self.state != SocketState::SynSent => ts_recent,
+            // The SYN-ACK brings the first timestamp of the peer
+            _ => {
+                self.ts_recent = Some(tsval);
+                return;
+            }
+        };
+        if tcp::seq_le(ts_recent, tsval) && tcp::seq_le(seq, self.ack) {
+            self.ts_recent = Some(tsval);
+        }
+    }
+
+    /// Largest segment payload that fits the peer's MSS and the path
+    pub fn send_mss(&self) -> usize {
+        let mtu = match self.plpmtud {
+            Some(ref plpmtud) => plpmtud.mtu(self.max_mtu()),
+            None => self.max_mtu(),
+        };
+        cmp::max(cmp::min(self.mss, mtu - HEADER_LEN).saturating_sub(self.options_len()),
+                 1)
+    }
+
+    /// Length of the options carried by every segment, which the MSS doesn't account for
+    /// (RFC 6691)
+    fn options_len(&self) -> usize {
+        let dss_len = match self.mptcp {
+            Some(_) => mptcp::DSS_OPTION_LEN,
+            None => 0,
+        };
+        let timestamps_len = if self.timestamps {
+            TIMESTAMPS_OPTION_LEN
+        } else {
+            0
+        };
+        self.auth.as_ref().map_or(0, |auth| auth.options_len()) + dss_len + timestamps_len
+    }
+
+    /// Our timestamp value at `now`, a millisecond clock
+    pub fn tsval(&self, now: Instant) -> u32 {
+        now.duration_since(self.ts_base).as_millis() as u32
+    }
+
+    /// Timestamps option of a segment sent at `now`, if timestamps are in use
+    pub fn timestamp_option(&self, now: Instant) -> Option<tcp::TcpOption<'static>> {
+        if !self.timestamps {
+            return None;
+        }
+        Some(tcp::TcpOption::Timestamps {
+                 tsval: self.tsval(now),
+                 tsecr: self.ts_recent.unwrap_or(0),
+             })
+    }
+
+    /// Keys signing the segments sent next, `syn` for a SYN without ACK
+    pub fn signer(&mut self,
+                  local: tcp::Endpoint,
+                  remote: tcp::Endpoint,
+                  syn: bool)
+                  -> Option<Signer> {
+        let snd_nxt = self.seq;
+        self.auth
+            .as_mut()
+            .map(|auth| auth.signer(local, remote, syn, snd_nxt))
+    }
+
+    /// Largest MTU the connection could ever use
+    fn max_mtu(&self) -> usize {
+        cmp::min(self.path_mtu, self.mss + HEADER_LEN)
+    }
+
+    /// Oldest unacknowledged sequence number (SND.UNA)
+    pub fn una(&self) -> u32 {
+        self.retransmit
+            .front()
+            .map_or(self.seq, |segment| segment.seq)
+    }
+
+    /// Whether `seq` lies within data that has been sent but not yet acknowledged
+    pub fn in_flight(&self, seq: u32) -> bool {
+        tcp::seq_le(self.una(), seq) && tcp::seq_lt(seq, self.seq)
+    }
+
+    /// Sequence space believed to still be in the network (pipe, RFC 6675)
+    pub fn pipe(&self) -> usize {
+        self.retransmit
+            .iter()
+            .filter(|segment| !segment.lost && !segment.sacked)
+            .map(|segment| segment.len() as usize)
+            .sum()
+    }
+
+    /// Queues data written by the socket, `fin` closing the sending direction after it
+    pub fn queue(&mut self, payload: &[u8], fin: bool) {
+        self.unsent.extend(payload);
+        self.fin_queued |= fin;
+    }
+
+    /// Whether the connection may send data in its current state
+    pub fn can_send(&self) -> bool {
+        self.state == SocketState::Established || self.state == SocketState::CloseWait
+    }
+
+    /// Picks the next segment allowed by the congestion and send windows, retransmissions
+    /// first, and returns it for transmission
+    pub fn next_segment(&mut self, now: Instant) -> Option<&Segment> {
+        if !self.can_send() && !self.retransmit.iter().any(|segment| segment.lost) {
+            return None;
+        }
+        if self.pacing && self.next_send.is_some_and(|next| next > now) {
+            return None;
+        }
+        // A tail loss probe may exceed the congestion window (RFC 8985 section 7.3), as may
+        // the new segments of F-RTO
+        let tail_probe = match self.rack {
+            Some(ref rack) => rack.probe_pending,
+            None => false,
+        };
+        let frto_probe = match self.frto_check {
+            Some(ref check) => check.allowance > 0,
+            None => false,
+        };
+        let window = if tail_probe || frto_probe {
+            cmp::max(self.window, 1)
+        } else {
+            cmp::min(self.cwnd, cmp::max(self.window, 1))
+        };
+        let pipe = self.pipe();
+
+        if let Some(index) = self.retransmit.iter().position(|segment| segment.lost) {
+            if pipe > 0 && pipe + self.retransmit[index].len() as usize > window {
+                return None;
+            }
+            let segment = &mut self.retransmit[index];
+            segment.lost = false;
+            segment.retransmitted = true;
+            segment.sent = now;
+            let len = segment.payload.len();
+            self.arm_timer(now);
+            self.pace(len, now);
+            return Some(&self.retransmit[index]);
+        }
+
+        if !self.can_send() || (self.unsent.is_empty() && !self.fin_queued) {
+            return None;
+        }
+
+        // Prefer a PLPMTUD probe when one is due and there is enough data to fill it
+        let mss = self.send_mss();
+        let max_mtu = self.max_mtu();
+        let overhead = HEADER_LEN + self.options_len();
+        let available = self.available();
+        let probe = match self.plpmtud {
+            Some(ref mut plpmtud) if self.recover.is_none() => {
+                plpmtud
+                    .probe_size(max_mtu, now)
+                    .map(|mtu| mtu - overhead)
+                    .filter(|&size| size > mss && available >= size)
+            }
+            _ => None,
+        };
+        let len = probe.unwrap_or_else(|| cmp::min(mss, available));
+        if pipe > 0 && pipe + len > window {
+            return None;
+        }
+
+        let data_seq = match self.mptcp {
+            Some(ref mut subflow) if subflow.joined() && len > 0 => subflow.take(len),
+            _ => None,
+        };
+        let payload: Vec<u8> = self.unsent.drain(..len).collect();
+        let fin = self.fin_queued && self.unsent.is_empty();
+        let segment = Segment {
+            seq: self.seq,
+            payload,
+            fin,
+            sent: now,
+            retransmitted: false,
+            lost: false,
+            sacked: false,
+            probe: probe.is_some(),
+            data_seq,
+        };
+        self.seq = segment.end();
+
+        if frto_probe {
+            self.frto_check.as_mut().unwrap().allowance -= 1;
+        }
+        if tail_probe {
+            let rack = self.rack.as_mut().unwrap();
+            rack.probe_pending = false;
+            rack.tlp_end_seq = Some(segment.end());
+            rack.tlp_is_retrans = false;
+        }
+
+        if let Some(size) = probe {
+            self.plpmtud.as_mut().unwrap().on_probe_sent(size + overhead);
+        }
+        if fin {
+            self.fin_queued = false;
+            self.state = match self.state {
+                SocketState::CloseWait => SocketState::LastAck,
+                _ => SocketState::FinWait1,
+            };
+        }
+
+        if self.retransmit.is_empty() {
+            self.stalled_since = Some(now);
+        }
+        self.retransmit.push_back(segment);
+        self.arm_timer(now);
+        self.arm_pto(now);
+        self.pace(len, now);
+        self.retransmit.back()
+    }
+
+    /// Pacing rate in bytes per second, if known yet
+    pub fn current_pacing_rate(&self) -> Option<u64> {
+        let srtt = cmp::max(self.rtt.srtt?.as_micros() as u64, 1);
+        let ratio = if self.ledbat.is_some() {
+            ledbat::PACING_RATIO
+        } else if self.cwnd < self.ssthresh {
+            PACING_SS_RATIO
+        } else {
+            PACING_CA_RATIO
+        };
+        Some(self.cwnd as u64 * ratio * 10_000 / srtt)
+    }
+
+    /// Holds back the next segment until one of `len` bytes has left at the pacing rate
+    fn pace(&mut self, len: usize, now: Instant) {
+        let rate = match self.current_pacing_rate() {
+            Some(rate) if self.pacing && rate > 0 => rate,
+            _ => return,
+        };
+        let wire_len = (len + HEADER_LEN + self.options_len()) as u64;
+        let start = self.next_send.map_or(now, |next| cmp::max(next, now));
+        self.next_send = Some(start + Duration::from_nanos(wire_len * 1_000_000_000 / rate));
+    }
+
+    /// Unsent bytes that may go into the next segment, which must not cross the end of an
+    /// MPTCP mapping
+    fn available(&self) -> usize {
+        match self.mptcp {
+            Some(ref subflow) if subflow.joined() => subflow.mapped_len(),
+            _ => self.unsent.len(),
+        }
+    }
+
+    fn arm_timer(&mut self, now: Instant) {
+        if self.rto_deadline.is_none() {
+            self.rto_deadline = Some(now + self.rtt.rto);
+        }
+    }
+
+    /// Arms the probe timeout of RACK-TLP while data is outstanding outside of recovery
+    fn arm_pto(&mut self, now: Instant) {
+        let arm = !self.retransmit.is_empty() && self.recover.is_none();
+        let single_segment = self.retransmit.len() == 1;
+        let srtt = self.rtt.srtt;
+        let rto_deadline = self.rto_deadline;
+        if let Some(ref mut rack) = self.rack {
+            if arm {
+                rack.arm_pto(srtt, single_segment, rto_deadline, now);
+            } else {
+                rack.pto_deadline = None;
+            }
+        }
+    }
+
+    /// Processes the acknowledgment fields of an incoming segment
+    pub fn on_ack(&mut self, repr: &tcp::Repr, now: Instant) {
+        let ack = match repr.ack() {
+            Some(ack) => ack,
+            None => return,
+        };
+        let window = (repr.window as usize) << self.snd_shift;
+        let una = self.una();
+        if tcp::seq_lt(self.seq, ack) {
+            return;
+        }
+        if self.sack {
+            self.on_sack(repr, now);
+        }
+        if self.frto_check.is_some() {
+            self.frto_on_ack(repr, ack, una);
+        }
+
+        if ack == una {
+            // Duplicate ACK (RFC 5681 section 2), RACK doesn't count them
+            let duplicate = repr.payload.is_empty() && window == self.window &&
+                            !self.retransmit.is_empty();
+            if duplicate {
+                self.stats.dup_acks += 1;
+                if self.rack.is_none() {
+                    self.on_dup_ack();
+                }
+            }
+            self.window = window;
+            self.rack_on_ack(ack, now);
+            return;
+        }
+        if !tcp::seq_lt(una, ack) {
+            return;
+        }
+        self.window = window;
+
+        let acked = self.acknowledge(ack, now);
+        self.buffers.release(acked);
+        self.dup_acks = 0;
+        self.timeouts = 0;
+        self.stalled_since = if self.retransmit.is_empty() {
+            None
+        } else {
+            Some(now)
+        };
+        self.rto_deadline = None;
+        if !self.retransmit.is_empty() {
+            self.arm_timer(now);
+        }
+
+        let mss = self.send_mss();
+        let reduced = self.dctcp_on_ack(repr, ack, acked, mss);
+        match self.recover {
+            Some(recover) if tcp::seq_lt(ack, recover) => {
+                // Partial ACK, the next hole was lost as well (RFC 6582). RACK decides
+                // that from transmit times instead.
+                if self.rack.is_none() {
+                    if let Some(segment) = self.retransmit.front_mut() {
+                        segment.lost = true;
+                    }
+                }
+            }
+            Some(_) => {
+                self.recover = None;
+                self.cwnd = self.ssthresh;
+            }
+            None if reduced => (),
+            None if self.ledbat.is_some() => {
+                let flight_size = self.seq.wrapping_sub(una) as usize;
+                self.ledbat_on_ack(repr, acked, flight_size, now);
+            }
+            None => {
+                if self.cwnd < self.ssthresh {
+                    self.cwnd += cmp::min(acked, mss);
+                } else {
+                    self.cwnd += cmp::max(mss * mss / self.cwnd, 1);
+                }
+            }
+        }
+        self.rack_on_ack(ack, now);
+    }
+
+    /// Estimates the extent of congestion from echoed marks and reduces the window by it,
+    /// returning true if it did
+    fn dctcp_on_ack(&mut self, repr: &tcp::Repr, ack: u32, acked: usize, mss: usize) -> bool {
+        let ece = self.ecn && repr.flags.ece();
+        let (cwnd, seq) = (self.cwnd, self.seq);
+        let dctcp = match self.dctcp {
+            Some(ref mut dctcp) => dctcp,
+            None => return false,
+        };
+        dctcp.on_ack(ack, acked, ece, seq);
+        if !ece {
+            return false;
+        }
+        match dctcp.on_congestion(cwnd, mss, ack, seq) {
+            Some(cwnd) => {
+                self.cwnd = cwnd;
+                self.ssthresh = cwnd;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Takes a delay sample and resizes the window by LEDBAT, measuring one-way delays by
+    /// timestamps and falling back to the RTT without them
+    fn ledbat_on_ack(&mut self, repr: &tcp::Repr, acked: usize, flight_size: usize, now: Instant) {
+        let delay = match repr.timestamps() {
+            Some((tsval, tsecr)) if self.timestamps => {
+                Some(tsval.wrapping_sub(tsecr) as i32 as i64)
+            }
+            _ => self.rtt.srtt.map(|srtt| srtt.as_millis() as i64),
+        };
+        let mss = self.send_mss();
+        let ledbat = self.ledbat.as_mut().unwrap();
+        if let Some(delay) = delay {
+            ledbat.on_delay(delay, now);
+        }
+        self.cwnd = ledbat.on_ack(self.cwnd, acked, flight_size, mss);
+    }
+
+    /// Marks segments covered by the SACK blocks of an incoming segment as delivered
+    fn on_sack(&mut self, repr: &tcp::Repr, now: Instant) {
+        for option in &repr.options {
+            let blocks = match *option {
+                tcp::TcpOption::Sack(ref blocks) => blocks,
+                _ => continue,
+            };
+            for &(left, right) in blocks.iter().flatten() {
+                for segment in self.retransmit.iter_mut() {
+                    if segment.sacked || !tcp::seq_le(left, segment.seq) ||
+                       !tcp::seq_le(segment.end(), right) {
+                        continue;
+                    }
+                    segment.sacked = true;
+                    segment.lost = false;
+                    if let Some(ref mut rack) = self.rack {
+                        rack.on_delivered(segment, now);
+                    }
+                }
+            }
+        }
+    }
+
+    /// RACK loss detection and Tail Loss Probe bookkeeping after an acknowledgment
+    fn rack_on_ack(&mut self, ack: u32, now: Instant) {
+        if self.rack.is_none() {
+            return;
+        }
+        let in_recovery = self.recover.is_some();
+        self.rack_detect_loss(now);
+
+        let probe_repaired = {
+            let rack = self.rack.as_mut().unwrap();
+            match rack.tlp_end_seq {
+                Some(end) if tcp::seq_le(end, ack) => {
+                    rack.tlp_end_seq = None;
+                    rack.tlp_is_retrans
+                }
+                _ => false,
+            }
+        };
+        if probe_repaired && !in_recovery && self.recover.is_none() {
+            // Without DSACK the retransmitted probe may have repaired a loss, which still
+            // calls for a congestion response (RFC 8985 section 7.4.2)
+            let mss = self.send_mss();
+            self.ssthresh = cmp::max(self.cwnd / 2, 2 * mss);
+            self.cwnd = self.ssthresh;
+        }
+        self.arm_pto(now);
+    }
+
+    fn rack_detect_loss(&mut self, now: Instant) {
+        let in_recovery = self.recover.is_some();
+        let srtt = self.rtt.srtt;
+        let lost = match self.rack {
+            Some(ref mut rack) => rack.detect_loss(&mut self.retransmit, in_recovery, srtt, now),
+            None => return,
+        };
+
+        if self.retransmit.iter().any(|segment| segment.probe && segment.lost) {
+            self.on_probe_lost();
+        }
+        if lost && !in_recovery {
+            self.enter_recovery();
+        }
+    }
+
+    /// Drops acknowledged data from the retransmission queue, returning the number of
+    /// newly acknowledged sequence numbers
+    fn acknowledge(&mut self, ack: u32, now: Instant) -> usize {
+        let una = self.una();
+        let mut sample = None;
+
+        while let Some(mut segment) = self.retransmit.pop_front() {
+            if tcp::seq_le(segment.end(), ack) {
+                if !segment.retransmitted {
+                    sample = Some(now.duration_since(segment.sent));
+                }
+                if !segment.sacked {
+                    if let Some(ref mut rack) = self.rack {
+                        rack.on_delivered(&segment, now);
+                    }
+                }
+                if segment.probe {
+                    if let Some(ref mut plpmtud) = self.plpmtud {
+                        plpmtud.on_probe_acked();
+                    }
+                }
+                continue;
+            }
+
+            // Partially acknowledged, keep the remainder
+            let acked = ack.wrapping_sub(segment.seq) as usize;
+            segment.payload.drain(..acked);
+            segment.seq = ack;
+            segment.data_seq = segment.data_seq.map(|data_seq| data_seq + acked as u64);
+            self.retransmit.push_front(segment);
+            break;
+        }
+
+        if let Some(rtt) = sample {
+            self.rtt.sample(rtt);
+        }
+        ack.wrapping_sub(una) as usize
+    }
+
+    fn on_dup_ack(&mut self) {
+        self.dup_acks += 1;
+        if self.dup_acks != DUP_ACK_THRESHOLD || self.recover.is_some() {
+            return;
+        }
+
+        if self.retransmit[0].probe {
+            self.on_probe_lost();
+            return;
+        }
+
+        // Fast retransmit
+        self.enter_recovery();
+        self.retransmit[0].lost = true;
+    }
+
+    /// Halves the window and starts recovering everything sent so far
+    fn enter_recovery(&mut self) {
+        let mss = self.send_mss();
+        let flight_size = self.seq.wrapping_sub(self.una()) as usize;
+        self.ssthresh = if self.ledbat.is_some() {
+            Ledbat::on_loss(self.cwnd, mss)
+        } else {
+            cmp::max(flight_size / 2, 2 * mss)
+        };
+        self.cwnd = self.ssthresh;
+        self.recover = Some(self.seq);
+    }
+
+    /// The probe at the front of the queue was lost, resend its data in regular segments
+    /// without touching the congestion state
+    fn on_probe_lost(&mut self) {
+        if let Some(ref mut plpmtud) = self.plpmtud {
+            plpmtud.on_probe_lost();
+        }
+        let mss = self.send_mss();
+        self.resegment(mss);
+    }
+
+    /// Takes the steps of F-RTO, or of Eifel with timestamps, on an ACK after a timeout
+    fn frto_on_ack(&mut self, repr: &tcp::Repr, ack: u32, una: u32) {
+        let (recover, retransmit_ts, probing) = match self.frto_check {
+            Some(ref check) => (check.recover, check.retransmit_ts, check.probing),
+            None => return,
+        };
+        let advanced = tcp::seq_lt(una, ack);
+
+        // The ACK echoes the timestamp of the original transmission
+        let eifel = match (retransmit_ts, repr.timestamps()) {
+            (Some(retransmit_ts), Some((_, tsecr))) => tcp::seq_lt(tsecr, retransmit_ts),
+            _ => false,
+        };
+        if advanced && (probing || eifel) {
+            self.on_spurious_timeout();
+            return;
+        }
+
+        if !advanced || probing || !tcp::seq_lt(ack, recover) || self.unsent.is_empty() {
+            // Conventional recovery, everything outstanding is presumed lost
+            if probing {
+                for segment in &mut self.retransmit {
+                    segment.lost = !segment.sacked;
+                }
+            }
+            self.frto_check = None;
+            return;
+        }
+
+        // The retransmission arrived, see whether new segments get the rest acknowledged
+        // instead of retransmitting it (step 2b)
+        for segment in &mut self.retransmit {
+            segment.lost = false;
+        }
+        let check = self.frto_check.as_mut().unwrap();
+        check.probing = true;
+        check.allowance = FRTO_NEW_SEGMENTS;
+    }
+
+    /// Undoes the congestion response to a timeout that turned out spurious
+    fn on_spurious_timeout(&mut self) {
+        let check = self.frto_check.take().unwrap();
+        self.cwnd = check.cwnd;
+        self.ssthresh = check.ssthresh;
+        for segment in &mut self.retransmit {
+            segment.lost = false;
+        }
+        self.stats.spurious_rtos += 1;
+    }
+
+    /// Handles expired retransmission, reordering and probe timers
+    pub fn on_timer(&mut self, now: Instant) {
+        self.on_rack_timer(now);
+
+        match self.rto_deadline {
+            Some(deadline) if deadline <= now => (),
+            _ => return,
+        }
+        self.rto_deadline = None;
+        if self.retransmit.is_empty() {
+            return;
+        }
+        self.rtt.backoff();
+
+        if self.retransmit[0].probe {
+            self.on_probe_lost();
+            self.arm_timer(now);
+            return;
+        }
+
+        self.timeouts += 1;
+        if self.timeouts >= BLACK_HOLE_TIMEOUTS && self.plpmtud.is_some() {
+            self.plpmtud.as_mut().unwrap().on_black_hole();
+            let mss = self.send_mss();
+            self.resegment(mss);
+        }
+
+        // Only the first timeout in a row can be checked, later ones end the check
+        self.frto_check = if self.frto && self.timeouts == 1 {
+            let retransmit_ts = if self.timestamps {
+                Some(self.tsval(now))
+            } else {
+                None
+            };
+            Some(Frto {
+                     recover: self.seq,
+                     retransmit_ts,
+                     probing: false,
+                     allowance: 0,
+                     cwnd: self.cwnd,
+                     ssthresh: self.ssthresh,
+                 })
+        } else {
+            None
+        };
+
+        // Back to slow start, everything outstanding is presumed lost (RFC 5681 section 3.1)
+        let mss = self.send_mss();
+        self.ssthresh = cmp::max(self.pipe() / 2, 2 * mss);
+        self.cwnd = mss;
+        self.recover = None;
+        self.dup_acks = 0;
+        for segment in &mut self.retransmit {
+            segment.lost = !segment.sacked;
+        }
+        self.arm_timer(now);
+
+        if let Some(ref mut rack) = self.rack {
+            rack.pto_deadline = None;
+            rack.probe_pending = false;
+            rack.tlp_end_seq = None;
+        }
+    }
+
+    fn on_rack_timer(&mut self, now: Instant) {
+        let (reorder, pto) = match self.rack {
+            Some(ref rack) => {
+                (expired(rack.reorder_deadline, now), expired(rack.pto_deadline, now))
+            }
+            None => return,
+        };
+        if reorder {
+            self.rack_detect_loss(now);
+        }
+
+        // An RTO expiring at the same time takes precedence over the probe
+        if pto && !expired(self.rto_deadline, now) {
+            self.send_tail_probe(now);
+        }
+    }
+
+    /// Solicits an ACK after the probe timeout, with new data if there is any and the last
+    /// segment again otherwise (RFC 8985 section 7.3)
+    fn send_tail_probe(&mut self, now: Instant) {
+        let new_data = self.can_send() && (!self.unsent.is_empty() || self.fin_queued);
+        let rack = self.rack.as_mut().unwrap();
+        rack.pto_deadline = None;
+
+        if new_data {
+            rack.probe_pending = true;
+        } else if let Some(segment) = self.retransmit.iter_mut().rev().find(|s| !s.sacked) {
+            segment.lost = true;
+            rack.tlp_end_seq = Some(segment.end());
+            rack.tlp_is_retrans = true;
+        } else {
+            return;
+        }
+        self.rto_deadline = Some(now + self.rtt.rto);
+    }
+
+    /// Splits queued segments larger than `mss` and marks the pieces lost so they get
+    /// retransmitted
+    pub fn resegment(&mut self, mss: usize) {
+        let mut queue = VecDeque::with_capacity(self.retransmit.len());
+
+        for segment in self.retransmit.drain(..) {
+            if segment.payload.len() <= mss {
+                queue.push_back(segment);
+                continue;
+            }
+
+            let chunks = segment.payload.len().div_ceil(mss);
+            for (index, chunk) in segment.payload.chunks(mss).enumerate() {
+                let offset = (index * mss) as u32;
+                queue.push_back(Segment {
+                                    seq: segment.seq.wrapping_add(offset),
+                                    payload: chunk.to_vec(),
+                                    fin: segment.fin && index == chunks - 1,
+                                    sent: segment.sent,
+                                    retransmitted: true,
+                                    lost: !segment.sacked,
+                                    sacked: segment.sacked,
+                                    probe: false,
+                                    data_seq: segment
+                                        .data_seq
+                                        .map(|data_seq| data_seq + offset as u64),
+                                });
+            }
+        }
+
+        self.retransmit = queue;
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // Subflows of an MPTCP connection share the buffers of its session
+        let joined = self.mptcp.as_ref().is_some_and(|subflow| subflow.joined());
+        if !joined {
+            self.buffers.close();
+        }
+    }
+}
+
+/// Whether a timer armed for `deadline` has gone off
+fn expired(deadline: Option<Instant>, now: Instant) -> bool {
+    match deadline {
+        Some(deadline) => deadline <= now,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::memory::Memory;
+
+    const SEQ: u32 = 1000;
+
+    /// An established connection with F-RTO and `sent` segments outstanding, plenty more
+    /// queued, and their retransmission timeout just expired
+    fn timed_out(sent: usize) -> (Connection, Instant) {
+        let now = Instant::now();
+        let memory = Arc::new(Memory::default());
+        let buffers = Arc::new(Buffers::new(buffers::DEFAULT_SNDBUF, memory));
+        let mut connection = Connection::new(SocketState::SynSent, None, 1500, buffers);
+        connection.establish(SEQ, 5000, 65535);
+        connection.frto = true;
+
+        let mss = connection.send_mss();
+        connection.queue(&vec![0; 10 * mss], false);
+        for _ in 0..sent {
+            assert!(connection.next_segment(now).is_some());
+        }
+        let now = connection.rto_deadline.unwrap();
+        connection.on_timer(now);
+        assert!(connection.frto_check.is_some());
+        assert_eq!(connection.cwnd, mss);
+        (connection, now)
+    }
+
+    fn ack(connection: &mut Connection, ack: u32, now: Instant) {
+        let repr = tcp::Repr {
+            src_port: 80,
+            dst_port: 1000,
+            seq: 5000,
+            ack_num: ack,
+            flags: tcp::Flags::ACK,
+            window: 65535,
+            urgent: 0,
+            options: Vec::new(),
+            payload: &[],
+        };
+        connection.on_ack(&repr, now);
+    }
+
+    #[test]
+    fn frto_spurious_timeout() {
+        let (mut connection, now) = timed_out(4);
+        let mss = connection.send_mss();
+
+        // The retransmission is acknowledged, new data is sent instead of the rest
+        assert_eq!(connection.next_segment(now).unwrap().seq, SEQ);
+        ack(&mut connection, SEQ + mss as u32, now);
+        assert!(connection.retransmit.iter().all(|segment| !segment.lost));
+        for _ in 0..FRTO_NEW_SEGMENTS {
+            let segment = connection.next_segment(now).unwrap();
+            assert!(!segment.retransmitted);
+        }
+
+        // Which gets the original segments acknowledged: the timeout was spurious and its
+        // congestion response undone
+        ack(&mut connection, SEQ + 2 * mss as u32, now);
+        assert!(connection.frto_check.is_none());
+        assert_eq!(connection.ssthresh, usize::MAX);
+        assert!(connection.cwnd >= INITIAL_WINDOW * mss);
+        assert!(connection.retransmit.iter().all(|segment| !segment.lost));
+        assert_eq!(connection.stats.spurious_rtos, 1);
+    }
+
+    #[test]
+    fn frto_genuine_timeout() {
+        let (mut connection, now) = timed_out(4);
+        let mss = connection.send_mss();
+
+        assert!(connection.next_segment(now).is_some());
+        ack(&mut connection, SEQ + mss as u32, now);
+        for _ in 0..FRTO_NEW_SEGMENTS {
+            assert!(connection.next_segment(now).is_some());
+        }
+
+        // A duplicate ACK for the new data: the rest was lost after all
+        ack(&mut connection, SEQ + mss as u32, now);
+        assert!(connection.frto_check.is_none());
+        assert_eq!(connection.ssthresh, 2 * mss);
+        assert_eq!(connection.cwnd, 2 * mss);
+        assert!(connection.retransmit.iter().all(|segment| segment.lost));
+        assert_eq!(connection.stats.spurious_rtos, 0);
+    }
+
+    #[test]
+    fn frto_without_new_data() {
+        // Nothing new to send, so recovery goes on conventionally
+        let (mut connection, now) = timed_out(10);
+        let mss = connection.send_mss();
+
+        assert!(connection.next_segment(now).is_some());
+        ack(&mut connection, SEQ + mss as u32, now);
+        assert!(connection.frto_check.is_none());
+        assert!(connection.retransmit.iter().all(|segment| segment.lost));
+    }
+}
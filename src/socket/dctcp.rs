@@ -0,0 +1,130 @@
+use std::cmp;
+
+use tcp;
+
+/// Scale of `alpha`, which is a fraction
+const ALPHA_ONE: usize = 1024;
+
+/// Weight of a new observation in `alpha` as a shift, g = 1/16 (RFC 8257 section 4.2)
+const G_SHIFT: usize = 4;
+
+/// Data Center TCP (RFC 8257) sender state of one connection
+#[derive(Debug)]
+pub struct Dctcp {
+    /// Estimated fraction of bytes marked CE, starting out at one (DCTCP.Alpha)
+    alpha: usize,
+    /// End of the current observation window (DCTCP.WindowEnd)
+    window_end: Option<u32>,
+    /// Bytes acknowledged in the current observation window (DCTCP.BytesAcked)
+    bytes_acked: usize,
+    /// Bytes acknowledged with ECE in the current observation window (DCTCP.BytesMarked)
+    bytes_marked: usize,
+    /// The window was reduced for data up to here already, once per round trip
+    reduced_until: Option<u32>,
+}
+
+impl Dctcp {
+    pub fn new() -> Self {
+        Dctcp {
+            alpha: ALPHA_ONE,
+            window_end: None,
+            bytes_acked: 0,
+            bytes_marked: 0,
+            reduced_until: None,
+        }
+    }
+
+    /// Accounts for `acked` bytes acknowledged up to `ack`, updating `alpha` once a window
+    /// worth of data up to `snd_nxt` has been acknowledged
+    pub fn on_ack(&mut self, ack: u32, acked: usize, ece: bool, snd_nxt: u32) {
+        self.bytes_acked += acked;
+        if ece {
+            self.bytes_marked += acked;
+        }
+
+        let window_end = *self.window_end.get_or_insert(snd_nxt);
+        if tcp::seq_lt(ack, window_end) {
+            return;
+        }
+        let marked = self.bytes_marked * ALPHA_ONE / cmp::max(self.bytes_acked, 1);
+        self.alpha = self.alpha - (self.alpha >> G_SHIFT) + (marked >> G_SHIFT);
+        self.window_end = Some(snd_nxt);
+        self.bytes_acked = 0;
+        self.bytes_marked = 0;
+    }
+
+    /// Window after congestion was experienced, reduced in proportion to its extent and
+    /// once per round trip, `None` when already reduced for data up to `ack`
+    pub fn on_congestion(&mut self,
+                         cwnd: usize,
+                         mss: usize,
+                         ack: u32,
+                         snd_nxt: u32)
+                         -> Option<usize> {
+        if let Some(until) = self.reduced_until {
+            if tcp::seq_lt(ack, until) {
+                return None;
+            }
+        }
+        self.reduced_until = Some(snd_nxt);
+        let cwnd = cwnd - cwnd * self.alpha / (2 * ALPHA_ONE);
+        Some(cmp::max(cwnd, 2 * mss))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alpha_moving_average() {
+        let mut dctcp = Dctcp::new();
+
+        // Nothing changes before the first window is acknowledged in full
+        dctcp.on_ack(1000, 1000, false, 2000);
+        assert_eq!(dctcp.alpha, ALPHA_ONE);
+
+        // An unmarked window takes off g = 1/16
+        dctcp.on_ack(2000, 1000, false, 3000);
+        assert_eq!(dctcp.alpha, 960);
+
+        // A fully marked one adds g back in
+        dctcp.on_ack(3000, 1000, true, 4000);
+        assert_eq!(dctcp.alpha, 960 - 60 + 64);
+
+        // Half marked windows converge to one half
+        let mut ack = 4000;
+        for _ in 0..200 {
+            dctcp.on_ack(ack - 500, 500, true, ack);
+            dctcp.on_ack(ack, 500, false, ack + 1000);
+            ack += 1000;
+        }
+        assert!(dctcp.alpha >= ALPHA_ONE / 2 && dctcp.alpha < ALPHA_ONE / 2 + 16);
+
+        // Unmarked windows decay it to nearly nothing
+        for _ in 0..200 {
+            dctcp.on_ack(ack, 1000, false, ack + 1000);
+            ack += 1000;
+        }
+        assert!(dctcp.alpha < 1 << G_SHIFT);
+    }
+
+    #[test]
+    fn window_cut() {
+        let mut dctcp = Dctcp::new();
+
+        // Halved while everything is marked
+        assert_eq!(dctcp.on_congestion(100_000, 1000, 0, 10_000), Some(50_000));
+
+        // Once per round trip only
+        assert_eq!(dctcp.on_congestion(50_000, 1000, 5000, 20_000), None);
+
+        // In proportion to the marked fraction
+        dctcp.alpha = ALPHA_ONE / 2;
+        assert_eq!(dctcp.on_congestion(100_000, 1000, 10_000, 30_000), Some(75_000));
+
+        // No lower than two segments
+        dctcp.alpha = ALPHA_ONE;
+        assert_eq!(dctcp.on_congestion(3000, 1000, 30_000, 40_000), Some(2000));
+    }
+}
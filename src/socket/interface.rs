@@ -1,27 +1,238 @@
+use std::cmp;
 use std::collections::HashMap;
 use std::collections::hash_map::Entry;
 use std::mem;
 use std::ptr;
 use std::sync::{Arc, Mutex, mpsc};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{RecvTimeoutError, TrySendError};
 use std::thread;
 use std::time::{Duration, Instant};
 
-use super::{PacketBuffer, Socket, SocketState};
+use byteorder::{ByteOrder, NetworkEndian};
+
+use super::{CongestionControl, Linger, PacketBuffer, Socket, SocketState};
+use super::auth::{Auth, KeyChain, Signer};
+use super::buffers::{self, Autotune, Buffers};
+use super::connection::{self, Connection, DEFAULT_MSS, HEADER_LEN, Segment};
+use super::isn::IsnGenerator;
+use super::memory::Memory;
+use super::mptcp::{DataLevel, FAILOVER_TIMEOUTS, Handshake, Session, SessionMap, Subflow};
+use super::path_mtu::{self, PathMtuCache};
+use super::plpmtud::Plpmtud;
+use super::rack::{LossDetection, Rack};
+use super::uto::UserTimeout;
 use tcp;
+use tcp_ao;
+use icmp;
+use mptcp::{self, MptcpOption};
 use ipv4;
 use platform::{MappedBuffer, RawSocket};
 use error::{Error, SocketError};
 
-const RECV_BUF_LEN: usize = 17000;
+/// Window of SYN and SYN-ACK segments, which is never scaled
+const WINDOW_SIZE: u16 = 43690;
+
+/// MSS announced in our SYNs, derived from the MTU of the link
+const ADVERTISED_MSS: u16 = (path_mtu::DEFAULT_MTU - HEADER_LEN) as u16;
+
+/// Interval at which the send thread checks connection timers
+const TIMER_GRANULARITY: Duration = Duration::from_millis(10);
+
+/// Longest `stop` waits for closed connections to complete their closing handshake, as
+/// far as their linger policy doesn't have it wait longer
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Half-open connections allowed by default, as Linux' `tcp_max_syn_backlog`
+const DEFAULT_SYN_QUEUE: usize = 128;
+
+type SocketMap = HashMap<tcp::Endpoint, Connection>;
+
+/// What happens to a SYN that finds the SYN queue full, or a handshake completing with the
+/// accept backlog full
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Overflow {
+    /// Ignore the segment, the peer tries again once its SYN times out or our SYN-ACK is
+    /// retransmitted
+    #[default]
+    Drop,
+    /// Refuse the connection with a RST
+    Reset,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Config {
+    /// Packetization layer path MTU discovery for new connections
+    plpmtud: bool,
+    loss_detection: LossDetection,
+    congestion_control: CongestionControl,
+    /// Offer Multipath TCP in SYNs and accept it from peers
+    mptcp: bool,
+    /// Pace the segments of new connections
+    pacing: bool,
+    /// Check retransmission timeouts for being spurious with F-RTO
+    frto: bool,
+    /// Offer timestamps in SYNs and accept them from peers
+    timestamps: bool,
+    /// User timeout of new connections, advertised in their SYNs
+    user_timeout: Option<Duration>,
+    half_open_timeout: Duration,
+    idle_timeout: Option<Duration>,
+    /// Most connections in SYN-RECEIVED at a time
+    syn_queue: usize,
+    overflow: Overflow,
+    /// Initial and largest receive buffer of new connections
+    rcvbuf: (usize, usize),
+    /// Initial and largest send buffer of new connections
+    sndbuf: (usize, usize),
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            plpmtud: false,
+            loss_detection: LossDetection::default(),
+            congestion_control: CongestionControl::default(),
+            mptcp: false,
+            pacing: false,
+            frto: false,
+            timestamps: false,
+            user_timeout: None,
+            half_open_timeout: connection::handshake_timeout(),
+            idle_timeout: None,
+            syn_queue: DEFAULT_SYN_QUEUE,
+            overflow: Overflow::default(),
+            rcvbuf: (buffers::DEFAULT_RCVBUF, buffers::DEFAULT_RCVBUF_MAX),
+            sndbuf: (buffers::DEFAULT_SNDBUF, buffers::DEFAULT_SNDBUF_MAX),
+        }
+    }
+}
+
+impl Config {
+    fn connection(&self,
+                  state: SocketState,
+                  iss: u32,
+                  rx: Option<mpsc::Sender<PacketBuffer>>,
+                  path_mtu: usize,
+                  memory: &Arc<Memory>)
+                  -> Connection {
+        let buffers = Arc::new(Buffers::new(self.sndbuf.0, memory.clone()));
+        let mut connection = Connection::new(state, rx, path_mtu, buffers);
+        connection.iss = iss;
+        connection.seq = iss;
+        if self.plpmtud {
+            connection.plpmtud = Some(Plpmtud::new());
+        }
+        if self.loss_detection == LossDetection::Rack {
+            connection.rack = Some(Rack::new());
+        }
+        connection.set_congestion_control(self.congestion_control);
+        connection.ecn = self.congestion_control == CongestionControl::Dctcp;
+        connection.pacing = self.pacing;
+        connection.frto = self.frto;
+        connection.timestamps = self.timestamps;
+        connection.user_timeout = UserTimeout::new(self.user_timeout);
+        connection.half_open_timeout = self.half_open_timeout;
+        connection.idle_timeout = self.idle_timeout;
+        connection.autotune = Autotune::new(self.rcvbuf.0, self.rcvbuf.1, self.sndbuf.1);
+        connection.rcv_shift = Some(connection.autotune.window_shift());
+        connection
+    }
+
+    /// Whether SYNs offer SACK, only RACK makes use of it
+    fn sack(&self) -> bool {
+        self.loss_detection == LossDetection::Rack
+    }
+
+    /// Whether SACK is used with the peer that sent the SYN `tcprepr`, only if both ends
+    /// offered it
+    fn sack_permitted(&self, tcprepr: &tcp::Repr) -> bool {
+        self.sack() && tcprepr.options.contains(&tcp::TcpOption::SackPermitted)
+    }
+
+    fn syn_options(&self, sack: bool) -> Vec<tcp::TcpOption<'static>> {
+        let mut options = vec![tcp::TcpOption::Mss(ADVERTISED_MSS)];
+        if sack {
+            options.push(tcp::TcpOption::SackPermitted);
+        }
+        options
+    }
+
+    /// MP_CAPABLE for our SYN or SYN-ACK, MPTCP not fitting next to MD5 or TCP-AO options
+    fn mptcp_capable(&self,
+                     connection: &mut Connection,
+                     remote: tcp::Endpoint,
+                     irs: u32)
+                     -> Option<tcp::TcpOption<'static>> {
+        if !self.mptcp || connection.auth.is_some() {
+            return None;
+        }
+        let local_key = mptcp::new_key();
+        let mut subflow = Subflow::capable(remote, local_key, connection.iss);
+        subflow.irs = irs;
+        connection.mptcp = Some(subflow);
+
+        let syn_ack = connection.state == SocketState::SynReceived;
+        Some(tcp::TcpOption::Mptcp(MptcpOption::Capable {
+                                       version: mptcp::VERSION,
+                                       flags: mptcp::FLAG_HMAC_SHA256,
+                                       sender_key: if syn_ack { Some(local_key) } else { None },
+                                       receiver_key: None,
+                                   }))
+    }
+}
+
+/// MSS announced by the peer that sent the SYN `tcprepr`, the default one if it didn't
+fn peer_mss(tcprepr: &tcp::Repr) -> usize {
+    tcprepr.max_seg_size().map_or(DEFAULT_MSS, |mss| mss as usize)
+}
+
+/// State of an interface shared with its threads
+#[derive(Clone)]
+struct Shared {
+    raw: Arc<RawSocket>,
+    sockets: Arc<Mutex<SocketMap>>,
+    sessions: Arc<Mutex<SessionMap>>,
+    path_mtu: Arc<Mutex<PathMtuCache>>,
+    keys: Arc<Mutex<KeyChain>>,
+    isn: Arc<IsnGenerator>,
+    /// Read for every segment, so that settings apply to connections accepted later
+    config: Arc<Mutex<Config>>,
+    /// SYNs turned away because the SYN queue or the accept backlog was full
+    overflows: Arc<AtomicUsize>,
+    memory: Arc<Memory>,
+}
+
+impl Shared {
+    fn config(&self) -> Config {
+        *self.config.lock().unwrap()
+    }
+}
 
-type SocketMap = HashMap<tcp::Endpoint, (SocketState, Option<mpsc::Sender<PacketBuffer>>)>;
+/// What the receive thread processes segments with
+struct RecvContext {
+    shared: Shared,
+    /// Takes the sockets of connections once their handshake completes
+    socket_send: mpsc::SyncSender<Socket>,
+    /// Handed to those sockets for writing through the send thread
+    tx_send: mpsc::Sender<(tcp::Endpoint, PacketBuffer)>,
+}
+
+/// What the segments sent in one go by `output` have in common
+struct Batch {
+    ack: u32,
+    window: u16,
+    signer: Option<Signer>,
+    data_level: Option<DataLevel>,
+    timestamp: Option<tcp::TcpOption<'static>>,
+    /// Echo the CE mark of the latest segment received, like the pure ACKs
+    ece: bool,
+}
 
 pub struct Interface {
     running: Arc<AtomicBool>,
     endpoint: tcp::Endpoint,
-    raw: Arc<RawSocket>,
-    sockets: Arc<Mutex<SocketMap>>,
+    shared: Shared,
 
     send_thread: Option<thread::JoinHandle<()>>,
     recv_thread: Option<thread::JoinHandle<()>>,
@@ -31,9 +242,18 @@ impl Interface {
     pub fn new(endpoint: tcp::Endpoint, raw: RawSocket) -> Self {
         Interface {
             running: Arc::new(AtomicBool::new(false)),
-            endpoint: endpoint,
-            raw: Arc::new(raw),
-            sockets: Arc::new(Mutex::new(HashMap::new())),
+            endpoint,
+            shared: Shared {
+                raw: Arc::new(raw),
+                sockets: Arc::new(Mutex::new(HashMap::new())),
+                sessions: Arc::new(Mutex::new(HashMap::new())),
+                path_mtu: Arc::new(Mutex::new(PathMtuCache::default())),
+                keys: Arc::new(Mutex::new(KeyChain::default())),
+                isn: Arc::new(IsnGenerator::new()),
+                config: Arc::new(Mutex::new(Config::default())),
+                overflows: Arc::new(AtomicUsize::new(0)),
+                memory: Arc::new(Memory::default()),
+            },
 
             send_thread: None,
             recv_thread: None,
@@ -41,60 +261,365 @@ impl Interface {
     }
 
     pub fn connect(&mut self, remote: tcp::Endpoint) -> Result<Socket, SocketError> {
-        let (tx, rx) = mpsc::channel::<Socket>();
+        let (tx, rx) = mpsc::sync_channel::<Socket>(1);
         if !self.running.load(Ordering::Relaxed) {
             self.start(tx);
         }
+        if self.shared.memory.exhausted() {
+            return Err(SocketError::NoMemory);
+        }
+        let config = *self.shared.config.lock().unwrap();
         {
-            let mut sockets = self.sockets.lock().unwrap();
-            sockets.insert(remote, (SocketState::SynSent, None));
-            Self::send_syn(&self.raw, self.endpoint, remote);
+            let path_mtu = self.path_mtu(remote.addr);
+            let iss = self.shared.isn.generate(self.endpoint, remote);
+            let mut connection = config.connection(SocketState::SynSent,
+                                                   iss,
+                                                   None,
+                                                   path_mtu,
+                                                   &self.shared.memory);
+            connection.auth = self.shared.keys.lock().unwrap().auth(remote, iss, None);
+
+            // A SYN of ours could be taken for a segment of a connection still in TIME-WAIT,
+            // unless it's clearly beyond that connection
+            let mut sockets = self.shared.sockets.lock().unwrap();
+            match sockets.get(&remote) {
+                Some(old) if old.state == SocketState::TimeWait && old.accepts_connect(iss) => {
+                    let old = sockets.remove(&remote).unwrap();
+                    connection.take_over(&old);
+                    let mut sessions = self.shared.sessions.lock().unwrap();
+                    Self::close_subflow(&self.shared.raw,
+                                        self.endpoint,
+                                        remote,
+                                        old,
+                                        &mut sockets,
+                                        &mut sessions);
+                }
+                Some(_) => return Err(SocketError::InUse),
+                None => (),
+            }
+
+            let mut options = config.syn_options(config.sack());
+            options.extend(connection.user_timeout.option());
+            options.extend(connection.window_scale_option());
+            options.extend(config.mptcp_capable(&mut connection, remote, 0));
+            let now = Instant::now();
+            connection.arm_syn_timer(options.clone(), now);
+            options.extend(connection.timestamp_option(now));
+            Self::send_syn(&self.shared.raw, self.endpoint, remote, &mut connection, options);
+            sockets.insert(remote, connection);
+        }
+        rx.recv_timeout(config.half_open_timeout)
+            .map_err(|_| {
+                let mut sockets = self.shared.sockets.lock().unwrap();
+                let pending = sockets
+                    .get(&remote)
+                    .is_some_and(|connection| connection.state == SocketState::SynSent);
+                if pending {
+                    sockets.remove(&remote);
+                }
+                SocketError::Timeout
+            })
+    }
+
+    /// Current path MTU towards `addr`
+    pub fn path_mtu(&self, addr: ipv4::Address) -> usize {
+        self.shared.path_mtu.lock().unwrap().get(addr)
+    }
+
+    /// Enables packetization layer path MTU discovery (RFC 4821) for connections opened
+    /// from now on, for paths where ICMP messages get filtered
+    pub fn set_plpmtud(&mut self, enabled: bool) {
+        self.shared.config.lock().unwrap().plpmtud = enabled;
+    }
+
+    /// Selects how connections opened from now on detect lost segments
+    pub fn set_loss_detection(&mut self, loss_detection: LossDetection) {
+        self.shared.config.lock().unwrap().loss_detection = loss_detection;
+    }
+
+    /// Selects the congestion control of connections opened from now on
+    ///
+    /// LEDBAT measures one-way delays by timestamps and falls back to the RTT when the peer
+    /// doesn't take them up, so it's best used with `set_timestamps`. DCTCP relies on ECN,
+    /// which is only offered in the handshakes of connections it is selected for here.
+    pub fn set_congestion_control(&mut self, congestion_control: CongestionControl) {
+        self.shared.config.lock().unwrap().congestion_control = congestion_control;
+    }
+
+    /// Paces the segments of connections opened from now on at a rate derived from their
+    /// congestion window and SRTT, rather than sending what the windows allow at once
+    pub fn set_pacing(&mut self, enabled: bool) {
+        self.shared.config.lock().unwrap().pacing = enabled;
+    }
+
+    /// Detects spurious retransmission timeouts with F-RTO (RFC 5682) on connections opened
+    /// from now on, undoing the congestion response to them
+    ///
+    /// With timestamps in use, the Eifel algorithm (RFC 3522) detects them a round trip
+    /// sooner.
+    pub fn set_frto(&mut self, enabled: bool) {
+        self.shared.config.lock().unwrap().frto = enabled;
+    }
+
+    /// Offers the timestamps option (RFC 7323) on connections opened from now on
+    pub fn set_timestamps(&mut self, enabled: bool) {
+        self.shared.config.lock().unwrap().timestamps = enabled;
+    }
+
+    /// Aborts connections opened from now on once sent data stays unacknowledged for longer
+    /// than `timeout`, which is advertised to peers with the User Timeout Option (RFC 5482)
+    ///
+    /// The timeout a peer advertises is only taken over, within sane bounds, by connections
+    /// without a timeout set here or by `Socket::set_user_timeout`.
+    pub fn set_user_timeout(&mut self, timeout: Option<Duration>) {
+        self.shared.config.lock().unwrap().user_timeout = timeout;
+    }
+
+    /// Gives up connections opened from now on whose handshake takes longer than `timeout`,
+    /// by default once their SYN or SYN-ACK went unanswered through all retransmissions
+    pub fn set_half_open_timeout(&mut self, timeout: Duration) {
+        self.shared.config.lock().unwrap().half_open_timeout = timeout;
+    }
+
+    /// Resets connections opened from now on once nothing is received from the peer for
+    /// longer than `timeout`, their sockets failing with `TimedOut`
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.shared.config.lock().unwrap().idle_timeout = timeout;
+    }
+
+    /// Sizes the receive buffer of connections opened from now on, starting at `initial` and
+    /// growing up to `max` as the application keeps up with the data
+    ///
+    /// The window scale option (RFC 7323) is offered as far as `max` needs it. Buffers of
+    /// all connections together are bounded by `set_memory_limit`.
+    pub fn set_receive_buffer(&mut self, initial: usize, max: usize) {
+        self.shared.config.lock().unwrap().rcvbuf = (initial, max);
+    }
+
+    /// Sizes the send buffer of connections opened from now on, starting at `initial` and
+    /// growing up to `max` with the congestion window
+    ///
+    /// `Socket::write` blocks while the send buffer is full. Buffers of all connections
+    /// together are bounded by `set_memory_limit`.
+    pub fn set_send_buffer(&mut self, initial: usize, max: usize) {
+        self.shared.config.lock().unwrap().sndbuf = (initial, max);
+    }
+
+    /// Offers Multipath TCP (RFC 8684) on connections opened from now on
+    ///
+    /// Connections fall back to regular TCP when the peer doesn't take it up. MPTCP is not
+    /// used together with MD5 or TCP-AO keys, there is no room for both options.
+    pub fn set_mptcp(&mut self, enabled: bool) {
+        self.shared.config.lock().unwrap().mptcp = enabled;
+    }
+
+    /// Opens another subflow of the MPTCP connection of `socket` to `remote`, for example
+    /// another address of the peer reached over another uplink
+    ///
+    /// Backup subflows only carry data while no regular subflow is left.
+    pub fn add_subflow(&mut self,
+                       socket: &Socket,
+                       remote: tcp::Endpoint,
+                       backup: bool)
+                       -> Result<(), SocketError> {
+        if self.shared.memory.exhausted() {
+            return Err(SocketError::NoMemory);
+        }
+        let mut sockets = self.shared.sockets.lock().unwrap();
+        let sessions = self.shared.sessions.lock().unwrap();
+        let session = sessions
+            .get(&socket.endpoint)
+            .ok_or(SocketError::Closed)?;
+        Self::join(&self.shared,
+                   self.endpoint,
+                   remote,
+                   socket.endpoint,
+                   session,
+                   &mut sockets,
+                   backup);
+        Ok(())
+    }
+
+    /// Announces another address of ours to the peer of `socket` with ADD_ADDR, for it to
+    /// open subflows to
+    pub fn announce_address(&mut self,
+                            socket: &Socket,
+                            address_id: u8,
+                            addr: ipv4::Address)
+                            -> Result<(), SocketError> {
+        let mut sockets = self.shared.sockets.lock().unwrap();
+        let sessions = self.shared.sessions.lock().unwrap();
+        let session = sessions
+            .get(&socket.endpoint)
+            .ok_or(SocketError::Closed)?;
+        let remote = *session.subflows.first().ok_or(SocketError::Closed)?;
+        let connection = sockets.get_mut(&remote).ok_or(SocketError::Closed)?;
+
+        let hmac = mptcp::add_addr_hmac(session.local_key,
+                                        session.remote_key,
+                                        address_id,
+                                        addr,
+                                        None);
+        let option = MptcpOption::AddAddr {
+            echo: false,
+            address_id,
+            addr,
+            port: None,
+            hmac: Some(hmac),
+        };
+        Self::send_control(&self.shared.raw,
+                           self.endpoint,
+                           remote,
+                           connection,
+                           vec![tcp::TcpOption::Mptcp(option)]);
+        Ok(())
+    }
+
+    /// Signs segments to and requires signatures from `remote` with TCP MD5 (RFC 2385)
+    ///
+    /// A port of 0 applies the key to every port of the address. Only connections opened
+    /// from now on are affected.
+    pub fn set_md5_key(&mut self, remote: tcp::Endpoint, key: &[u8]) {
+        self.shared.keys.lock().unwrap().set_md5_key(remote, key);
+    }
+
+    pub fn remove_md5_key(&mut self, remote: tcp::Endpoint) {
+        self.shared.keys.lock().unwrap().remove_md5_key(remote);
+    }
+
+    /// Adds a TCP-AO master key (RFC 5925) for `remote`, port 0 matching every port
+    ///
+    /// Connections to `remote` that already use TCP-AO can roll over to the new key. New
+    /// connections use TCP-AO instead of MD5 when both are configured.
+    pub fn add_ao_key(&mut self, remote: tcp::Endpoint, key: tcp_ao::MasterKey) {
+        self.shared.keys.lock().unwrap().add_ao_key(remote, key.clone());
+        self.update_ao(remote, |ao| ao.add_key(key.clone()));
+    }
+
+    /// Removes a TCP-AO master key, connections keep the last key they have
+    pub fn remove_ao_key(&mut self, remote: tcp::Endpoint, send_id: u8, recv_id: u8) {
+        self.shared.keys
+            .lock()
+            .unwrap()
+            .remove_ao_key(remote, send_id, recv_id);
+        self.update_ao(remote, |ao| ao.remove_key(send_id, recv_id));
+    }
+
+    /// Asks the peers of connections to `remote` to sign with the key of `recv_id` from
+    /// now on, rolling over once they do
+    pub fn set_ao_rnext_key(&mut self, remote: tcp::Endpoint, recv_id: u8) {
+        self.update_ao(remote, |ao| ao.set_rnext(recv_id));
+    }
+
+    fn update_ao<F>(&self, remote: tcp::Endpoint, mut f: F)
+        where F: FnMut(&mut super::auth::Ao)
+    {
+        let mut sockets = self.shared.sockets.lock().unwrap();
+        for (&endpoint, connection) in sockets.iter_mut() {
+            if let Some(Auth::Ao(ref mut ao)) = connection.auth {
+                if KeyChain::matches(remote, endpoint) {
+                    f(ao);
+                }
+            }
         }
-        rx.recv_timeout(Duration::from_secs(2))
-            .map_err(|_| SocketError::Timeout)
     }
 
+    /// Closes the connection to `remote`, sending a FIN after any data still queued
+    ///
+    /// Once both FINs are through the connection lingers in TIME-WAIT for 2MSL, so that
+    /// late segments of it aren't taken for a new connection to the same peer. Connections
+    /// still in their handshake are dropped at once.
     pub fn close(&mut self, remote: tcp::Endpoint) {
-        let mut sockets = self.sockets.lock().unwrap();
-        sockets.remove(&remote);
+        {
+            let mut sockets = self.shared.sockets.lock().unwrap();
+            let handshake = match sockets.get(&remote) {
+                Some(connection) => {
+                    matches!(connection.state, SocketState::SynSent | SocketState::SynReceived)
+                }
+                None => return,
+            };
+            if handshake {
+                sockets.remove(&remote);
+                return;
+            }
+        }
+        Self::send(&self.shared, self.endpoint, remote, &PacketBuffer::fin(&[]));
     }
 
-    pub fn listen(&mut self, tx: mpsc::Sender<Socket>) {
+    /// Accepts connections, handing their sockets to `tx` once their handshake completes
+    ///
+    /// The bound of the channel is the accept backlog: handshakes completing while it is
+    /// full, and SYNs arriving while the SYN queue is, are handled as set by
+    /// `set_syn_overflow`.
+    pub fn listen(&mut self, tx: mpsc::SyncSender<Socket>) {
         self.start(tx);
     }
 
-    pub fn start(&mut self, tx: mpsc::Sender<Socket>) {
+    /// Limits the connections in their handshake at a time
+    pub fn set_syn_queue(&mut self, len: usize) {
+        self.shared.config.lock().unwrap().syn_queue = len;
+    }
+
+    /// Selects what happens to connections that find the SYN queue or the accept backlog full
+    pub fn set_syn_overflow(&mut self, overflow: Overflow) {
+        self.shared.config.lock().unwrap().overflow = overflow;
+    }
+
+    /// SYNs turned away so far because the SYN queue or the accept backlog was full, or the
+    /// memory budget exhausted
+    pub fn overflows(&self) -> usize {
+        self.shared.overflows.load(Ordering::Relaxed)
+    }
+
+    /// Budgets the memory held for data buffered on all connections, unread or
+    /// unacknowledged, or held out of order by MPTCP sessions
+    ///
+    /// Under pressure, past three quarters of the budget, buffers stop growing and MPTCP
+    /// sessions drop their out-of-order data. Once it's exhausted, windows close and new
+    /// connections are refused until the memory is freed again.
+    pub fn set_memory_limit(&mut self, limit: usize) {
+        self.shared.memory.set_limit(limit);
+    }
+
+    /// Memory held for buffered data at the moment
+    pub fn memory_used(&self) -> usize {
+        self.shared.memory.used()
+    }
+
+    pub fn start(&mut self, tx: mpsc::SyncSender<Socket>) {
         self.running.store(true, Ordering::Relaxed);
         let (tx_send, tx_recv) = mpsc::channel::<(tcp::Endpoint, PacketBuffer)>();
 
         self.send_thread = Some({
                                     let running = self.running.clone();
                                     let local = self.endpoint;
-                                    let raw = self.raw.clone();
-                                    let mut sockets = self.sockets.clone();
-                                    thread::spawn(move || while running.load(Ordering::Relaxed) {
-                                                      let buf = tx_recv.recv().unwrap();
-                                                      Self::send(&raw,
-                                                                 &mut sockets,
-                                                                 local,
-                                                                 buf.0,
-                                                                 &*buf.1.payload);
+                                    let shared = self.shared.clone();
+                                    thread::spawn(move || {
+                                                      Self::run_sender(shared,
+                                                                       running,
+                                                                       local,
+                                                                       tx_recv);
                                                   })
                                 });
 
-        self.recv_thread =
-            Some({
-                     let running = self.running.clone();
-                     let endpoint = self.endpoint;
-                     let raw = self.raw.clone();
-                     let sockets = self.sockets.clone();
-                     thread::spawn(move || {
-                                       Self::recv(raw, running, endpoint, sockets, tx, tx_send);
-                                   })
-                 });
+        self.recv_thread = Some({
+                                    let running = self.running.clone();
+                                    let endpoint = self.endpoint;
+                                    let ctx = RecvContext {
+                                        shared: self.shared.clone(),
+                                        socket_send: tx,
+                                        tx_send,
+                                    };
+                                    thread::spawn(move || Self::recv(ctx, running, endpoint))
+                                });
     }
 
+    /// Stops the interface, closing every connection as the linger policy of its socket says
+    ///
+    /// Graceful closes are given `STOP_TIMEOUT` to complete, those lingering with a
+    /// timeout until it expires if their data isn't acknowledged by then.
     pub fn stop(&mut self) {
+        self.close_all();
         self.running.store(false, Ordering::Relaxed);
 
         if let Some(handle) = mem::replace(&mut self.send_thread, None) {
@@ -106,271 +631,1553 @@ impl Interface {
         }
     }
 
-    fn send_syn(raw: &Arc<RawSocket>, local: tcp::Endpoint, remote: tcp::Endpoint) {
-        let mut buf = [0; 40];
-        let len = {
-            let iprepr = ipv4::Repr {
-                src_addr: local.addr,
-                dst_addr: remote.addr,
-                payload_len: 20,
+    /// Closes the connections of all sockets, waiting for their FINs to be acknowledged
+    /// and the peers' FINs to arrive while the send thread keeps retransmitting
+    fn close_all(&self) {
+        let closing: HashMap<tcp::Endpoint, Linger> = self.shared.sockets
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|&(_, connection)| {
+                        matches!(connection.state,
+                                 SocketState::SynSent | SocketState::SynReceived |
+                                 SocketState::Established | SocketState::CloseWait)
+                    })
+            .map(|(&remote, connection)| match connection.mptcp {
+                     Some(ref subflow) => (subflow.session, connection.linger),
+                     None => (remote, connection.linger),
+                 })
+            .collect();
+        for (remote, linger) in closing {
+            Self::send(&self.shared, self.endpoint, remote, &PacketBuffer::close(linger));
+        }
+
+        let deadline = Instant::now() + STOP_TIMEOUT;
+        while self.running.load(Ordering::Relaxed) && self.lingering(deadline) {
+            thread::sleep(TIMER_GRANULARITY);
+        }
+    }
+
+    /// Whether closed connections are still in their closing handshake, until `deadline`
+    /// or, for data still unacknowledged, until their linger timeout
+    fn lingering(&self, deadline: Instant) -> bool {
+        let now = Instant::now();
+        self.shared.sockets
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|connection| {
+                        matches!(connection.state,
+                                 SocketState::FinWait1 | SocketState::FinWait2 |
+                                 SocketState::Closing | SocketState::LastAck)
+                    })
+            .any(|connection| {
+                     now < deadline ||
+                     connection.linger_deadline.is_some() && connection.outstanding()
+                 })
+    }
+
+    /// Resets the connection of the socket at `key` with all its subflows and forgets it
+    fn abort(raw: &Arc<RawSocket>,
+             local: tcp::Endpoint,
+             key: tcp::Endpoint,
+             sockets: &mut SocketMap,
+             sessions: &mut SessionMap) {
+        let endpoints = match sessions.remove(&key) {
+            Some(mut session) => mem::take(&mut session.subflows),
+            None => vec![key],
+        };
+        for remote in endpoints {
+            // Nothing is left to reset of a connection in TIME-WAIT
+            let open = match sockets.get(&remote) {
+                Some(connection) => connection.state != SocketState::TimeWait,
+                None => false,
             };
-            let mut ip = ipv4::Packet::new(&mut buf[..]).unwrap();
-            {
-                iprepr.send(&mut ip);
+            if !open {
+                continue;
             }
-            {
-                let mut tcp = tcp::Packet::new(&mut ip.payload_mut()[..iprepr.payload_len])
-                    .unwrap();
-
-                let tcprepr = tcp::Repr {
-                    src_port: local.port,
-                    dst_port: remote.port,
-                    seq: 123123,
-                    ack: None,
-                    control: tcp::Control::Syn,
-                    payload: &[],
-                };
+            let mut connection = sockets.remove(&remote).unwrap();
+            Self::send_rst(raw, local, remote, connection.seq, None);
+            connection.state = SocketState::Closed;
+            connection.publish_stats();
+        }
+    }
 
-                tcprepr.emit(&mut tcp, &local.addr, &remote.addr);
-            }
+    /// Tells the socket of the connection at `key` why it goes away
+    fn notify(sockets: &SocketMap, sessions: &SessionMap, key: tcp::Endpoint, error: SocketError) {
+        let rx = match sessions.get(&key) {
+            Some(session) => session.rx.as_ref(),
+            None => sockets.get(&key).and_then(|connection| connection.rx.as_ref()),
+        };
+        if let Some(rx) = rx {
+            let _ = rx.send(PacketBuffer::error(error));
+        }
+    }
 
-            let total_len = ip.total_len() as usize;
-            total_len
+    fn send_syn(raw: &Arc<RawSocket>,
+                local: tcp::Endpoint,
+                remote: tcp::Endpoint,
+                connection: &mut Connection,
+                options: Vec<tcp::TcpOption>) {
+        let signer = connection.signer(local, remote, true);
+        // An ECN-setup SYN (RFC 3168 section 6.1.1)
+        let mut flags = tcp::Flags::SYN;
+        if connection.ecn {
+            flags.insert(tcp::Flags::ECE | tcp::Flags::CWR);
+        }
+        let mut tcprepr = tcp::Repr {
+            src_port: local.port,
+            dst_port: remote.port,
+            seq: connection.iss,
+            ack_num: 0,
+            flags,
+            window: WINDOW_SIZE,
+            urgent: 0,
+            options,
+            payload: &[],
         };
+        Self::fit_timestamps(connection, &mut tcprepr, signer.as_ref());
+        Self::send_tcp(raw, local, remote, tcprepr, signer.as_ref(), ipv4::ECN_NOT_ECT);
+    }
 
-        raw.send(remote, &buf[..len]).unwrap();
+    fn send_syn_ack(raw: &Arc<RawSocket>,
+                    local: tcp::Endpoint,
+                    remote: tcp::Endpoint,
+                    connection: &mut Connection,
+                    options: Vec<tcp::TcpOption>) {
+        let signer = connection.signer(local, remote, false);
+        let mut flags = tcp::Flags::SYN | tcp::Flags::ACK;
+        if connection.ecn {
+            flags.insert(tcp::Flags::ECE);
+        }
+        let mut tcprepr = tcp::Repr {
+            src_port: local.port,
+            dst_port: remote.port,
+            seq: connection.iss,
+            ack_num: connection.ack,
+            flags,
+            window: WINDOW_SIZE,
+            urgent: 0,
+            options,
+            payload: &[],
+        };
+        Self::fit_timestamps(connection, &mut tcprepr, signer.as_ref());
+        Self::send_tcp(raw, local, remote, tcprepr, signer.as_ref(), ipv4::ECN_NOT_ECT);
+    }
+
+    /// Sends the SYN or SYN-ACK of a connection in its handshake again
+    fn resend_syn(raw: &Arc<RawSocket>,
+                  local: tcp::Endpoint,
+                  remote: tcp::Endpoint,
+                  connection: &mut Connection) {
+        let syn = connection.state == SocketState::SynSent;
+        let mut flags = if syn {
+            tcp::Flags::SYN
+        } else {
+            tcp::Flags::SYN | tcp::Flags::ACK
+        };
+        match (connection.ecn, syn) {
+            (true, true) => flags.insert(tcp::Flags::ECE | tcp::Flags::CWR),
+            (true, false) => flags.insert(tcp::Flags::ECE),
+            (false, _) => (),
+        }
+        let mut options = connection.syn_options.clone();
+        options.extend(connection.timestamp_option(Instant::now()));
+        let signer = connection.signer(local, remote, syn);
+        let mut tcprepr = tcp::Repr {
+            src_port: local.port,
+            dst_port: remote.port,
+            seq: connection.iss,
+            ack_num: if syn { 0 } else { connection.ack },
+            flags,
+            window: WINDOW_SIZE,
+            urgent: 0,
+            options,
+            payload: &[],
+        };
+        Self::fit_timestamps(connection, &mut tcprepr, signer.as_ref());
+        Self::send_tcp(raw, local, remote, tcprepr, signer.as_ref(), ipv4::ECN_NOT_ECT);
+        connection.stats.retransmits += 1;
+    }
+
+    /// Leaves timestamps out of a SYN or SYN-ACK they don't fit into next to the other
+    /// options and the signature, turning them off for the connection, as once negotiated
+    /// they can't be dropped from any segment
+    fn fit_timestamps(connection: &mut Connection,
+                      tcprepr: &mut tcp::Repr,
+                      signer: Option<&Signer>) {
+        let mut options = tcprepr.options.clone();
+        options.extend(signer.map(|signer| signer.signing_key(tcprepr.seq).option()));
+        let mut fitted = tcp::Repr { options, ..*tcprepr };
+        if connection.timestamps && !fitted.fit_options() {
+            connection.timestamps = false;
+            tcprepr.options
+                .retain(|option| !matches!(*option, tcp::TcpOption::Timestamps { .. }));
+        }
+    }
+
+    /// Acknowledges everything received so far on a connection
+    fn send_pure_ack(raw: &Arc<RawSocket>,
+                     local: tcp::Endpoint,
+                     remote: tcp::Endpoint,
+                     connection: &mut Connection) {
+        let options = connection
+            .mptcp
+            .as_ref()
+            .and_then(|subflow| subflow.data_level())
+            .map(|data_level| data_level.option(connection.seq, None, 0));
+        Self::send_control(raw, local, remote, connection, options.into_iter().collect());
+    }
+
+    /// Sends a segment without data carrying `options`
+    fn send_control(raw: &Arc<RawSocket>,
+                    local: tcp::Endpoint,
+                    remote: tcp::Endpoint,
+                    connection: &mut Connection,
+                    mut options: Vec<tcp::TcpOption>) {
+        options.extend(connection.timestamp_option(Instant::now()));
+        let signer = connection.signer(local, remote, false);
+        // Every segment received gets its own ACK, so echoing the CE mark of the latest
+        // one gives the exact feedback DCTCP needs (RFC 8257 section 3.2)
+        let mut flags = tcp::Flags::ACK;
+        if connection.ce {
+            flags.insert(tcp::Flags::ECE);
+        }
+        let tcprepr = tcp::Repr {
+            src_port: local.port,
+            dst_port: remote.port,
+            seq: connection.seq,
+            ack_num: connection.ack,
+            flags,
+            window: connection.advertise(),
+            urgent: 0,
+            options,
+            payload: &[],
+        };
+        Self::send_tcp(raw, local, remote, tcprepr, signer.as_ref(), ipv4::ECN_NOT_ECT);
+        connection.stats.segments_sent += 1;
+    }
+
+    /// Resets a connection, `ack` acknowledging a SYN that is refused
+    fn send_rst(raw: &Arc<RawSocket>,
+                local: tcp::Endpoint,
+                remote: tcp::Endpoint,
+                seq: u32,
+                ack: Option<u32>) {
+        let mut flags = tcp::Flags::RST;
+        if ack.is_some() {
+            flags.insert(tcp::Flags::ACK);
+        }
+        let tcprepr = tcp::Repr {
+            src_port: local.port,
+            dst_port: remote.port,
+            seq,
+            ack_num: ack.unwrap_or(0),
+            flags,
+            window: 0,
+            urgent: 0,
+            options: Vec::new(),
+            payload: &[],
+        };
+        Self::send_tcp(raw, local, remote, tcprepr, None, ipv4::ECN_NOT_ECT);
+    }
+
+    fn transmit(raw: &Arc<RawSocket>,
+                local: tcp::Endpoint,
+                remote: tcp::Endpoint,
+                batch: &Batch,
+                segment: &Segment,
+                ecn: u8) {
+        let options = batch
+            .data_level
+            .map(|data_level| {
+                     data_level.option(segment.seq, segment.data_seq, segment.payload.len())
+                 })
+            .into_iter()
+            .chain(batch.timestamp);
+        let mut flags = segment.flags();
+        if batch.ece {
+            flags.insert(tcp::Flags::ECE);
+        }
+        let tcprepr = tcp::Repr {
+            src_port: local.port,
+            dst_port: remote.port,
+            seq: segment.seq,
+            ack_num: batch.ack,
+            flags,
+            window: batch.window,
+            urgent: 0,
+            options: options.collect(),
+            payload: &segment.payload,
+        };
+        Self::send_tcp(raw, local, remote, tcprepr, batch.signer.as_ref(), ecn);
     }
 
-    fn send_ack(raw: &Arc<RawSocket>,
-                recv: &tcp::Packet<&[u8]>,
+    /// Signs a segment, wraps it in an IPv4 header and sends it
+    fn send_tcp(raw: &Arc<RawSocket>,
                 local: tcp::Endpoint,
-                remote: tcp::Endpoint) {
-        let mut buf = [0; 40];
+                remote: tcp::Endpoint,
+                mut tcprepr: tcp::Repr,
+                signer: Option<&Signer>,
+                ecn: u8) {
+        let key = signer.map(|signer| signer.signing_key(tcprepr.seq));
+        if let Some(ref key) = key {
+            tcprepr.options.push(key.option());
+        }
+        tcprepr.fit_options();
+        let iprepr = ipv4::Repr {
+            src_addr: local.addr,
+            dst_addr: remote.addr,
+            protocol: ipv4::Protocol::Tcp,
+            payload_len: tcprepr.header_len() + tcprepr.payload.len(),
+            ecn,
+        };
+
+        let mut buf = vec![0; 20 + iprepr.payload_len];
         let len = {
-            let iprepr = ipv4::Repr {
-                src_addr: local.addr,
-                dst_addr: remote.addr,
-                payload_len: 20,
-            };
             let mut ip = ipv4::Packet::new(&mut buf[..]).unwrap();
             {
                 iprepr.send(&mut ip);
             }
             {
-                let mut tcp = tcp::Packet::new(&mut ip.payload_mut()[..iprepr.payload_len])
-                    .unwrap();
-
-                let tcprepr = tcp::Repr {
-                    src_port: local.port,
-                    dst_port: remote.port,
-                    seq: recv.ack_num(),
-                    ack: Some(recv.seq_num() + 1),
-                    control: tcp::Control::None,
-                    payload: &[],
-                };
-
-                tcprepr.emit(&mut tcp, &local.addr, &remote.addr);
+                let mut tcp =
+                    tcp::Packet::new_unchecked(&mut ip.payload_mut()[..iprepr.payload_len]);
+                if tcprepr.emit(&mut tcp, &local.addr, &remote.addr, key).is_err() {
+                    return;
+                }
             }
 
-            let total_len = ip.total_len() as usize;
-            total_len
+            ip.total_len() as usize
         };
 
         raw.send(remote, &buf[..len]).unwrap();
     }
 
-    fn send_syn_ack(raw: &Arc<RawSocket>,
-                    recv: &tcp::Packet<&[u8]>,
-                    local: tcp::Endpoint,
-                    remote: tcp::Endpoint) {
-        let mut buf = [0; 40];
-        let len = {
-            let iprepr = ipv4::Repr {
-                src_addr: local.addr,
-                dst_addr: remote.addr,
-                payload_len: 20,
-            };
-            let mut ip = ipv4::Packet::new(&mut buf[..]).unwrap();
-            {
-                iprepr.send(&mut ip);
+    fn send(shared: &Shared, local: tcp::Endpoint, remote: tcp::Endpoint, buffer: &PacketBuffer) {
+        let raw = &shared.raw;
+        let mtu = shared.path_mtu.lock().unwrap().get(remote.addr);
+
+        let mut sockets = shared.sockets.lock().unwrap();
+        let mut sessions = shared.sessions.lock().unwrap();
+        // Settings of the socket apply to all subflows of an MPTCP connection
+        let endpoints = match sessions.get(&remote) {
+            Some(session) => session.subflows.clone(),
+            None => vec![remote],
+        };
+        if let Some(timeout) = buffer.user_timeout {
+            for endpoint in &endpoints {
+                if let Some(connection) = sockets.get_mut(endpoint) {
+                    connection.user_timeout.set(timeout);
+                    if connection.can_send() {
+                        let option = connection.user_timeout.option();
+                        Self::send_control(raw,
+                                           local,
+                                           *endpoint,
+                                           connection,
+                                           option.into_iter().collect());
+                    }
+                }
             }
-            {
-                let mut tcp = tcp::Packet::new(&mut ip.payload_mut()[..iprepr.payload_len])
-                    .unwrap();
-
-                let tcprepr = tcp::Repr {
-                    src_port: local.port,
-                    dst_port: remote.port,
-                    seq: 123123,
-                    ack: Some(recv.seq_num() + 1),
-                    control: tcp::Control::Syn,
-                    payload: &[],
-                };
+        }
+        if let Some(congestion_control) = buffer.congestion_control {
+            for endpoint in &endpoints {
+                if let Some(connection) = sockets.get_mut(endpoint) {
+                    connection.set_congestion_control(congestion_control);
+                }
+            }
+        }
+        if let Some(linger) = buffer.linger {
+            let now = Instant::now();
+            for endpoint in endpoints {
+                if let Some(connection) = sockets.get_mut(&endpoint) {
+                    connection.linger = linger;
+                    if let (true, Linger::Timeout(timeout)) = (buffer.fin, linger) {
+                        connection.linger_deadline = Some(now + timeout);
+                    }
+                }
+            }
+            if buffer.fin && linger == Linger::Abort {
+                Self::abort(raw, local, remote, &mut sockets, &mut sessions);
+                return;
+            }
+        }
 
-                tcprepr.emit(&mut tcp, &local.addr, &remote.addr);
+        if let Some(session) = sessions.get_mut(&remote) {
+            session.send(&mut sockets, &buffer.payload, buffer.fin);
+            for subflow in &session.subflows {
+                if let Some(connection) = sockets.get_mut(subflow) {
+                    Self::output(raw, local, *subflow, connection);
+                }
             }
+            return;
+        }
+        let connection = match sockets.get_mut(&remote) {
+            Some(connection) => connection,
+            None => return,
+        };
+        match connection.state {
+            SocketState::SynSent |
+            SocketState::SynReceived |
+            SocketState::Established |
+            SocketState::CloseWait => (),
+            _ => return,
+        }
 
-            let total_len = ip.total_len() as usize;
-            total_len
+        // Learned path MTUs expire, so the path may have grown again
+        connection.path_mtu = mtu;
+        connection.queue(&buffer.payload, buffer.fin);
+        Self::output(raw, local, remote, connection);
+    }
+
+    /// Sends whatever the connection's windows allow
+    fn output(raw: &Arc<RawSocket>,
+              local: tcp::Endpoint,
+              remote: tcp::Endpoint,
+              connection: &mut Connection) {
+        let now = Instant::now();
+        let batch = Batch {
+            ack: connection.ack,
+            window: connection.advertise(),
+            signer: connection.signer(local, remote, false),
+            data_level: connection
+                .mptcp
+                .as_ref()
+                .and_then(|subflow| subflow.data_level()),
+            timestamp: connection.timestamp_option(now),
+            ece: connection.ce,
         };
+        let ecn = connection.ecn;
+        while let Some(segment) = connection.next_segment(now) {
+            let (len, retransmitted) = (segment.payload.len(), segment.retransmitted);
+            // Retransmissions are not ECN-capable (RFC 3168 section 6.1.5)
+            let ecn = if ecn && !retransmitted {
+                ipv4::ECN_ECT0
+            } else {
+                ipv4::ECN_NOT_ECT
+            };
+            Self::transmit(raw, local, remote, &batch, segment, ecn);
+            connection.stats.segments_sent += 1;
+            connection.stats.bytes_sent += len as u64;
+            connection.stats.retransmits += retransmitted as u64;
+        }
+        connection.publish_stats();
+    }
 
-        raw.send(remote, &buf[..len]).unwrap();
+    fn run_sender(shared: Shared,
+                  running: Arc<AtomicBool>,
+                  local: tcp::Endpoint,
+                  tx_recv: mpsc::Receiver<(tcp::Endpoint, PacketBuffer)>) {
+        let mut timeout = TIMER_GRANULARITY;
+        while running.load(Ordering::Relaxed) {
+            // Wake up regularly even without writes so that timers fire, and in time for
+            // the next paced segment
+            match tx_recv.recv_timeout(timeout) {
+                Ok(buf) => Self::send(&shared, local, buf.0, &buf.1),
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            let next_send = Self::poll(&shared, local);
+            timeout = next_send.map_or(TIMER_GRANULARITY, |next| {
+                cmp::min(next.saturating_duration_since(Instant::now()), TIMER_GRANULARITY)
+            });
+        }
     }
 
-    fn send(raw: &Arc<RawSocket>,
-            sockets: &Arc<Mutex<SocketMap>>,
-            local: tcp::Endpoint,
-            remote: tcp::Endpoint,
-            payload: &[u8]) {
-        let mut buf = vec![0; 40 + payload.len()];
-        let len = {
-            let iprepr = ipv4::Repr {
-                src_addr: local.addr,
-                dst_addr: remote.addr,
-                payload_len: 20 + payload.len(),
+    /// Runs the timers of all connections, returning when the next paced segment is due
+    fn poll(shared: &Shared, local: tcp::Endpoint) -> Option<Instant> {
+        let raw = &shared.raw;
+        let now = Instant::now();
+        let mut sockets = shared.sockets.lock().unwrap();
+        let mut next_send: Option<Instant> = None;
+        let mut abandoned = Vec::new();
+        for (remote, connection) in sockets.iter_mut() {
+            if connection.half_open_expired(now) {
+                abandoned.push(*remote);
+                continue;
+            }
+            if connection.syn_timer_expired(now) {
+                if connection.syn_given_up() {
+                    abandoned.push(*remote);
+                    continue;
+                }
+                Self::resend_syn(raw, local, *remote, connection);
+            }
+            connection.on_timer(now);
+            connection.tune_buffers(now);
+            Self::output(raw, local, *remote, connection);
+            // The application read enough to open the window noticeably (RFC 1122
+            // section 4.2.3.3)
+            if connection.window_update_due() {
+                Self::send_pure_ack(raw, local, *remote, connection);
+            }
+            if let Some(next) = connection.next_send.filter(|&next| next > now) {
+                next_send = Some(next_send.map_or(next, |earliest| cmp::min(earliest, next)));
+            }
+        }
+        for remote in abandoned {
+            sockets.remove(&remote);
+        }
+
+        let mut sessions = shared.sessions.lock().unwrap();
+        let expired: Vec<tcp::Endpoint> = sockets
+            .iter()
+            .filter(|&(_, connection)| connection.time_wait_expired(now))
+            .map(|(&remote, _)| remote)
+            .collect();
+        for remote in expired {
+            let mut connection = sockets.remove(&remote).unwrap();
+            connection.state = SocketState::Closed;
+            connection.publish_stats();
+            Self::close_subflow(raw, local, remote, connection, &mut sockets, &mut sessions);
+        }
+        let lingering: Vec<tcp::Endpoint> = sockets
+            .iter()
+            .filter(|&(_, connection)| {
+                        connection.linger_expired(now) || connection.user_timeout_expired(now)
+                    })
+            .map(|(&remote, connection)| match connection.mptcp {
+                     Some(ref subflow) => subflow.session,
+                     None => remote,
+                 })
+            .collect();
+        for key in lingering {
+            Self::notify(&sockets, &sessions, key, SocketError::Timeout);
+            Self::abort(raw, local, key, &mut sockets, &mut sessions);
+        }
+        let idle: Vec<tcp::Endpoint> = sockets
+            .iter()
+            .filter(|&(_, connection)| connection.idle_expired(now))
+            .map(|(&remote, connection)| match connection.mptcp {
+                     Some(ref subflow) => subflow.session,
+                     None => remote,
+                 })
+            .collect();
+        for key in idle {
+            // An MPTCP connection is idle only once all its subflows are
+            let endpoints = match sessions.get(&key) {
+                Some(session) => session.subflows.clone(),
+                None => vec![key],
             };
-            let mut ip = ipv4::Packet::new(&mut buf[..]).unwrap();
-            {
-                iprepr.send(&mut ip);
+            let idle = endpoints.iter().all(|remote| {
+                sockets
+                    .get(remote)
+                    .is_none_or(|connection| connection.idle_expired(now))
+            });
+            // Aborted already for another of its subflows
+            let gone = endpoints.iter().all(|remote| !sockets.contains_key(remote));
+            if !idle || gone {
+                continue;
             }
-            {
-                let mut tcp = tcp::Packet::new(&mut ip.payload_mut()[..iprepr.payload_len])
-                    .unwrap();
-
-                let (seq, ack) = {
-                    let mut sockets = sockets.lock().unwrap();
-                    if let SocketState::Established {
-                               seq: ref mut seq,
-                               ack: ref mut ack,
-                           } = sockets.get_mut(&remote).unwrap().0 {
-                        let seqack = (*seq, *ack);
-                        println!("SEQ: {}, ACK: {:?}", seq, ack);
-                        *seq += payload.len() as u32;
-                        seqack
-                    } else {
-                        return;
-                    }
-                };
+            Self::notify(&sockets, &sessions, key, SocketError::Timeout);
+            Self::abort(raw, local, key, &mut sockets, &mut sessions);
+        }
+        Self::failover(raw, local, &mut sockets, &mut sessions);
+        for session in sessions.values_mut() {
+            session.moderate();
+            session.sync(&mut sockets);
+        }
+        next_send
+    }
 
-                let tcprepr = tcp::Repr {
-                    src_port: local.port,
-                    dst_port: remote.port,
-                    seq: seq,
-                    ack: Some(ack),
-                    control: tcp::Control::None,
-                    payload: payload,
-                };
+    /// Gives up MPTCP subflows that keep timing out while others are left, moving their
+    /// data to the remaining subflows
+    fn failover(raw: &Arc<RawSocket>,
+                local: tcp::Endpoint,
+                sockets: &mut SocketMap,
+                sessions: &mut SessionMap) {
+        let failed: Vec<(tcp::Endpoint, tcp::Endpoint)> = sockets
+            .iter()
+            .filter(|&(_, connection)| connection.timeouts >= FAILOVER_TIMEOUTS)
+            .filter_map(|(&remote, connection)| match connection.mptcp {
+                            Some(ref subflow) if subflow.joined() => {
+                                Some((remote, subflow.session))
+                            }
+                            _ => None,
+                        })
+            .collect();
 
-                tcprepr.emit(&mut tcp, &local.addr, &remote.addr);
+        for (remote, key) in failed {
+            let session = match sessions.get_mut(&key) {
+                Some(session) if session.subflows.len() > 1 => session,
+                _ => continue,
+            };
+            if let Some(connection) = sockets.remove(&remote) {
+                Self::send_rst(raw, local, remote, connection.seq, None);
+            }
+            session.detach(remote, sockets);
+            for subflow in &session.subflows {
+                if let Some(connection) = sockets.get_mut(subflow) {
+                    Self::output(raw, local, *subflow, connection);
+                }
             }
+        }
+    }
+
+    /// Starts another subflow of the MPTCP connection at `key` towards `remote`
+    fn join(shared: &Shared,
+            local: tcp::Endpoint,
+            remote: tcp::Endpoint,
+            key: tcp::Endpoint,
+            session: &Session,
+            sockets: &mut SocketMap,
+            backup: bool) {
+        if sockets.contains_key(&remote) || shared.memory.exhausted() {
+            return;
+        }
+        let config = shared.config();
+        let mtu = shared.path_mtu.lock().unwrap().get(remote.addr);
+        let nonce = mptcp::new_nonce();
+        let iss = shared.isn.generate(local, remote);
+        let mut connection =
+            config.connection(SocketState::SynSent, iss, None, mtu, &shared.memory);
+        connection.mptcp = Some(Subflow::join(key, nonce, None, backup, iss));
+
+        // Our subflows all leave from the address of the initial one
+        let mut options = config.syn_options(config.sack());
+        options.extend(connection.window_scale_option());
+        options.push(tcp::TcpOption::Mptcp(MptcpOption::JoinSyn {
+                                               backup,
+                                               address_id: 0,
+                                               token: session.remote_token,
+                                               nonce,
+                                           }));
+        connection.arm_syn_timer(options.clone(), Instant::now());
+        Self::send_syn(&shared.raw, local, remote, &mut connection, options);
+        sockets.insert(remote, connection);
+    }
 
-            let total_len = ip.total_len() as usize;
-            total_len
+    fn process_icmp(shared: &Shared, local: tcp::Endpoint, icmprepr: icmp::Repr) {
+        let (next_hop_mtu, header, header_len, data) = match icmprepr {
+            icmp::Repr::DstUnreachable {
+                code: icmp::CODE_FRAG_NEEDED,
+                next_hop_mtu,
+                header,
+                header_len,
+                data,
+            } => (next_hop_mtu, header, header_len, data),
+            _ => return,
         };
+        if header.protocol != ipv4::Protocol::Tcp ||
+           NetworkEndian::read_u16(&data[0..2]) != local.port {
+            return;
+        }
+        let remote = tcp::Endpoint::new(header.dst_addr, NetworkEndian::read_u16(&data[2..4]));
+        let seq = NetworkEndian::read_u32(&data[4..8]);
 
-        raw.send(remote, &buf[..len]).unwrap();
+        let mut sockets = shared.sockets.lock().unwrap();
+        let connection = match sockets.get_mut(&remote) {
+            Some(connection) => connection,
+            None => return,
+        };
+
+        // Only trust messages quoting data that is actually in flight (RFC 5927 section 4.1)
+        if !connection.in_flight(seq) {
+            return;
+        }
+
+        let mtu = match shared
+                  .path_mtu
+                  .lock()
+                  .unwrap()
+                  .update(remote.addr,
+                          next_hop_mtu as usize,
+                          header_len + header.payload_len) {
+            Some(mtu) => mtu,
+            None => return,
+        };
+
+        // Everything larger than the new MSS was dropped, send it again in smaller pieces
+        connection.path_mtu = mtu;
+        let mss = connection.send_mss();
+        connection.resegment(mss);
+        Self::output(&shared.raw, local, remote, connection);
     }
 
-    fn process_tcp(raw: &Arc<RawSocket>,
+    fn process_tcp(ctx: &RecvContext,
                    local: tcp::Endpoint,
                    remote: tcp::Endpoint,
                    tcp: tcp::Packet<&[u8]>,
-                   tcprepr: tcp::Repr,
-                   sockets: &Arc<Mutex<SocketMap>>,
-                   socket_send: &mpsc::Sender<Socket>,
-                   tx_send: &mpsc::Sender<(tcp::Endpoint, PacketBuffer)>) {
-        let mut sockets = sockets.lock().unwrap();
+                   ecn: u8) {
+        let RecvContext { ref shared, ref socket_send, ref tx_send } = *ctx;
+        let Shared { ref raw, ref keys, ref path_mtu, ref overflows, ref memory, .. } = *shared;
+        // Settings changed from now on apply to the next segment
+        let config = shared.config();
+        let mut sockets = shared.sockets.lock().unwrap();
+        let mut sessions = shared.sessions.lock().unwrap();
+
+        // Segments of a connection are checked with its own keys, others with the
+        // configured ones
+        let verifier = match sockets.get(&remote) {
+            Some(connection) => {
+                connection
+                    .auth
+                    .as_ref()
+                    .map(|auth| auth.verifier(local, remote, &tcp))
+            }
+            // Only a SYN opens a connection, and its MAC doesn't cover an ISN of ours yet
+            None => {
+                keys.lock()
+                    .unwrap()
+                    .auth(remote, 0, Some(tcp.seq_num()))
+                    .map(|auth| auth.verifier(local, remote, &tcp))
+            }
+        };
+        let tcprepr = verifier
+            .transpose()
+            .and_then(|verifier| {
+                let key = verifier.as_ref().map(|v| v.signing_key(tcp.seq_num()));
+                tcp::Repr::parse(&tcp, &remote.addr, &local.addr, key)
+            });
+        let tcprepr = match tcprepr {
+            Ok(repr) => repr,
+            Err(error) => {
+                println!("WARN: TCP packet {:?}", error);
+                return;
+            }
+        };
+        if let Some(connection) = sockets.get_mut(&remote) {
+            if let Some(ref mut auth) = connection.auth {
+                auth.on_authenticated(&tcprepr);
+            }
+        }
+
+        let peer_mss = peer_mss(&tcprepr);
+        let sack = config.sack_permitted(&tcprepr);
+
+        // A new SYN only takes over the 4-tuple of a connection in TIME-WAIT when it can't be
+        // confused with the old one, otherwise it just gets the old one's ACK again
+        if tcprepr.control() == tcp::Control::Syn && tcprepr.ack().is_none() {
+            let reuse = match sockets.get(&remote) {
+                Some(connection) if connection.state == SocketState::TimeWait => {
+                    Some(connection.accepts_syn(&tcprepr))
+                }
+                _ => None,
+            };
+            match reuse {
+                Some(true) => {
+                    let connection = sockets.remove(&remote).unwrap();
+                    Self::close_subflow(raw,
+                                        local,
+                                        remote,
+                                        connection,
+                                        &mut sockets,
+                                        &mut sessions);
+                }
+                Some(false) => {
+                    Self::send_pure_ack(raw, local, remote, sockets.get_mut(&remote).unwrap());
+                    return;
+                }
+                None => (),
+            }
+        }
+        if let Some(connection) = sockets.get_mut(&remote) {
+            if let Some((tsval, _)) = tcprepr.timestamps() {
+                connection.on_timestamp(tcprepr.seq, tsval);
+            }
+            if let Some((minutes, timeout)) = tcprepr.user_timeout() {
+                connection.user_timeout.on_option(minutes, timeout);
+            }
+            connection.ce = connection.ecn && ecn == ipv4::ECN_CE;
+            connection.last_received = Instant::now();
+            connection.stats.segments_received += 1;
+            connection.stats.bytes_received += tcprepr.payload.len() as u64;
+        }
+
+        let mptcp_options = super::mptcp::options(&tcprepr);
+        if Self::process_mptcp(shared,
+                               local,
+                               remote,
+                               &tcprepr,
+                               &mptcp_options,
+                               &mut sockets,
+                               &mut sessions) {
+            return;
+        }
+
+        let mut closed = None;
         {
             if let Entry::Occupied(mut socket_entry) = sockets.entry(remote) {
-                match tcprepr.control {
+                // Only an ACK of our SYN completes the handshake, be it the final ACK or the
+                // SYN-ACK of a simultaneous open (RFC 793 page 69)
+                let connection = socket_entry.get();
+                let iss = connection.iss;
+                if connection.state == SocketState::SynReceived && !tcprepr.flags.rst() &&
+                   tcprepr.ack().is_some_and(|ack| ack != iss.wrapping_add(1)) {
+                    Self::send_rst(raw, local, remote, tcprepr.ack_num, None);
+                    return;
+                }
+
+                if tcprepr.flags.ack() {
+                    let connection = socket_entry.get_mut();
+                    connection.on_ack(&tcprepr, Instant::now());
+                    Self::output(raw, local, remote, connection);
+                }
+
+                match tcprepr.control() {
                     tcp::Control::Rst => {
-                        socket_entry.remove_entry();
+                        closed = Some(socket_entry.remove_entry().1);
                     }
                     tcp::Control::Syn => {
                         let mut socket = socket_entry.get_mut();
-                        match socket.0 {
+                        match socket.state {
                             // SYN-ACK of handshake
                             SocketState::SynSent => {
-                                if tcprepr.ack.is_some() {
-                                    Self::send_ack(&raw, &tcp, local, remote);
+                                // A SYN-ACK for some other SYN of ours (RFC 793 page 66)
+                                let iss = socket.iss;
+                                if tcprepr.ack().is_some_and(|ack| ack != iss.wrapping_add(1)) {
+                                    Self::send_rst(raw, local, remote, tcprepr.ack_num, None);
+                                    return;
+                                }
+                                if let Some(ack) = tcprepr.ack() {
                                     let (rx_tx, rx_rx) = mpsc::channel();
-
-                                    socket_send
-                                        .send(Socket::new(remote, rx_rx, tx_send.clone()))
-                                        .unwrap();
-                                    socket.0 = SocketState::Established {
-                                        seq: tcprepr.ack.unwrap(),
-                                        ack: tcprepr.seq + 1,
+                                    let connected = Socket::new(remote,
+                                                                rx_rx,
+                                                                tx_send.clone(),
+                                                                socket.published.clone(),
+                                                                socket.buffers.clone());
+                                    match Self::deliver(socket_send, connected, config, overflows) {
+                                        Ok(()) => (),
+                                        Err(Overflow::Drop) => return,
+                                        Err(Overflow::Reset) => {
+                                            Self::send_rst(raw, local, remote, ack, None);
+                                            socket_entry.remove();
+                                            return;
+                                        }
+                                    }
+                                    socket.timestamps &= tcprepr.timestamps().is_some();
+                                    socket.ecn &= tcprepr.flags.ece() && !tcprepr.flags.cwr();
+                                    socket.on_window_scale(tcprepr.window_scale());
+                                    let keys = Self::capable_keys(socket, &mptcp_options);
+                                    let options = match keys {
+                                        Some((local_key, remote_key)) => {
+                                            let option = MptcpOption::Capable {
+                                                version: mptcp::VERSION,
+                                                flags: mptcp::FLAG_HMAC_SHA256,
+                                                sender_key: Some(local_key),
+                                                receiver_key: Some(remote_key),
+                                            };
+                                            vec![tcp::TcpOption::Mptcp(option)]
+                                        }
+                                        None => Vec::new(),
+                                    };
+                                    // Data on the SYN-ACK is taken in, but without a mapping
+                                    // it is no MPTCP data and left for a retransmission
+                                    let data = match keys {
+                                        Some(_) => &[][..],
+                                        None => tcp.payload(),
                                     };
-                                    println!("SEQ: {}, ACK: {:?}",
-                                             tcprepr.ack.unwrap(),
-                                             tcprepr.seq);
-                                    socket.1 = Some(rx_tx);
+                                    let rcv_nxt = tcprepr.seq.wrapping_add(1 + data.len() as u32);
+                                    socket.mss = peer_mss;
+                                    socket.sack = sack;
+                                    socket.establish(ack, rcv_nxt, tcprepr.window as usize);
+                                    Self::send_control(raw, local, remote, socket, options);
+                                    match keys {
+                                        Some((local_key, remote_key)) => {
+                                            socket.mptcp.as_mut().unwrap().irs = tcprepr.seq;
+                                            let mut session =
+                                                Session::new(local_key,
+                                                             remote_key,
+                                                             Some(rx_tx),
+                                                             socket.buffers.clone());
+                                            session.attach(remote, socket);
+                                            sessions.insert(remote, session);
+                                        }
+                                        None => {
+                                            if !data.is_empty() {
+                                                socket.buffers.on_delivered(data.len());
+                                                let _ = rx_tx.send(PacketBuffer::new(data));
+                                            }
+                                            socket.rx = Some(rx_tx);
+                                        }
+                                    }
+                                    Self::output(raw, local, remote, socket);
+                                } else {
+                                    // Simultaneous open: the peer's SYN crossed ours, so
+                                    // answer with a SYN-ACK and move to SYN-RECEIVED
+                                    socket.mptcp = None;
+                                    socket.timestamps &= tcprepr.timestamps().is_some();
+                                    socket.ecn &= tcprepr.flags.ece() && tcprepr.flags.cwr();
+                                    socket.on_window_scale(tcprepr.window_scale());
+                                    socket.ack = tcprepr.seq.wrapping_add(1);
+                                    let mut options = config.syn_options(sack);
+                                    options.extend(socket.window_scale_option());
+                                    let now = Instant::now();
+                                    socket.arm_syn_timer(options.clone(), now);
+                                    options.extend(socket.timestamp_option(now));
+                                    Self::send_syn_ack(raw, local, remote, socket, options);
+                                    socket.state = SocketState::SynReceived;
+                                    socket.mss = peer_mss;
+                                    socket.sack = sack;
+                                    return;
+                                }
+                            }
+                            // SYN-ACK of a simultaneous open
+                            SocketState::SynReceived => {
+                                if let Some(ack) = tcprepr.ack() {
+                                    if socket.rx.is_none() {
+                                        let (rx_tx, rx_rx) = mpsc::channel();
+                                        let connected = Socket::new(remote,
+                                                                    rx_rx,
+                                                                    tx_send.clone(),
+                                                                    socket.published.clone(),
+                                                                    socket.buffers.clone());
+                                        match Self::deliver(socket_send,
+                                                            connected,
+                                                            config,
+                                                            overflows) {
+                                            Ok(()) => socket.rx = Some(rx_tx),
+                                            Err(Overflow::Drop) => return,
+                                            Err(Overflow::Reset) => {
+                                                Self::send_rst(raw, local, remote, ack, None);
+                                                socket_entry.remove();
+                                                return;
+                                            }
+                                        }
+                                    }
+                                    socket.establish(ack,
+                                                     tcprepr.seq.wrapping_add(1),
+                                                     tcprepr.window as usize);
+                                    Self::send_pure_ack(raw, local, remote, socket);
+                                    Self::output(raw, local, remote, socket);
+                                } else if tcprepr
+                                              .seq
+                                              .wrapping_add(1 + socket.syn_data.len() as u32) ==
+                                          socket.ack {
+                                    // The peer retransmitted its SYN, our SYN-ACK got lost
+                                    Self::resend_syn(raw, local, remote, socket);
+                                    return;
+                                } else {
+                                    // The peer started over with another ISN, the
+                                    // half-open connection is replaced below
+                                    socket_entry.remove();
                                 }
                             }
-                            _ => (),
+                            // A retransmitted SYN-ACK whose ACK got lost, or a SYN for a
+                            // synchronized connection, which gets a challenge ACK
+                            // (RFC 5961 section 4)
+                            _ => {
+                                Self::send_pure_ack(raw, local, remote, socket);
+                                return;
+                            }
                         }
                     }
                     tcp::Control::None => {
                         let mut socket = socket_entry.get_mut();
-                        match socket.0 {
+                        match socket.state {
                             SocketState::SynSent => (),
                             // ACK in response to SYN-ACK
                             SocketState::SynReceived => {
-                                if tcprepr.ack.is_some() {
-                                    socket.0 = SocketState::Established {
-                                        seq: tcprepr.ack.unwrap(),
-                                        ack: tcprepr.seq,
-                                    };
-                                    println!("SEQ: {}, ACK: {:?}",
-                                             tcprepr.ack.unwrap(),
-                                             tcprepr.seq);
+                                if let Some(ack) = tcprepr.ack() {
+                                    // Only now is the socket handed over, a full backlog
+                                    // leaves the connection half-open
+                                    let (rx_tx, rx_rx) = mpsc::channel();
+                                    let accepted = Socket::new(remote,
+                                                               rx_rx,
+                                                               tx_send.clone(),
+                                                               socket.published.clone(),
+                                                               socket.buffers.clone());
+                                    match Self::deliver(socket_send, accepted, config, overflows) {
+                                        Ok(()) => (),
+                                        Err(Overflow::Drop) => return,
+                                        Err(Overflow::Reset) => {
+                                            Self::send_rst(raw, local, remote, ack, None);
+                                            socket_entry.remove();
+                                            return;
+                                        }
+                                    }
+
+                                    let keys = Self::capable_keys(socket, &mptcp_options);
+                                    let window = (tcprepr.window as usize) << socket.snd_shift;
+                                    socket.establish(ack, tcprepr.seq, window);
+                                    let syn_data = mem::take(&mut socket.syn_data);
+                                    if !syn_data.is_empty() {
+                                        socket.buffers.on_delivered(syn_data.len());
+                                        let _ = rx_tx.send(PacketBuffer::new(&syn_data));
+                                    }
+                                    match keys {
+                                        Some((local_key, remote_key)) => {
+                                            let mut session =
+                                                Session::new(local_key,
+                                                             remote_key,
+                                                             Some(rx_tx),
+                                                             socket.buffers.clone());
+                                            session.attach(remote, socket);
+                                            sessions.insert(remote, session);
+                                        }
+                                        None => socket.rx = Some(rx_tx),
+                                    }
+                                    Self::output(raw, local, remote, socket);
+                                }
+                            }
+                            SocketState::Established |
+                            SocketState::FinWait1 |
+                            SocketState::FinWait2 => {
+                                // ACK of our FIN
+                                if socket.state == SocketState::FinWait1 &&
+                                   tcprepr.ack() == Some(socket.seq) {
+                                    socket.state = SocketState::FinWait2;
                                 }
+
+                                if tcp.payload().is_empty() {
+                                    return;
+                                }
+
+                                // Without reassembly only in-order data is accepted, anything
+                                // else just gets our RCV.NXT acknowledged again
+                                if tcprepr.seq == socket.ack {
+                                    match socket.mptcp {
+                                        Some(ref mut subflow) if subflow.joined() => {
+                                            socket.ack = socket
+                                                .ack
+                                                .wrapping_add(tcp.payload().len() as u32);
+                                            Self::receive_mptcp(subflow,
+                                                                &tcprepr,
+                                                                &mptcp_options,
+                                                                &mut sessions);
+                                        }
+                                        _ => {
+                                            if let Some(ref rx) = socket.rx {
+                                                socket.ack = socket
+                                                    .ack
+                                                    .wrapping_add(tcp.payload().len() as u32);
+                                                socket.buffers.on_delivered(tcp.payload().len());
+                                                let _ = rx.send(PacketBuffer::new(tcp.payload()));
+                                            }
+                                        }
+                                    }
+                                } else {
+                                    socket.stats.out_of_order += 1;
+                                }
+                                Self::send_pure_ack(raw, local, remote, socket);
                             }
-                            SocketState::Established {
-                                ack: ref mut ack,
-                                seq: seq,
-                            } => {
-                                let socket = match socket.1 {
-                                    Some(ref socket) => socket,
-                                    None => return,
-                                };
-                                *ack += tcp.payload().len() as u32;
-                                socket.send(PacketBuffer::new(tcp.payload())).unwrap();
+                            SocketState::Closing => {
+                                if tcprepr.ack() == Some(socket.seq) {
+                                    socket.enter_time_wait(Instant::now());
+                                }
+                            }
+                            SocketState::LastAck => {
+                                if tcprepr.ack() == Some(socket.seq) {
+                                    closed = Some(socket_entry.remove_entry().1);
+                                }
                             }
+                            SocketState::CloseWait |
+                            SocketState::TimeWait |
                             SocketState::Closed => (),
                         };
                     }
-                    _ => {
-                        println!("WARNING: Control flag not implemented({:?})",
-                                 tcprepr.control)
+                    tcp::Control::Fin => {
+                        let socket = socket_entry.get_mut();
+                        let next = match socket.state {
+                            SocketState::Established => SocketState::CloseWait,
+                            SocketState::FinWait1 => {
+                                if tcprepr.ack() == Some(socket.seq) {
+                                    SocketState::TimeWait
+                                } else {
+                                    SocketState::Closing
+                                }
+                            }
+                            SocketState::FinWait2 => SocketState::TimeWait,
+                            // Our ACK of the FIN got lost (RFC 793 page 73)
+                            SocketState::TimeWait => {
+                                socket.enter_time_wait(Instant::now());
+                                Self::send_pure_ack(raw, local, remote, socket);
+                                return;
+                            }
+                            // The FIN was taken in already, our ACK of it got lost
+                            SocketState::CloseWait |
+                            SocketState::Closing |
+                            SocketState::LastAck => {
+                                Self::send_pure_ack(raw, local, remote, socket);
+                                return;
+                            }
+                            _ => return,
+                        };
+                        // Like data, the FIN is only taken in order, otherwise the peer
+                        // retransmits it along with whatever got lost before it
+                        if tcprepr.seq != socket.ack {
+                            socket.stats.out_of_order += 1;
+                            Self::send_pure_ack(raw, local, remote, socket);
+                            return;
+                        }
+                        socket.state = next;
+                        if socket.state == SocketState::TimeWait {
+                            socket.enter_time_wait(Instant::now());
+                        }
+
+                        if let Some(ref mut subflow) = socket.mptcp {
+                            if subflow.joined() {
+                                Self::receive_mptcp(subflow,
+                                                    &tcprepr,
+                                                    &mptcp_options,
+                                                    &mut sessions);
+                            }
+                        }
+
+                        // FIN occupies one sequence number after any data it carries
+                        socket.ack = socket
+                            .ack
+                            .wrapping_add(tcp.payload().len() as u32 + 1);
+                        Self::send_pure_ack(raw, local, remote, socket);
+
+                        // Signal EOF to the socket, nothing more will be received
+                        if let Some(rx) = socket.rx.take() {
+                            socket.buffers.on_delivered(tcp.payload().len());
+                            let _ = rx.send(PacketBuffer::fin(tcp.payload()));
+                        }
                     }
                 }
             }
         }
+        if let Some(mut connection) = closed {
+            connection.state = SocketState::Closed;
+            connection.publish_stats();
+            Self::close_subflow(raw, local, remote, connection, &mut sockets, &mut sessions);
+        }
 
         // Initial SYN in handshake
-        if tcprepr.control == tcp::Control::Syn {
-            if tcprepr.ack.is_none() {
-                Self::send_syn_ack(&raw, &tcp, local, remote);
+        if tcprepr.control() == tcp::Control::Syn {
+            if tcprepr.ack().is_none() && !sockets.contains_key(&remote) {
+                if memory.exhausted() {
+                    Self::overflow(raw, local, remote, &tcprepr, config, overflows);
+                    return;
+                }
+
+                let join = mptcp_options
+                    .iter()
+                    .filter_map(|option| match *option {
+                                    MptcpOption::JoinSyn { token, nonce, backup, .. } => {
+                                        Some((token, nonce, backup))
+                                    }
+                                    _ => None,
+                                })
+                    .next();
+                if let Some((token, nonce, backup)) = join {
+                    Self::accept_join(shared,
+                                      local,
+                                      remote,
+                                      &tcprepr,
+                                      (token, nonce, backup),
+                                      &mut sockets,
+                                      &sessions);
+                    return;
+                }
+
+                let half_open = sockets
+                    .values()
+                    .filter(|connection| connection.state == SocketState::SynReceived)
+                    .count();
+                if half_open >= config.syn_queue {
+                    Self::overflow(raw, local, remote, &tcprepr, config, overflows);
+                    return;
+                }
+
+                // The socket is handed over once the handshake completes
+                let mtu = path_mtu.lock().unwrap().get(remote.addr);
+                let iss = shared.isn.generate(local, remote);
+                let mut connection =
+                    config.connection(SocketState::SynReceived, iss, None, mtu, memory);
+                connection.ack = tcprepr.seq.wrapping_add(1);
+                connection.mss = peer_mss;
+                connection.sack = sack;
+                connection.auth = keys.lock().unwrap().auth(remote, iss, Some(tcprepr.seq));
+                connection.timestamps &= tcprepr.timestamps().is_some();
+                connection.ecn &= tcprepr.flags.ece() && tcprepr.flags.cwr();
+                connection.on_window_scale(tcprepr.window_scale());
+                connection.ts_recent = tcprepr.timestamps().map(|(tsval, _)| tsval);
+                if let Some((minutes, timeout)) = tcprepr.user_timeout() {
+                    connection.user_timeout.on_option(minutes, timeout);
+                }
+                let mut options = config.syn_options(sack);
+                options.extend(connection.user_timeout.option());
+                options.extend(connection.window_scale_option());
+                let capable = mptcp_options.iter().any(|option| match *option {
+                    MptcpOption::Capable { version, flags, .. } => {
+                        version == mptcp::VERSION && flags & mptcp::FLAG_CHECKSUM == 0
+                    }
+                    _ => false,
+                });
+                if capable {
+                    options.extend(config.mptcp_capable(&mut connection, remote, tcprepr.seq));
+                }
+                // Data on the SYN (RFC 793 section 3.4) is held until the handshake completes,
+                // except on MPTCP where it has no mapping
+                if connection.mptcp.is_none() {
+                    connection.syn_data = tcprepr.payload.to_vec();
+                    connection.ack = connection.ack.wrapping_add(tcprepr.payload.len() as u32);
+                }
+                let now = Instant::now();
+                connection.arm_syn_timer(options.clone(), now);
+                options.extend(connection.timestamp_option(now));
+                Self::send_syn_ack(raw, local, remote, &mut connection, options);
+                sockets.insert(remote, connection);
+            }
+        }
+    }
+
+    /// Hands over the socket of a connection whose handshake completed
+    ///
+    /// Fails with what to do with the connection when the accept backlog is full, as
+    /// `set_syn_overflow` says, or when nobody takes connections anymore.
+    fn deliver(socket_send: &mpsc::SyncSender<Socket>,
+               socket: Socket,
+               config: Config,
+               overflows: &AtomicUsize)
+               -> Result<(), Overflow> {
+        match socket_send.try_send(socket) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                overflows.fetch_add(1, Ordering::Relaxed);
+                Err(config.overflow)
+            }
+            Err(TrySendError::Disconnected(_)) => Err(Overflow::Reset),
+        }
+    }
+
+    /// Turns away a SYN for which there is no room
+    fn overflow(raw: &Arc<RawSocket>,
+                local: tcp::Endpoint,
+                remote: tcp::Endpoint,
+                tcprepr: &tcp::Repr,
+                config: Config,
+                overflows: &AtomicUsize) {
+        overflows.fetch_add(1, Ordering::Relaxed);
+        if config.overflow == Overflow::Reset {
+            Self::send_rst(raw, local, remote, 0, Some(tcprepr.seq.wrapping_add(1)));
+        }
+    }
+
+    /// Handles the MPTCP options of a segment for an existing subflow, returning whether
+    /// nothing is left to do with the segment
+    fn process_mptcp(shared: &Shared,
+                     local: tcp::Endpoint,
+                     remote: tcp::Endpoint,
+                     tcprepr: &tcp::Repr,
+                     options: &[MptcpOption],
+                     sockets: &mut SocketMap,
+                     sessions: &mut SessionMap)
+                     -> bool {
+        let raw = &shared.raw;
+        let (key, handshake) = match sockets.get(&remote).and_then(|c| c.mptcp.as_ref()) {
+            Some(subflow) => (subflow.session, subflow.handshake),
+            None => return false,
+        };
+        match handshake {
+            Handshake::Join { .. } if !tcprepr.flags.rst() => {
+                let connection = sockets.get_mut(&remote).unwrap();
+                connection.mss = peer_mss(tcprepr);
+                connection.sack = shared.config().sack_permitted(tcprepr);
+                if !Self::finish_join(raw, local, remote, connection, tcprepr, options, sessions) {
+                    Self::send_rst(raw, local, remote, tcprepr.ack_num, None);
+                    sockets.remove(&remote);
+                }
+                return true;
+            }
+            Handshake::Joined => (),
+            _ => return false,
+        }
+        let session = match sessions.get_mut(&key) {
+            Some(session) => session,
+            None => return false,
+        };
+
+        let data_ack = session.data_ack();
+        let mut announced = Vec::new();
+        for option in options {
+            match *option {
+                MptcpOption::Dss {
+                    data_ack,
+                    mapping,
+                    data_fin,
+                } => {
+                    if let Some(ack) = data_ack {
+                        session.on_data_ack(ack);
+                    }
+                    if let (true, Some(mapping)) = (data_fin, mapping) {
+                        let data_seq = mapping.data_seq.expand(session.data_ack());
+                        session.on_data_fin(data_seq + mapping.len as u64 - 1);
+                    }
+                }
+                MptcpOption::AddAddr {
+                    echo: false,
+                    address_id,
+                    addr,
+                    port,
+                    hmac: Some(hmac),
+                } => announced.push((address_id, addr, port, hmac)),
+                _ => (),
+            }
+        }
+        session.sync(sockets);
 
-                // Channel for sending packets
-                let (rx_tx, rx_rx) = mpsc::channel();
+        // A DATA_FIN without data still needs its DATA_ACK
+        if session.data_ack() != data_ack && tcprepr.payload.is_empty() {
+            Self::send_pure_ack(raw, local, remote, sockets.get_mut(&remote).unwrap());
+        }
 
-                socket_send
-                    .send(Socket::new(remote, rx_rx, tx_send.clone()))
-                    .unwrap();
-                sockets.insert(remote, (SocketState::SynReceived, Some(rx_tx)));
+        // Echo announced addresses (RFC 8684 section 3.4.1) and open subflows to them
+        for (address_id, addr, port, hmac) in announced {
+            let expected = mptcp::add_addr_hmac(session.remote_key,
+                                                session.local_key,
+                                                address_id,
+                                                addr,
+                                                port);
+            if hmac != expected {
+                continue;
             }
+            let echo = MptcpOption::AddAddr {
+                echo: true,
+                address_id,
+                addr,
+                port,
+                hmac: None,
+            };
+            Self::send_control(raw,
+                               local,
+                               remote,
+                               sockets.get_mut(&remote).unwrap(),
+                               vec![tcp::TcpOption::Mptcp(echo)]);
+
+            let target = tcp::Endpoint::new(addr, port.unwrap_or(key.port));
+            Self::join(shared, local, target, key, session, sockets, false);
         }
+        false
     }
 
-    fn recv(raw: Arc<RawSocket>,
-            running: Arc<AtomicBool>,
-            local: tcp::Endpoint,
-            sockets: Arc<Mutex<SocketMap>>,
-            socket_send: mpsc::Sender<Socket>,
-            tx_send: mpsc::Sender<(tcp::Endpoint, PacketBuffer)>) {
+    /// Keys of an MP_CAPABLE handshake the peer took up, the connection falling back to
+    /// regular TCP otherwise
+    fn capable_keys(connection: &mut Connection, options: &[MptcpOption]) -> Option<(u64, u64)> {
+        let local_key = match connection.mptcp {
+            Some(ref subflow) => {
+                match subflow.handshake {
+                    Handshake::Capable { local_key } => local_key,
+                    _ => return None,
+                }
+            }
+            None => return None,
+        };
+        let remote_key = options
+            .iter()
+            .filter_map(|option| match *option {
+                            MptcpOption::Capable {
+                                version,
+                                flags,
+                                sender_key: Some(key),
+                                receiver_key,
+                            } if version == mptcp::VERSION &&
+                                 flags & mptcp::FLAG_CHECKSUM == 0 &&
+                                 receiver_key.unwrap_or(local_key) == local_key => Some(key),
+                            _ => None,
+                        })
+            .next();
+        if remote_key.is_none() {
+            connection.mptcp = None;
+        }
+        remote_key.map(|remote_key| (local_key, remote_key))
+    }
+
+    /// Answers an MP_JOIN SYN for one of our MPTCP connections
+    fn accept_join(shared: &Shared,
+                   local: tcp::Endpoint,
+                   remote: tcp::Endpoint,
+                   tcprepr: &tcp::Repr,
+                   (token, remote_nonce, backup): (u32, u32, bool),
+                   sockets: &mut SocketMap,
+                   sessions: &SessionMap) {
+        let raw = &shared.raw;
+        let key = match super::mptcp::find_session(sessions, token) {
+            Some(key) => key,
+            None => {
+                Self::send_rst(raw, local, remote, 0, Some(tcprepr.seq.wrapping_add(1)));
+                return;
+            }
+        };
+        let session = &sessions[&key];
+        let local_nonce = mptcp::new_nonce();
+        let hmac = mptcp::join_hmac(session.local_key,
+                                    session.remote_key,
+                                    local_nonce,
+                                    remote_nonce);
+
+        let config = shared.config();
+        let mtu = shared.path_mtu.lock().unwrap().get(remote.addr);
+        let iss = shared.isn.generate(local, remote);
+        let mut connection =
+            config.connection(SocketState::SynReceived, iss, None, mtu, &shared.memory);
+        connection.mss = peer_mss(tcprepr);
+        connection.sack = config.sack_permitted(tcprepr);
+        let mut subflow = Subflow::join(key, local_nonce, Some(remote_nonce), backup, iss);
+        subflow.irs = tcprepr.seq;
+        connection.mptcp = Some(subflow);
+        connection.ecn &= tcprepr.flags.ece() && tcprepr.flags.cwr();
+        connection.on_window_scale(tcprepr.window_scale());
+        connection.ack = tcprepr.seq.wrapping_add(1);
+        let mut options = config.syn_options(connection.sack);
+        options.extend(connection.window_scale_option());
+        options.push(tcp::TcpOption::Mptcp(MptcpOption::JoinSynAck {
+                                               backup,
+                                               address_id: 0,
+                                               hmac: NetworkEndian::read_u64(&hmac[..8]),
+                                               nonce: local_nonce,
+                                           }));
+        connection.arm_syn_timer(options.clone(), Instant::now());
+        Self::send_syn_ack(raw, local, remote, &mut connection, options);
+        sockets.insert(remote, connection);
+    }
+
+    /// Completes the MP_JOIN handshake of a subflow, returning false when the peer failed
+    /// to prove it knows the keys of the MPTCP connection (RFC 8684 section 3.2)
+    fn finish_join(raw: &Arc<RawSocket>,
+                   local: tcp::Endpoint,
+                   remote: tcp::Endpoint,
+                   connection: &mut Connection,
+                   tcprepr: &tcp::Repr,
+                   options: &[MptcpOption],
+                   sessions: &mut SessionMap)
+                   -> bool {
+        let (key, local_nonce, remote_nonce) = match connection.mptcp {
+            Some(Subflow {
+                     session,
+                     handshake: Handshake::Join {
+                         local_nonce,
+                         remote_nonce,
+                     },
+                     ..
+                 }) => (session, local_nonce, remote_nonce),
+            _ => return false,
+        };
+        let session = match sessions.get_mut(&key) {
+            Some(session) => session,
+            None => return false,
+        };
+        let ack = match tcprepr.ack() {
+            Some(ack) => ack,
+            None => return true,
+        };
+
+        match connection.state {
+            SocketState::SynSent if tcprepr.flags.syn() => {
+                let remote_nonce = options
+                    .iter()
+                    .filter_map(|option| match *option {
+                                    MptcpOption::JoinSynAck { hmac, nonce, .. } => {
+                                        Some((hmac, nonce))
+                                    }
+                                    _ => None,
+                                })
+                    .find(|&(hmac, nonce)| {
+                              let expected = mptcp::join_hmac(session.remote_key,
+                                                              session.local_key,
+                                                              nonce,
+                                                              local_nonce);
+                              hmac == NetworkEndian::read_u64(&expected[..8])
+                          })
+                    .map(|(_, nonce)| nonce);
+                let remote_nonce = match remote_nonce {
+                    Some(nonce) => nonce,
+                    None => return false,
+                };
+
+                let hmac = mptcp::join_hmac(session.local_key,
+                                            session.remote_key,
+                                            local_nonce,
+                                            remote_nonce);
+                let mut truncated = [0; mptcp::JOIN_ACK_HMAC_LEN];
+                truncated.copy_from_slice(&hmac[..mptcp::JOIN_ACK_HMAC_LEN]);
+
+                connection.establish(ack, tcprepr.seq.wrapping_add(1), tcprepr.window as usize);
+                connection.mptcp.as_mut().unwrap().irs = tcprepr.seq;
+                let option = MptcpOption::JoinAck { hmac: truncated };
+                Self::send_control(raw,
+                                   local,
+                                   remote,
+                                   connection,
+                                   vec![tcp::TcpOption::Mptcp(option)]);
+                session.attach(remote, connection);
+            }
+            SocketState::SynReceived => {
+                let remote_nonce = match remote_nonce {
+                    Some(nonce) => nonce,
+                    None => return false,
+                };
+                let expected = mptcp::join_hmac(session.remote_key,
+                                                session.local_key,
+                                                remote_nonce,
+                                                local_nonce);
+                let valid = options.iter().any(|option| match *option {
+                    MptcpOption::JoinAck { ref hmac } => {
+                        hmac[..] == expected[..mptcp::JOIN_ACK_HMAC_LEN]
+                    }
+                    _ => false,
+                });
+                if !valid {
+                    return false;
+                }
+
+                let window = (tcprepr.window as usize) << connection.snd_shift;
+                connection.establish(ack, tcprepr.seq, window);
+                session.attach(remote, connection);
+                // The peer may only use the subflow once its third ACK is acknowledged
+                Self::send_pure_ack(raw, local, remote, connection);
+            }
+            _ => (),
+        }
+        true
+    }
+
+    /// Hands in-order data received on a subflow to its MPTCP connection
+    fn receive_mptcp(subflow: &mut Subflow,
+                     tcprepr: &tcp::Repr,
+                     options: &[MptcpOption],
+                     sessions: &mut SessionMap) {
+        let session = match sessions.get_mut(&subflow.session) {
+            Some(session) => session,
+            None => return,
+        };
+        let mapping = options
+            .iter()
+            .filter_map(|option| match *option {
+                            MptcpOption::Dss { mapping, .. } => mapping,
+                            _ => None,
+                        })
+            .next();
+        // Data without a mapping is dropped
+        if let Some(data_seq) = subflow.data_seq(tcprepr.seq, mapping) {
+            session.receive(data_seq, tcprepr.payload);
+        }
+        subflow.data_ack = session.data_ack();
+    }
+
+    /// Detaches a subflow that went away from its MPTCP connection, which ends with its
+    /// last subflow
+    fn close_subflow(raw: &Arc<RawSocket>,
+                     local: tcp::Endpoint,
+                     remote: tcp::Endpoint,
+                     connection: Connection,
+                     sockets: &mut SocketMap,
+                     sessions: &mut SessionMap) {
+        let key = match connection.mptcp {
+            Some(ref subflow) if subflow.joined() => subflow.session,
+            _ => return,
+        };
+        let empty = match sessions.get_mut(&key) {
+            Some(session) => {
+                session.detach(remote, sockets);
+                for subflow in &session.subflows {
+                    if let Some(connection) = sockets.get_mut(subflow) {
+                        Self::output(raw, local, *subflow, connection);
+                    }
+                }
+                session.subflows.is_empty()
+            }
+            None => return,
+        };
+        if empty {
+            sessions.remove(&key);
+        }
+    }
+
+    fn recv(ctx: RecvContext, running: Arc<AtomicBool>, local: tcp::Endpoint) {
+        let raw = &ctx.shared.raw;
         let mut index = 0;
         while running.load(Ordering::Relaxed) {
             let buf = raw.recv(index).unwrap_or(Default::default());
@@ -394,32 +2201,137 @@ impl Interface {
                 }
             };
 
-            let tcp = tcp::Packet::new(&ip.payload()[..iprepr.payload_len]).unwrap();
-            let tcprepr = match tcp::Repr::parse(&tcp, &iprepr.src_addr, &iprepr.dst_addr) {
-                Ok(repr) => repr,
+            if iprepr.protocol == ipv4::Protocol::Icmp {
+                let icmp = match icmp::Packet::new(&ip.payload()[..iprepr.payload_len]) {
+                    Ok(packet) => packet,
+                    Err(_) => continue,
+                };
+                if let Ok(repr) = icmp::Repr::parse(&icmp) {
+                    Self::process_icmp(&ctx.shared, local, repr);
+                }
+                continue;
+            }
+
+            let tcp = match tcp::Packet::new(&ip.payload()[..iprepr.payload_len]) {
+                Ok(packet) => packet,
                 Err(error) => {
                     println!("WARN: TCP packet {:?}", error);
                     continue;
                 }
             };
+            if tcp.dst_port() != local.port {
+                continue;
+            }
 
-            if tcprepr.dst_port == local.port {
-                let local = tcp::Endpoint::new(iprepr.dst_addr, tcprepr.dst_port);
-                let remote = tcp::Endpoint::new(iprepr.src_addr, tcprepr.src_port);
+            let remote = tcp::Endpoint::new(iprepr.src_addr, tcp.src_port());
+            let local = tcp::Endpoint::new(iprepr.dst_addr, tcp.dst_port());
 
-                let start = Instant::now();
+            let start = Instant::now();
 
-                Self::process_tcp(&raw,
-                                  local,
-                                  remote,
-                                  tcp,
-                                  tcprepr,
-                                  &sockets,
-                                  &socket_send,
-                                  &tx_send);
+            Self::process_tcp(&ctx, local, remote, tcp, iprepr.ecn);
 
-                println!("{:?}", start.elapsed());
-            }
+            println!("{:?}", start.elapsed());
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(host: u8, port: u16) -> tcp::Endpoint {
+        tcp::Endpoint::new(ipv4::Address::from_bytes(&[127, 0, 0, host]), port)
+    }
+
+    /// Interface listening on one end of 127.0.0.1:4000 and 127.0.0.2:5000, with what its
+    /// receive thread works from, or none without the rights to open a raw socket
+    fn context() -> Option<(RecvContext, mpsc::Receiver<Socket>, tcp::Endpoint, tcp::Endpoint)> {
+        let raw = RawSocket::new().ok()?;
+        let (local, remote) = (endpoint(1, 4000), endpoint(2, 5000));
+        let interface = Interface::new(local, raw);
+        let (socket_send, socket_recv) = mpsc::sync_channel(1);
+        let (tx_send, _) = mpsc::channel();
+        let ctx = RecvContext {
+            shared: interface.shared.clone(),
+            socket_send,
+            tx_send,
+        };
+        Some((ctx, socket_recv, local, remote))
+    }
+
+    /// Processes a segment without options or data from `remote`
+    fn receive(ctx: &RecvContext,
+               local: tcp::Endpoint,
+               remote: tcp::Endpoint,
+               flags: tcp::Flags,
+               seq: u32,
+               ack: u32) {
+        let repr = tcp::Repr {
+            src_port: remote.port,
+            dst_port: local.port,
+            seq,
+            ack_num: ack,
+            flags,
+            window: 1024,
+            urgent: 0,
+            options: Vec::new(),
+            payload: &[],
+        };
+        let mut buffer = vec![0; repr.header_len()];
+        let mut packet = tcp::Packet::new_unchecked(&mut buffer[..]);
+        repr.emit(&mut packet, &remote.addr, &local.addr, None).unwrap();
+        let packet = tcp::Packet::new(&buffer[..]).unwrap();
+        Interface::process_tcp(ctx, local, remote, packet, ipv4::ECN_NOT_ECT);
+    }
+
+    fn state(ctx: &RecvContext, remote: tcp::Endpoint) -> Option<SocketState> {
+        ctx.shared.sockets.lock().unwrap().get(&remote).map(|connection| connection.state)
+    }
+
+    #[test]
+    fn bad_ack_leaves_handshake_open() {
+        let (ctx, socket_recv, local, remote) = match context() {
+            Some(context) => context,
+            None => return,
+        };
+        receive(&ctx, local, remote, tcp::Flags::SYN, 1000, 0);
+        let iss = ctx.shared.sockets.lock().unwrap()[&remote].iss;
+
+        for &ack in &[iss, iss.wrapping_add(2), iss.wrapping_add(0x8000_0000)] {
+            receive(&ctx, local, remote, tcp::Flags::ACK, 1001, ack);
+            assert!(socket_recv.try_recv().is_err());
+            assert_eq!(state(&ctx, remote), Some(SocketState::SynReceived));
+        }
+
+        receive(&ctx, local, remote, tcp::Flags::ACK, 1001, iss.wrapping_add(1));
+        assert!(socket_recv.try_recv().is_ok());
+        assert_eq!(state(&ctx, remote), Some(SocketState::Established));
+    }
+
+    #[test]
+    fn bad_syn_ack_leaves_simultaneous_open() {
+        let (ctx, socket_recv, local, remote) = match context() {
+            Some(context) => context,
+            None => return,
+        };
+        let iss = 7000;
+        let connection = ctx.shared.config().connection(SocketState::SynSent,
+                                                        iss,
+                                                        None,
+                                                        path_mtu::DEFAULT_MTU,
+                                                        &ctx.shared.memory);
+        ctx.shared.sockets.lock().unwrap().insert(remote, connection);
+
+        // The peer's SYN crosses ours
+        receive(&ctx, local, remote, tcp::Flags::SYN, 1000, 0);
+        assert_eq!(state(&ctx, remote), Some(SocketState::SynReceived));
+
+        receive(&ctx, local, remote, tcp::Flags::SYN | tcp::Flags::ACK, 1000, iss + 2);
+        assert!(socket_recv.try_recv().is_err());
+        assert_eq!(state(&ctx, remote), Some(SocketState::SynReceived));
+
+        receive(&ctx, local, remote, tcp::Flags::SYN | tcp::Flags::ACK, 1000, iss + 1);
+        assert!(socket_recv.try_recv().is_ok());
+        assert_eq!(state(&ctx, remote), Some(SocketState::Established));
+    }
+}
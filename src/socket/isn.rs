@@ -0,0 +1,52 @@
+use std::time::Instant;
+
+use byteorder::{ByteOrder, NetworkEndian};
+use getrandom;
+use sha2::{Digest, Sha256};
+
+use tcp;
+
+/// Generator of initial sequence numbers (RFC 6528)
+///
+/// ISN = M + F(localip, localport, remoteip, remoteport, secretkey), where M is a clock
+/// ticking every 4 microseconds and F a keyed SHA-256 of the 4-tuple. Successive
+/// connections between the same ends thus get increasing ISNs, while those of other
+/// connections can't be guessed from them.
+#[derive(Debug)]
+pub struct IsnGenerator {
+    secret: [u8; 16],
+    epoch: Instant,
+}
+
+impl IsnGenerator {
+    pub fn new() -> Self {
+        let mut secret = [0; 16];
+        getrandom::getrandom(&mut secret).expect("system random number generator");
+        IsnGenerator {
+            secret,
+            epoch: Instant::now(),
+        }
+    }
+
+    pub fn generate(&self, local: tcp::Endpoint, remote: tcp::Endpoint) -> u32 {
+        let mut ports = [0; 4];
+        NetworkEndian::write_u16(&mut ports[..2], local.port);
+        NetworkEndian::write_u16(&mut ports[2..], remote.port);
+
+        let mut hash = Sha256::new();
+        hash.update(local.addr.as_bytes());
+        hash.update(remote.addr.as_bytes());
+        hash.update(ports);
+        hash.update(self.secret);
+        let offset = NetworkEndian::read_u32(&hash.finalize()[..4]);
+
+        let ticks = (self.epoch.elapsed().as_micros() / 4) as u32;
+        ticks.wrapping_add(offset)
+    }
+}
+
+impl Default for IsnGenerator {
+    fn default() -> Self {
+        IsnGenerator::new()
+    }
+}
@@ -0,0 +1,174 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Queueing delay aimed for, the most RFC 6817 allows (TARGET)
+const TARGET: Duration = Duration::from_millis(100);
+
+/// Segments the window may exceed the flight size by (ALLOWED_INCREASE)
+const ALLOWED_INCREASE: usize = 1;
+
+/// Pacing rate relative to cwnd/SRTT in percent, without the headroom of other congestion
+/// controls: the window is kept to the flight size anyway, and bursts ahead of it would
+/// only build the queue whose delay is measured
+pub const PACING_RATIO: u64 = 100;
+
+/// Lower bound of the window in segments (MIN_CWND)
+const MIN_CWND: usize = 2;
+
+/// Latest delay samples filtered for the current delay (CURRENT_FILTER)
+const CURRENT_FILTER: usize = 4;
+
+/// Minutes of minimum delays kept for the base delay (BASE_HISTORY)
+const BASE_HISTORY: usize = 10;
+const BASE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Low Extra Delay Background Transport (RFC 6817) for one connection
+///
+/// Delays are one-way delays in milliseconds, the peer's timestamp value minus the echo of
+/// ours. The offset between the two clocks cancels out against the base delay.
+#[derive(Debug)]
+pub struct Ledbat {
+    /// Latest delay samples (current_delays)
+    current: VecDeque<i64>,
+    /// Minimum delay of each of the last minutes, the current one last (base_delays)
+    base: VecDeque<i64>,
+    /// Start of the current minute
+    base_started: Option<Instant>,
+}
+
+impl Ledbat {
+    pub fn new() -> Self {
+        Ledbat {
+            current: VecDeque::new(),
+            base: VecDeque::new(),
+            base_started: None,
+        }
+    }
+
+    pub fn on_delay(&mut self, delay: i64, now: Instant) {
+        if self.current.len() == CURRENT_FILTER {
+            self.current.pop_front();
+        }
+        self.current.push_back(delay);
+
+        match self.base_started {
+            Some(started) if now.duration_since(started) < BASE_INTERVAL => {
+                let minimum = self.base.back_mut().unwrap();
+                *minimum = cmp::min(*minimum, delay);
+            }
+            _ => {
+                if self.base.len() == BASE_HISTORY {
+                    self.base.pop_front();
+                }
+                self.base.push_back(delay);
+                self.base_started = Some(now);
+            }
+        }
+    }
+
+    /// Delay above the base delay, if measured yet
+    fn queuing_delay(&self) -> Option<i64> {
+        let current = self.current.iter().min()?;
+        let base = self.base.iter().min()?;
+        Some(current - base)
+    }
+
+    /// Window after `acked` bytes were acknowledged with `flight_size` bytes outstanding,
+    /// growing below the target delay and shrinking above it
+    pub fn on_ack(&self, cwnd: usize, acked: usize, flight_size: usize, mss: usize) -> usize {
+        let queuing_delay = match self.queuing_delay() {
+            Some(delay) => delay,
+            None => return cwnd,
+        };
+        let target = TARGET.as_millis() as i64;
+        let off_target = target - queuing_delay;
+        let delta = off_target * (acked * mss) as i64 / (target * cmp::max(cwnd, 1) as i64);
+        let cwnd = cmp::max(cwnd as i64 + delta, 0) as usize;
+        let max_allowed = flight_size + ALLOWED_INCREASE * mss;
+        cmp::max(cmp::min(cwnd, max_allowed), MIN_CWND * mss)
+    }
+
+    /// Window after a loss, at most halved
+    pub fn on_loss(cwnd: usize, mss: usize) -> usize {
+        cmp::min(cwnd, cmp::max(cwnd / 2, MIN_CWND * mss))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_delay_measured() {
+        let ledbat = Ledbat::new();
+        assert_eq!(ledbat.on_ack(10_000, 1000, 20_000, 1000), 10_000);
+    }
+
+    #[test]
+    fn below_target() {
+        let now = Instant::now();
+        let mut ledbat = Ledbat::new();
+        ledbat.on_delay(10, now);
+
+        // Without queuing delay, the window grows by up to one segment per window
+        assert_eq!(ledbat.on_ack(10_000, 1000, 20_000, 1000), 10_100);
+
+        // Half way to the target, by half as much
+        for _ in 0..CURRENT_FILTER {
+            ledbat.on_delay(60, now);
+        }
+        assert_eq!(ledbat.on_ack(10_000, 1000, 20_000, 1000), 10_050);
+
+        // But no further than a segment above the flight size
+        assert_eq!(ledbat.on_ack(10_000, 10_000, 9000, 1000), 10_000);
+    }
+
+    #[test]
+    fn above_target() {
+        let now = Instant::now();
+        let mut ledbat = Ledbat::new();
+        ledbat.on_delay(10, now);
+
+        // At the target, the window stays
+        for _ in 0..CURRENT_FILTER {
+            ledbat.on_delay(110, now);
+        }
+        assert_eq!(ledbat.on_ack(10_000, 1000, 20_000, 1000), 10_000);
+
+        // Beyond it, the window shrinks in proportion
+        for _ in 0..CURRENT_FILTER {
+            ledbat.on_delay(210, now);
+        }
+        assert_eq!(ledbat.on_ack(10_000, 1000, 20_000, 1000), 9900);
+
+        // But no lower than MIN_CWND segments
+        assert_eq!(ledbat.on_ack(2000, 2000, 20_000, 1000), 2000);
+    }
+
+    #[test]
+    fn base_delay_history() {
+        let now = Instant::now();
+        let mut ledbat = Ledbat::new();
+        ledbat.on_delay(10, now);
+
+        // The minimum of an earlier minute still counts
+        for _ in 0..CURRENT_FILTER {
+            ledbat.on_delay(110, now + BASE_INTERVAL);
+        }
+        assert_eq!(ledbat.queuing_delay(), Some(100));
+
+        // Until it falls out of the history
+        for minute in 2..BASE_HISTORY as u32 + 1 {
+            ledbat.on_delay(110, now + BASE_INTERVAL * minute);
+        }
+        assert_eq!(ledbat.queuing_delay(), Some(0));
+    }
+
+    #[test]
+    fn loss() {
+        assert_eq!(Ledbat::on_loss(10_000, 1000), 5000);
+        assert_eq!(Ledbat::on_loss(3000, 1000), 2000);
+        assert_eq!(Ledbat::on_loss(1000, 1000), 1000);
+    }
+}
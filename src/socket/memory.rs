@@ -0,0 +1,61 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Memory budget of an interface by default
+pub const DEFAULT_MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+
+/// Memory taken by the data buffered on all connections of an interface, against a budget
+///
+/// Past three quarters of the budget the interface is under pressure, much like between
+/// the second and third of Linux' `tcp_mem` thresholds: buffers stop growing, receive
+/// buffers shrink back and MPTCP sessions drop the data they hold out of order. Plain
+/// connections don't reassemble, so they never hold any. Once the budget is exhausted,
+/// windows close and new connections are refused.
+#[derive(Debug)]
+pub struct Memory {
+    limit: AtomicUsize,
+    used: AtomicUsize,
+}
+
+impl Memory {
+    pub fn new(limit: usize) -> Self {
+        Memory {
+            limit: AtomicUsize::new(limit),
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn set_limit(&self, limit: usize) {
+        self.limit.store(limit, Ordering::Relaxed);
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub fn charge(&self, len: usize) {
+        self.used.fetch_add(len, Ordering::Relaxed);
+    }
+
+    pub fn uncharge(&self, len: usize) {
+        self.used.fetch_sub(len, Ordering::Relaxed);
+    }
+
+    /// Memory left in the budget
+    pub fn room(&self) -> usize {
+        self.limit.load(Ordering::Relaxed).saturating_sub(self.used())
+    }
+
+    pub fn under_pressure(&self) -> bool {
+        self.used() > self.limit.load(Ordering::Relaxed) / 4 * 3
+    }
+
+    pub fn exhausted(&self) -> bool {
+        self.room() == 0
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Memory::new(DEFAULT_MEMORY_LIMIT)
+    }
+}
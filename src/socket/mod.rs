@@ -1,26 +1,115 @@
+mod auth;
+mod buffers;
+mod congestion;
+mod connection;
+mod dctcp;
 mod interface;
+mod isn;
+mod ledbat;
+mod memory;
+mod mptcp;
+mod path_mtu;
+mod plpmtud;
+mod rack;
 mod socket;
+mod stats;
+mod uto;
+
+use std::time::Duration;
 
 use ::error::SocketError;
 
-pub use self::interface::Interface as SocketInterface;
-pub use self::socket::Socket;
+pub use self::congestion::CongestionControl;
+pub use self::interface::{Interface as SocketInterface, Overflow};
+pub use self::rack::LossDetection;
+pub use self::socket::{Linger, Socket, SocketSender};
+pub use self::stats::Stats;
 
-#[derive(Debug)]
+/// Data of a connection along with the control messages between a socket and the
+/// interface, all fields but `payload` being unset for plain data
+#[derive(Debug, Default)]
 pub struct PacketBuffer {
     pub payload: Box<[u8]>,
+    /// Marks the end of the stream in this direction (FIN)
+    pub fin: bool,
+    /// Linger policy of the socket, which closes the connection by it along with `fin`
+    pub linger: Option<Linger>,
+    /// User timeout set for the socket
+    pub user_timeout: Option<Duration>,
+    /// Congestion control selected for the socket
+    pub congestion_control: Option<CongestionControl>,
+    /// Why the connection went away, for the socket
+    pub error: Option<SocketError>,
 }
 
 impl PacketBuffer {
     pub fn new(payload: &[u8]) -> Self {
-        PacketBuffer { payload: payload.to_vec().into_boxed_slice() }
+        PacketBuffer {
+            payload: payload.to_vec().into_boxed_slice(),
+            ..Default::default()
+        }
+    }
+
+    pub fn fin(payload: &[u8]) -> Self {
+        PacketBuffer {
+            fin: true,
+            ..PacketBuffer::new(payload)
+        }
+    }
+
+    /// Sets the linger policy of the connection
+    pub fn linger(linger: Linger) -> Self {
+        PacketBuffer {
+            linger: Some(linger),
+            ..Default::default()
+        }
+    }
+
+    /// Sets the user timeout of the connection
+    pub fn user_timeout(timeout: Duration) -> Self {
+        PacketBuffer {
+            user_timeout: Some(timeout),
+            ..Default::default()
+        }
+    }
+
+    /// Selects the congestion control of the connection
+    pub fn congestion_control(congestion_control: CongestionControl) -> Self {
+        PacketBuffer {
+            congestion_control: Some(congestion_control),
+            ..Default::default()
+        }
+    }
+
+    /// Tells the socket the connection is gone
+    pub fn error(error: SocketError) -> Self {
+        PacketBuffer {
+            error: Some(error),
+            ..Default::default()
+        }
+    }
+
+    /// Closes the connection of a dropped socket as `linger` says
+    pub fn close(linger: Linger) -> Self {
+        PacketBuffer {
+            fin: true,
+            linger: Some(linger),
+            ..Default::default()
+        }
     }
 }
 
-#[derive(Debug, PartialEq)]
-enum SocketState {
+/// State of a connection (RFC 793 section 3.2)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SocketState {
     SynSent,
     SynReceived,
-    Established { seq: u32, ack: u32 },
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
     Closed,
 }
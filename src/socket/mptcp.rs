@@ -0,0 +1,511 @@
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{mpsc, Arc};
+
+use super::PacketBuffer;
+use super::buffers::Buffers;
+use super::connection::Connection;
+use mptcp::{self, DataSeq, Mapping, MptcpOption};
+use tcp;
+
+/// Room taken by the DSS option of a data segment, padded to a multiple of 4
+pub const DSS_OPTION_LEN: usize = 28;
+
+/// Retransmission timeouts in a row after which a subflow is given up and its data moved
+/// to the other subflows
+pub const FAILOVER_TIMEOUTS: u32 = 3;
+
+/// MPTCP connections keyed by the endpoint of their `Socket`
+pub type SessionMap = HashMap<tcp::Endpoint, Session>;
+
+type ConnectionMap = HashMap<tcp::Endpoint, Connection>;
+
+/// MPTCP options carried by a segment
+pub fn options(repr: &tcp::Repr) -> Vec<MptcpOption> {
+    repr.options
+        .iter()
+        .filter_map(|option| match *option {
+                        tcp::TcpOption::Mptcp(option) => Some(option),
+                        _ => None,
+                    })
+        .collect()
+}
+
+/// Key of the session the peer names by `token` in MP_JOIN
+pub fn find_session(sessions: &SessionMap, token: u32) -> Option<tcp::Endpoint> {
+    sessions
+        .iter()
+        .find(|&(_, session)| session.local_token == token)
+        .map(|(&endpoint, _)| endpoint)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Handshake {
+    /// MP_CAPABLE offered with our key
+    Capable { local_key: u64 },
+    /// MP_JOIN with our nonce, and the peer's once known
+    Join {
+        local_nonce: u32,
+        remote_nonce: Option<u32>,
+    },
+    /// Carrying data of the MPTCP connection
+    Joined,
+}
+
+/// MPTCP state of a connection that is a subflow of an MPTCP connection
+#[derive(Debug)]
+pub struct Subflow {
+    /// Key of the MPTCP connection in the `SessionMap`
+    pub session: tcp::Endpoint,
+    pub handshake: Handshake,
+    /// Only used when no regular subflow is left
+    pub backup: bool,
+    /// Initial sequence numbers that mappings are relative to
+    pub iss: u32,
+    pub irs: u32,
+    /// Data sequence numbers of the unsent data, one entry per mapping
+    unsent: VecDeque<(u64, usize)>,
+    /// Latest mapping received as data sequence, relative subflow sequence and length
+    rcv_mapping: Option<(u64, u32, u32)>,
+    /// The session's DATA_ACK and outstanding DATA_FIN, sent on every segment
+    pub data_ack: u64,
+    pub data_fin: Option<u64>,
+}
+
+impl Subflow {
+    /// Initial subflow negotiating MP_CAPABLE, its own key in the session map
+    pub fn capable(remote: tcp::Endpoint, local_key: u64, iss: u32) -> Self {
+        Self::new(remote, Handshake::Capable { local_key }, false, iss)
+    }
+
+    /// Additional subflow of the session at `session`
+    pub fn join(session: tcp::Endpoint,
+                local_nonce: u32,
+                remote_nonce: Option<u32>,
+                backup: bool,
+                iss: u32)
+                -> Self {
+        let handshake = Handshake::Join {
+            local_nonce,
+            remote_nonce,
+        };
+        Self::new(session, handshake, backup, iss)
+    }
+
+    fn new(session: tcp::Endpoint, handshake: Handshake, backup: bool, iss: u32) -> Self {
+        Subflow {
+            session,
+            handshake,
+            backup,
+            iss,
+            irs: 0,
+            unsent: VecDeque::new(),
+            rcv_mapping: None,
+            data_ack: 0,
+            data_fin: None,
+        }
+    }
+
+    pub fn joined(&self) -> bool {
+        self.handshake == Handshake::Joined
+    }
+
+    /// Maps the next `len` bytes queued on the subflow to data sequence numbers
+    pub fn map(&mut self, data_seq: u64, len: usize) {
+        if len > 0 {
+            self.unsent.push_back((data_seq, len));
+        }
+    }
+
+    /// Unsent bytes up to the end of the first mapping, which a segment must not cross
+    pub fn mapped_len(&self) -> usize {
+        self.unsent.front().map_or(0, |&(_, len)| len)
+    }
+
+    /// Data sequence number of the next `len` unsent bytes, which are taken off the queue
+    pub fn take(&mut self, len: usize) -> Option<u64> {
+        let &mut (data_seq, ref mut left) = self.unsent.front_mut()?;
+        *left -= len;
+        if *left == 0 {
+            self.unsent.pop_front();
+        } else {
+            self.unsent[0].0 += len as u64;
+        }
+        Some(data_seq)
+    }
+
+    /// Data sequence number of in-order data at subflow sequence number `seq`, using the
+    /// mapping of its segment or an earlier one
+    pub fn data_seq(&mut self, seq: u32, mapping: Option<Mapping>) -> Option<u64> {
+        if let Some(mapping) = mapping {
+            // A relative subflow sequence number of 0 marks a DATA_FIN without data
+            if mapping.subflow_seq != 0 {
+                self.rcv_mapping = Some((mapping.data_seq.expand(self.data_ack),
+                                         mapping.subflow_seq,
+                                         mapping.len as u32));
+            }
+        }
+        let (data_seq, subflow_seq, len) = self.rcv_mapping?;
+        let offset = seq.wrapping_sub(self.irs).wrapping_sub(subflow_seq);
+        if offset < len {
+            Some(data_seq + offset as u64)
+        } else {
+            None
+        }
+    }
+
+    /// Data-level state to stamp onto outgoing segments once the subflow carries data
+    pub fn data_level(&self) -> Option<DataLevel> {
+        if !self.joined() {
+            return None;
+        }
+        Some(DataLevel {
+                 iss: self.iss,
+                 data_ack: self.data_ack,
+                 data_fin: self.data_fin,
+             })
+    }
+}
+
+/// Snapshot of a subflow's data-level state for building DSS options
+#[derive(Clone, Copy, Debug)]
+pub struct DataLevel {
+    iss: u32,
+    data_ack: u64,
+    data_fin: Option<u64>,
+}
+
+impl DataLevel {
+    /// DSS option of a segment at `seq` carrying `len` bytes from `data_seq`
+    pub fn option(&self, seq: u32, data_seq: Option<u64>, len: usize) -> tcp::TcpOption<'static> {
+        let (mapping, data_fin) = match (data_seq, self.data_fin) {
+            (Some(data_seq), data_fin) => {
+                // The DATA_FIN directly following the data is part of its mapping
+                let fin = data_fin == Some(data_seq + len as u64);
+                let mapping = Mapping {
+                    data_seq: DataSeq::Full(data_seq),
+                    subflow_seq: seq.wrapping_sub(self.iss),
+                    len: len as u16 + fin as u16,
+                };
+                (Some(mapping), fin)
+            }
+            (None, Some(fin)) => {
+                let mapping = Mapping {
+                    data_seq: DataSeq::Full(fin),
+                    subflow_seq: 0,
+                    len: 1,
+                };
+                (Some(mapping), true)
+            }
+            (None, None) => (None, false),
+        };
+        tcp::TcpOption::Mptcp(MptcpOption::Dss {
+                                  data_ack: Some(DataSeq::Full(self.data_ack)),
+                                  mapping,
+                                  data_fin,
+                              })
+    }
+}
+
+/// Data sent on a subflow but not yet acknowledged at the data level
+#[derive(Debug)]
+struct Chunk {
+    data_seq: u64,
+    payload: Vec<u8>,
+    subflow: tcp::Endpoint,
+}
+
+impl Chunk {
+    fn end(&self) -> u64 {
+        self.data_seq + self.payload.len() as u64
+    }
+}
+
+/// Data-level state of an MPTCP connection (RFC 8684)
+#[derive(Debug)]
+pub struct Session {
+    pub local_key: u64,
+    pub remote_key: u64,
+    /// Token the peer names the connection by in MP_JOIN
+    pub local_token: u32,
+    pub remote_token: u32,
+    pub rx: Option<mpsc::Sender<PacketBuffer>>,
+    /// Buffers of the socket, shared by all subflows
+    pub buffers: Arc<Buffers>,
+    /// Established subflows
+    pub subflows: Vec<tcp::Endpoint>,
+
+    /// Next data sequence number to send
+    data_seq: u64,
+    /// Oldest data sequence number not acknowledged by the peer
+    data_una: u64,
+    /// Next data sequence number expected from the peer (DATA_ACK)
+    data_ack: u64,
+    /// Our DATA_FIN once the socket closed its sending direction
+    snd_fin: Option<u64>,
+    /// DATA_FIN of the peer
+    rcv_fin: Option<u64>,
+    /// Data sent on the subflows, or left by subflows that went away until another one
+    /// takes it over
+    unacked: Vec<Chunk>,
+    /// Data written by the socket that no subflow could take yet
+    pending: Vec<u8>,
+    /// The socket closed its sending direction after `pending`
+    pending_fin: bool,
+    /// Data that arrived on other subflows ahead of DATA_ACK
+    out_of_order: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Session {
+    pub fn new(local_key: u64,
+               remote_key: u64,
+               rx: Option<mpsc::Sender<PacketBuffer>>,
+               buffers: Arc<Buffers>)
+               -> Self {
+        let data_seq = mptcp::idsn(local_key).wrapping_add(1);
+        Session {
+            local_key,
+            remote_key,
+            local_token: mptcp::token(local_key),
+            remote_token: mptcp::token(remote_key),
+            rx,
+            buffers,
+            subflows: Vec::new(),
+
+            data_seq,
+            data_una: data_seq,
+            data_ack: mptcp::idsn(remote_key).wrapping_add(1),
+            snd_fin: None,
+            rcv_fin: None,
+            unacked: Vec::new(),
+            pending: Vec::new(),
+            pending_fin: false,
+            out_of_order: BTreeMap::new(),
+        }
+    }
+
+    /// Adds an established subflow, data queued on it so far becomes data of the session
+    pub fn attach(&mut self, remote: tcp::Endpoint, connection: &mut Connection) {
+        let queued: Vec<u8> = connection.unsent.iter().cloned().collect();
+        if let Some(ref mut subflow) = connection.mptcp {
+            subflow.handshake = Handshake::Joined;
+            subflow.data_ack = self.data_ack;
+            subflow.data_fin = self.data_fin();
+            subflow.map(self.data_seq, queued.len());
+        }
+        connection.buffers = self.buffers.clone();
+        self.track(remote, queued);
+        self.subflows.push(remote);
+    }
+
+    /// Removes a subflow that closed or failed, moving the data it still carried to the
+    /// remaining subflows
+    pub fn detach(&mut self, remote: tcp::Endpoint, connections: &mut ConnectionMap) {
+        self.subflows.retain(|&subflow| subflow != remote);
+        self.push(connections);
+    }
+
+    /// Spreads data written by the socket over the subflows, `fin` sending a DATA_FIN
+    /// after it
+    ///
+    /// Data no subflow can take is kept until one can, see `sync`.
+    pub fn send(&mut self, connections: &mut ConnectionMap, payload: &[u8], fin: bool) {
+        self.pending.extend_from_slice(payload);
+        self.pending_fin |= fin;
+        self.push(connections);
+    }
+
+    /// Queues data left by subflows that went away, then the data the socket wrote since,
+    /// on the subflows that can take it
+    fn push(&mut self, connections: &mut ConnectionMap) {
+        let subflows = &self.subflows;
+        let (orphaned, kept): (Vec<Chunk>, Vec<Chunk>) = self.unacked
+            .drain(..)
+            .partition(|chunk| !subflows.contains(&chunk.subflow));
+        self.unacked = kept;
+        for mut chunk in orphaned {
+            if let Some(other) = self.schedule(connections) {
+                let connection = connections.get_mut(&other).unwrap();
+                connection.queue(&chunk.payload, false);
+                if let Some(ref mut subflow) = connection.mptcp {
+                    subflow.map(chunk.data_seq, chunk.payload.len());
+                }
+                chunk.subflow = other;
+            }
+            self.unacked.push(chunk);
+        }
+
+        while !self.pending.is_empty() {
+            let remote = match self.schedule(connections) {
+                Some(remote) => remote,
+                None => return,
+            };
+            let connection = connections.get_mut(&remote).unwrap();
+            let len = cmp::min(self.pending.len(), connection.send_mss());
+            let chunk: Vec<u8> = self.pending.drain(..len).collect();
+            connection.queue(&chunk, false);
+            if let Some(ref mut subflow) = connection.mptcp {
+                subflow.map(self.data_seq, len);
+            }
+            self.track(remote, chunk);
+        }
+
+        if self.pending_fin && self.snd_fin.is_none() {
+            self.snd_fin = Some(self.data_seq);
+            self.data_seq += 1;
+            self.copy_state(connections);
+            for remote in &self.subflows {
+                if let Some(connection) = connections.get_mut(remote) {
+                    connection.queue(&[], true);
+                }
+            }
+        }
+    }
+
+    fn track(&mut self, remote: tcp::Endpoint, payload: Vec<u8>) {
+        if payload.is_empty() {
+            return;
+        }
+        let len = payload.len() as u64;
+        self.unacked
+            .push(Chunk {
+                      data_seq: self.data_seq,
+                      payload,
+                      subflow: remote,
+                  });
+        self.data_seq += len;
+    }
+
+    /// Subflow with the least data outstanding for its congestion window, backup subflows
+    /// only when no regular one is left
+    fn schedule(&self, connections: &ConnectionMap) -> Option<tcp::Endpoint> {
+        let usable: Vec<(tcp::Endpoint, &Connection)> = self.subflows
+            .iter()
+            .filter_map(|remote| connections.get(remote).map(|c| (*remote, c)))
+            .filter(|&(_, connection)| connection.can_send())
+            .collect();
+        let regular = usable
+            .iter()
+            .any(|&(_, connection)| !connection.mptcp.as_ref().unwrap().backup);
+
+        usable
+            .into_iter()
+            .filter(|&(_, connection)| !regular || !connection.mptcp.as_ref().unwrap().backup)
+            .min_by_key(|&(_, connection)| {
+                            (connection.unsent.len() + connection.pipe()) * 1024 /
+                            cmp::max(connection.cwnd, 1)
+                        })
+            .map(|(remote, _)| remote)
+    }
+
+    /// Drops data the peer acknowledged at the data level
+    pub fn on_data_ack(&mut self, ack: DataSeq) {
+        let ack = ack.expand(self.data_una);
+        if ack <= self.data_una || ack > self.data_seq {
+            return;
+        }
+        self.data_una = ack;
+        self.unacked.retain(|chunk| chunk.end() > ack);
+    }
+
+    /// Takes in data at `data_seq`, delivering it to the socket once in order
+    pub fn receive(&mut self, data_seq: u64, payload: &[u8]) {
+        let end = data_seq + payload.len() as u64;
+        if end <= self.data_ack {
+            return;
+        }
+        if data_seq > self.data_ack {
+            // Left for the peer to retransmit while memory is tight
+            let memory = self.buffers.memory();
+            if memory.under_pressure() || self.out_of_order.contains_key(&data_seq) {
+                return;
+            }
+            memory.charge(payload.len());
+            self.out_of_order.insert(data_seq, payload.to_vec());
+            return;
+        }
+        self.deliver(&payload[(self.data_ack - data_seq) as usize..]);
+
+        while let Some((&data_seq, _)) = self.out_of_order.first_key_value() {
+            if data_seq > self.data_ack {
+                break;
+            }
+            let payload = self.out_of_order.remove(&data_seq).unwrap();
+            self.buffers.memory().uncharge(payload.len());
+            if data_seq + payload.len() as u64 > self.data_ack {
+                self.deliver(&payload[(self.data_ack - data_seq) as usize..]);
+            }
+        }
+        self.check_fin();
+    }
+
+    /// Drops the out-of-order data while the interface is under memory pressure
+    pub fn moderate(&mut self) {
+        if !self.buffers.memory().under_pressure() {
+            return;
+        }
+        self.buffers.memory().uncharge(self.out_of_order_len());
+        self.out_of_order.clear();
+    }
+
+    fn out_of_order_len(&self) -> usize {
+        self.out_of_order.values().map(|payload| payload.len()).sum()
+    }
+
+    fn deliver(&mut self, payload: &[u8]) {
+        self.data_ack += payload.len() as u64;
+        self.buffers.on_delivered(payload.len());
+        if let Some(ref rx) = self.rx {
+            let _ = rx.send(PacketBuffer::new(payload));
+        }
+    }
+
+    /// The peer closed its sending direction after the data before `data_seq`
+    pub fn on_data_fin(&mut self, data_seq: u64) {
+        self.rcv_fin = Some(data_seq);
+        self.check_fin();
+    }
+
+    fn check_fin(&mut self) {
+        if self.rcv_fin == Some(self.data_ack) {
+            self.data_ack += 1;
+            if let Some(rx) = self.rx.take() {
+                let _ = rx.send(PacketBuffer::fin(&[]));
+            }
+        }
+    }
+
+    pub fn data_ack(&self) -> u64 {
+        self.data_ack
+    }
+
+    /// Our DATA_FIN while the peer hasn't acknowledged it
+    fn data_fin(&self) -> Option<u64> {
+        self.snd_fin.filter(|&fin| self.data_una <= fin)
+    }
+
+    /// Queues data that was waiting for a subflow and copies the data-level
+    /// acknowledgment state onto the subflows
+    pub fn sync(&mut self, connections: &mut ConnectionMap) {
+        self.push(connections);
+        self.copy_state(connections);
+    }
+
+    fn copy_state(&self, connections: &mut ConnectionMap) {
+        for remote in &self.subflows {
+            let subflow = match connections.get_mut(remote).and_then(|c| c.mptcp.as_mut()) {
+                Some(subflow) => subflow,
+                None => continue,
+            };
+            subflow.data_ack = self.data_ack;
+            subflow.data_fin = self.data_fin();
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.buffers.memory().uncharge(self.out_of_order_len());
+        self.buffers.close();
+    }
+}
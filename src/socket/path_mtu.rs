@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use ipv4;
+
+/// MTU assumed for destinations without a cached path MTU
+pub const DEFAULT_MTU: usize = 1500;
+
+/// Lowest path MTU accepted from the network, as Linux' `min_pmtu`
+pub const MIN_MTU: usize = 552;
+
+/// Lifetime of a learned path MTU before probing the default again (RFC 1191 section 6.3)
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Plateau table of common MTUs from RFC 1191 section 7, used when a router doesn't report
+/// the next-hop MTU
+const PLATEAUS: [usize; 10] = [32000, 17914, 8166, 4352, 2002, 1492, 1006, 508, 296, 68];
+
+#[derive(Debug)]
+pub struct PathMtuCache {
+    default_mtu: usize,
+    timeout: Duration,
+    entries: HashMap<ipv4::Address, (usize, Instant)>,
+}
+
+impl PathMtuCache {
+    pub fn new(default_mtu: usize, timeout: Duration) -> Self {
+        PathMtuCache {
+            default_mtu,
+            timeout,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Path MTU towards `addr`, falling back to the default once an entry expires
+    pub fn get(&mut self, addr: ipv4::Address) -> usize {
+        let expired = match self.entries.get(&addr) {
+            Some(&(mtu, updated)) if updated.elapsed() < self.timeout => return mtu,
+            Some(_) => true,
+            None => false,
+        };
+        if expired {
+            self.entries.remove(&addr);
+        }
+        self.default_mtu
+    }
+
+    /// Lowers the path MTU towards `addr` after a Fragmentation Needed message
+    ///
+    /// `datagram_len` is the total length of the datagram that was too big, used to pick a
+    /// plateau when `next_hop_mtu` is zero. Returns the new MTU if it was lowered.
+    pub fn update(&mut self,
+                  addr: ipv4::Address,
+                  next_hop_mtu: usize,
+                  datagram_len: usize)
+                  -> Option<usize> {
+        self.expire();
+
+        let current = self.get(addr);
+        let mtu = if next_hop_mtu == 0 || next_hop_mtu >= datagram_len {
+            PLATEAUS
+                .iter()
+                .cloned()
+                .find(|&plateau| plateau < datagram_len)
+                .unwrap_or(MIN_MTU)
+        } else {
+            next_hop_mtu
+        };
+        let mtu = ::std::cmp::max(mtu, MIN_MTU);
+
+        if mtu >= current {
+            return None;
+        }
+        self.entries.insert(addr, (mtu, Instant::now()));
+        Some(mtu)
+    }
+
+    /// Drops all expired entries
+    fn expire(&mut self) {
+        let timeout = self.timeout;
+        self.entries.retain(|_, &mut (_, updated)| updated.elapsed() < timeout);
+    }
+}
+
+impl Default for PathMtuCache {
+    fn default() -> Self {
+        PathMtuCache::new(DEFAULT_MTU, DEFAULT_TIMEOUT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(last: u8) -> ipv4::Address {
+        ipv4::Address::from_bytes(&[192, 0, 2, last])
+    }
+
+    #[test]
+    fn next_hop_mtu() {
+        let mut cache = PathMtuCache::default();
+        assert_eq!(cache.get(addr(1)), DEFAULT_MTU);
+
+        assert_eq!(cache.update(addr(1), 1400, 1500), Some(1400));
+        assert_eq!(cache.get(addr(1)), 1400);
+        assert_eq!(cache.get(addr(2)), DEFAULT_MTU);
+
+        // Never raised, and never below MIN_MTU
+        assert_eq!(cache.update(addr(1), 1450, 1500), None);
+        assert_eq!(cache.update(addr(1), 100, 1400), Some(MIN_MTU));
+        assert_eq!(cache.get(addr(1)), MIN_MTU);
+    }
+
+    #[test]
+    fn plateaus() {
+        let mut cache = PathMtuCache::default();
+
+        // Without a next-hop MTU, or with a bogus one, the next lower plateau is taken
+        assert_eq!(cache.update(addr(1), 0, 1500), Some(1492));
+        assert_eq!(cache.update(addr(1), 1492, 1492), Some(1006));
+        assert_eq!(cache.update(addr(1), 0, 1006), Some(MIN_MTU));
+    }
+
+    #[test]
+    fn expiry() {
+        let mut cache = PathMtuCache::new(DEFAULT_MTU, Duration::from_secs(0));
+        assert_eq!(cache.update(addr(1), 1400, 1500), Some(1400));
+        assert_eq!(cache.get(addr(1)), DEFAULT_MTU);
+        assert!(cache.entries.is_empty());
+    }
+}
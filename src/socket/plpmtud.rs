@@ -0,0 +1,186 @@
+use std::cmp;
+use std::time::{Duration, Instant};
+
+/// MTU every path is assumed to support before probing (BASE_PLPMTU, RFC 8899 section 5.1.2)
+pub const BASE_MTU: usize = 1200;
+
+/// Probes of one size that may be lost before the size is considered too big (MAX_PROBES)
+const MAX_PROBES: u32 = 3;
+
+/// Searching stops once the unconfirmed range is narrower than this
+const SEARCH_GRANULARITY: usize = 32;
+
+/// Time before searching for a larger MTU again (PMTU_RAISE_TIMER, RFC 8899 section 5.1.1)
+const RAISE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Packetization Layer Path MTU Discovery (RFC 4821, RFC 8899) for one connection
+///
+/// The sender starts at `BASE_MTU` and probes with single full-sized data segments. Probes
+/// are told apart from regular segments so that losing one only narrows the search instead
+/// of being treated as congestion.
+#[derive(Debug)]
+pub struct Plpmtud {
+    /// Largest MTU confirmed by an acknowledged probe (PLPMTU)
+    mtu: usize,
+    /// Smallest MTU found not to get through, or one past the maximum
+    ceiling: usize,
+    /// MTU of the outstanding probe
+    probe: Option<usize>,
+    /// Lost probes of the current probe size
+    probe_count: u32,
+    /// When searching resumes after the previous search completed
+    raise_at: Option<Instant>,
+}
+
+impl Plpmtud {
+    pub fn new() -> Self {
+        Plpmtud {
+            mtu: BASE_MTU,
+            ceiling: usize::MAX,
+            probe: None,
+            probe_count: 0,
+            raise_at: None,
+        }
+    }
+
+    /// Largest MTU known to work, bounded by `max_mtu`
+    pub fn mtu(&self, max_mtu: usize) -> usize {
+        cmp::min(self.mtu, max_mtu)
+    }
+
+    /// MTU of the next probe if one is due, `max_mtu` being the largest size worth trying
+    pub fn probe_size(&mut self, max_mtu: usize, now: Instant) -> Option<usize> {
+        if self.probe.is_some() {
+            return None;
+        }
+        match self.raise_at {
+            Some(raise_at) if now < raise_at => return None,
+            Some(_) => {
+                // Periodically look for a path that got better
+                self.raise_at = None;
+                self.ceiling = usize::MAX;
+                self.probe_count = 0;
+            }
+            None => (),
+        }
+
+        let ceiling = cmp::min(self.ceiling, max_mtu + 1);
+        if ceiling <= self.mtu + SEARCH_GRANULARITY {
+            self.raise_at = Some(now + RAISE_INTERVAL);
+            return None;
+        }
+
+        // Try the largest candidate first, most paths support it
+        if ceiling == max_mtu + 1 {
+            Some(max_mtu)
+        } else {
+            Some((self.mtu + ceiling) / 2)
+        }
+    }
+
+    pub fn on_probe_sent(&mut self, mtu: usize) {
+        self.probe = Some(mtu);
+    }
+
+    /// The outstanding probe was acknowledged
+    pub fn on_probe_acked(&mut self) {
+        if let Some(mtu) = self.probe.take() {
+            self.mtu = cmp::max(self.mtu, mtu);
+            self.probe_count = 0;
+        }
+    }
+
+    /// The outstanding probe was lost, which says nothing about congestion
+    pub fn on_probe_lost(&mut self) {
+        if let Some(mtu) = self.probe.take() {
+            self.probe_count += 1;
+            if self.probe_count >= MAX_PROBES {
+                self.ceiling = mtu;
+                self.probe_count = 0;
+            }
+        }
+    }
+
+    /// Regular segments keep getting lost, the path MTU may have shrunk below the PLPMTU
+    pub fn on_black_hole(&mut self) {
+        if self.mtu > BASE_MTU {
+            self.ceiling = self.mtu;
+            self.mtu = BASE_MTU;
+            self.probe = None;
+            self.probe_count = 0;
+            self.raise_at = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lose(plpmtud: &mut Plpmtud, mtu: usize) {
+        for _ in 0..MAX_PROBES {
+            plpmtud.on_probe_sent(mtu);
+            plpmtud.on_probe_lost();
+        }
+    }
+
+    #[test]
+    fn probe_success() {
+        let now = Instant::now();
+        let mut plpmtud = Plpmtud::new();
+        assert_eq!(plpmtud.mtu(1500), BASE_MTU);
+
+        assert_eq!(plpmtud.probe_size(1500, now), Some(1500));
+        plpmtud.on_probe_sent(1500);
+        assert_eq!(plpmtud.probe_size(1500, now), None);
+        plpmtud.on_probe_acked();
+        assert_eq!(plpmtud.mtu(1500), 1500);
+        assert_eq!(plpmtud.mtu(1400), 1400);
+
+        // Done until the raise timer, which looks for a larger MTU again
+        assert_eq!(plpmtud.probe_size(1500, now), None);
+        assert_eq!(plpmtud.probe_size(9000, now), None);
+        assert_eq!(plpmtud.probe_size(9000, now + RAISE_INTERVAL), Some(9000));
+    }
+
+    #[test]
+    fn probe_failure() {
+        let now = Instant::now();
+        let mut plpmtud = Plpmtud::new();
+
+        // A size is given up after MAX_PROBES losses, then the range is halved
+        plpmtud.on_probe_sent(1500);
+        plpmtud.on_probe_lost();
+        assert_eq!(plpmtud.probe_size(1500, now), Some(1500));
+        lose(&mut plpmtud, 1500);
+        assert_eq!(plpmtud.mtu(1500), BASE_MTU);
+        assert_eq!(plpmtud.probe_size(1500, now), Some(1350));
+
+        plpmtud.on_probe_sent(1350);
+        plpmtud.on_probe_acked();
+        assert_eq!(plpmtud.probe_size(1500, now), Some(1425));
+        plpmtud.on_probe_sent(1425);
+        plpmtud.on_probe_acked();
+        assert_eq!(plpmtud.probe_size(1500, now), Some(1462));
+        lose(&mut plpmtud, 1462);
+        assert_eq!(plpmtud.probe_size(1500, now), Some(1443));
+        plpmtud.on_probe_sent(1443);
+        plpmtud.on_probe_acked();
+
+        // Down to the search granularity
+        assert_eq!(plpmtud.probe_size(1500, now), None);
+        assert_eq!(plpmtud.mtu(1500), 1443);
+    }
+
+    #[test]
+    fn black_hole() {
+        let now = Instant::now();
+        let mut plpmtud = Plpmtud::new();
+        plpmtud.on_probe_sent(1500);
+        plpmtud.on_probe_acked();
+
+        plpmtud.on_black_hole();
+        assert_eq!(plpmtud.mtu(1500), BASE_MTU);
+        assert_eq!(plpmtud.probe_size(1500, now), Some(1350));
+    }
+}
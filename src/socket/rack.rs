@@ -0,0 +1,261 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use super::connection::Segment;
+use tcp;
+
+/// Probe timeout before an RTT sample is available (RFC 8985 section 7.2)
+const INITIAL_PTO: Duration = Duration::from_secs(1);
+
+/// Allowance for a delayed ACK when a single segment is in flight (WCDelAckT)
+const MAX_ACK_DELAY: Duration = Duration::from_millis(200);
+
+/// How a sender decides that a segment was lost
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LossDetection {
+    /// Fast retransmit after three duplicate ACKs (RFC 5681)
+    #[default]
+    DupAck,
+    /// Time-based detection from transmit times with Tail Loss Probes (RACK-TLP, RFC 8985)
+    Rack,
+}
+
+/// RACK-TLP state of one connection
+#[derive(Debug)]
+pub struct Rack {
+    /// Transmit time of the most recently sent segment that was delivered (RACK.xmit_ts)
+    xmit_ts: Option<Instant>,
+    /// End of that segment (RACK.end_seq)
+    end_seq: u32,
+    /// RTT measured on that segment (RACK.rtt)
+    rtt: Duration,
+    min_rtt: Option<Duration>,
+    /// A segment was delivered after one sent later than it
+    reordering_seen: bool,
+
+    /// Expiry of the reordering timer
+    pub reorder_deadline: Option<Instant>,
+    /// Expiry of the probe timeout
+    pub pto_deadline: Option<Instant>,
+    /// The next new segment is a probe and may exceed the congestion window
+    pub probe_pending: bool,
+    /// End of the outstanding probe (TLP.end_seq)
+    pub tlp_end_seq: Option<u32>,
+    /// The outstanding probe is a retransmission (TLP.is_retrans)
+    pub tlp_is_retrans: bool,
+}
+
+impl Rack {
+    pub fn new() -> Self {
+        Rack {
+            xmit_ts: None,
+            end_seq: 0,
+            rtt: Duration::from_secs(0),
+            min_rtt: None,
+            reordering_seen: false,
+
+            reorder_deadline: None,
+            pto_deadline: None,
+            probe_pending: false,
+            tlp_end_seq: None,
+            tlp_is_retrans: false,
+        }
+    }
+
+    /// Records the delivery of a segment by a cumulative or selective acknowledgment
+    pub fn on_delivered(&mut self, segment: &Segment, now: Instant) {
+        let rtt = now.duration_since(segment.sent);
+        if segment.retransmitted {
+            // Faster than possible for the retransmission, the original got through
+            if let Some(min_rtt) = self.min_rtt {
+                if rtt < min_rtt {
+                    return;
+                }
+            }
+        } else {
+            self.min_rtt = Some(self.min_rtt.map_or(rtt, |min_rtt| cmp::min(min_rtt, rtt)));
+        }
+
+        if self.xmit_ts.is_some() && tcp::seq_lt(segment.end(), self.end_seq) &&
+           !segment.retransmitted {
+            self.reordering_seen = true;
+        }
+
+        if self.sent_after(segment.sent, segment.end()) {
+            self.xmit_ts = Some(segment.sent);
+            self.end_seq = segment.end();
+            self.rtt = rtt;
+        }
+    }
+
+    /// Whether a segment sent at `sent` ending at `end` is newer than the latest delivery
+    fn sent_after(&self, sent: Instant, end: u32) -> bool {
+        match self.xmit_ts {
+            None => true,
+            Some(xmit_ts) => sent > xmit_ts || (sent == xmit_ts && tcp::seq_lt(self.end_seq, end)),
+        }
+    }
+
+    /// Reordering window (RFC 8985 section 6.2 step 4)
+    fn reo_wnd(&self, in_recovery: bool, srtt: Option<Duration>) -> Duration {
+        if !self.reordering_seen && in_recovery {
+            return Duration::from_secs(0);
+        }
+        let reo_wnd = self.min_rtt.map_or(Duration::from_secs(0), |min_rtt| min_rtt / 4);
+        srtt.map_or(reo_wnd, |srtt| cmp::min(reo_wnd, srtt))
+    }
+
+    /// Marks segments sent long enough before the latest delivered one as lost and arms
+    /// the reordering timer for the rest. Returns whether any regular segment was marked,
+    /// lost PLPMTUD probes are left to the caller.
+    pub fn detect_loss(&mut self,
+                       segments: &mut VecDeque<Segment>,
+                       in_recovery: bool,
+                       srtt: Option<Duration>,
+                       now: Instant)
+                       -> bool {
+        self.reorder_deadline = None;
+        if self.xmit_ts.is_none() {
+            return false;
+        }
+        let reo_wnd = self.reo_wnd(in_recovery, srtt);
+
+        let mut marked = false;
+        for segment in segments.iter_mut() {
+            if segment.sacked || segment.lost || self.sent_after(segment.sent, segment.end()) {
+                continue;
+            }
+            let deadline = segment.sent + self.rtt + reo_wnd;
+            if deadline <= now {
+                segment.lost = true;
+                marked |= !segment.probe;
+            } else {
+                self.reorder_deadline = Some(self.reorder_deadline
+                                                 .map_or(deadline, |d| cmp::max(d, deadline)));
+            }
+        }
+        marked
+    }
+
+    /// Arms the probe timeout unless a probe is already outstanding, never past `rto_deadline`
+    pub fn arm_pto(&mut self,
+                   srtt: Option<Duration>,
+                   single_segment: bool,
+                   rto_deadline: Option<Instant>,
+                   now: Instant) {
+        if self.tlp_end_seq.is_some() {
+            self.pto_deadline = None;
+            return;
+        }
+        let pto = match srtt {
+            Some(srtt) if single_segment => srtt * 2 + MAX_ACK_DELAY,
+            Some(srtt) => srtt * 2,
+            None => INITIAL_PTO,
+        };
+        let deadline = now + pto;
+        self.pto_deadline = Some(rto_deadline.map_or(deadline, |rto| cmp::min(rto, deadline)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(seq: u32, sent: Instant) -> Segment {
+        Segment {
+            seq,
+            payload: vec![0; 1000],
+            fin: false,
+            sent,
+            retransmitted: false,
+            lost: false,
+            sacked: false,
+            probe: false,
+            data_seq: None,
+        }
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn reorder_window() {
+        let now = Instant::now();
+        let mut rack = Rack::new();
+        assert_eq!(rack.reo_wnd(false, None), ms(0));
+
+        rack.on_delivered(&segment(0, now), now + ms(100));
+        assert_eq!(rack.reo_wnd(false, None), ms(25));
+        assert_eq!(rack.reo_wnd(false, Some(ms(10))), ms(10));
+
+        // None in recovery, until reordering was seen
+        assert_eq!(rack.reo_wnd(true, None), ms(0));
+        rack.on_delivered(&segment(2000, now + ms(20)), now + ms(120));
+        rack.on_delivered(&segment(1000, now + ms(10)), now + ms(130));
+        assert!(rack.reordering_seen);
+        assert_eq!(rack.reo_wnd(true, None), ms(25));
+    }
+
+    #[test]
+    fn loss_after_reorder_window() {
+        let now = Instant::now();
+        let mut rack = Rack::new();
+        let mut segments = VecDeque::new();
+        segments.push_back(segment(0, now));
+        segments.push_back(segment(1000, now + ms(10)));
+
+        // Nothing is lost before a delivery
+        assert!(!rack.detect_loss(&mut segments, false, None, now + ms(200)));
+
+        // The first segment is given RACK.rtt plus the reordering window from its
+        // transmission before it is considered lost
+        rack.on_delivered(&segments[1], now + ms(110));
+        segments.pop_back();
+        assert!(!rack.detect_loss(&mut segments, false, None, now + ms(110)));
+        assert!(!segments[0].lost);
+        assert_eq!(rack.reorder_deadline, Some(now + ms(125)));
+
+        assert!(rack.detect_loss(&mut segments, false, None, now + ms(125)));
+        assert!(segments[0].lost);
+        assert_eq!(rack.reorder_deadline, None);
+    }
+
+    #[test]
+    fn lost_probe() {
+        let now = Instant::now();
+        let mut rack = Rack::new();
+        let mut segments = VecDeque::new();
+        segments.push_back(Segment { probe: true, ..segment(0, now) });
+
+        rack.on_delivered(&segment(1000, now + ms(10)), now + ms(110));
+        assert!(!rack.detect_loss(&mut segments, false, None, now + ms(200)));
+        assert!(segments[0].lost);
+    }
+
+    #[test]
+    fn probe_timeout() {
+        let now = Instant::now();
+        let mut rack = Rack::new();
+
+        rack.arm_pto(None, false, None, now);
+        assert_eq!(rack.pto_deadline, Some(now + INITIAL_PTO));
+
+        rack.arm_pto(Some(ms(100)), false, None, now);
+        assert_eq!(rack.pto_deadline, Some(now + ms(200)));
+
+        // Leaving time for a delayed ACK of a single segment
+        rack.arm_pto(Some(ms(100)), true, None, now);
+        assert_eq!(rack.pto_deadline, Some(now + ms(400)));
+
+        // Never after the retransmission timeout
+        rack.arm_pto(Some(ms(100)), false, Some(now + ms(150)), now);
+        assert_eq!(rack.pto_deadline, Some(now + ms(150)));
+
+        // Nor with a probe outstanding
+        rack.tlp_end_seq = Some(1000);
+        rack.arm_pto(Some(ms(100)), false, None, now);
+        assert_eq!(rack.pto_deadline, None);
+    }
+}
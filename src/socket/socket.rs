@@ -1,30 +1,172 @@
 use std::cmp;
 use std::io;
-use std::sync::mpsc;
+use std::mem;
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex, mpsc};
+use std::time::Duration;
 
-use super::{PacketBuffer, SocketError};
+use super::{CongestionControl, PacketBuffer, SocketError, Stats};
+use super::buffers::Buffers;
 use tcp;
 
+/// What happens to the connection when its `Socket` is dropped or the interface stopped
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Linger {
+    /// Send a FIN after any unsent data, which is still delivered in the background
+    #[default]
+    Graceful,
+    /// Like `Graceful`, but reset the connection if data is still unacknowledged after the
+    /// timeout
+    Timeout(Duration),
+    /// Reset the connection at once, discarding unsent data (`SO_LINGER` of 0)
+    Abort,
+}
+
+/// Closes the connection as its linger policy says once the socket goes away
+#[derive(Debug)]
+struct Closer {
+    endpoint: tcp::Endpoint,
+    tx: mpsc::Sender<(tcp::Endpoint, PacketBuffer)>,
+    linger: Linger,
+}
+
+impl Drop for Closer {
+    fn drop(&mut self) {
+        let _ = self.tx.send((self.endpoint, PacketBuffer::close(self.linger)));
+    }
+}
+
+/// Sending half of a socket split by `Socket::to_tx_rx`, which closes the connection as its
+/// linger policy says once dropped
+#[derive(Debug)]
+pub struct SocketSender {
+    buffers: Arc<Buffers>,
+    closer: Closer,
+}
+
+impl SocketSender {
+    /// Sends the data of `buf` and then whatever else it carries, waiting for room in the
+    /// send buffer like `Socket::write`
+    pub fn send(&self, mut buf: PacketBuffer) -> Result<(), SocketError> {
+        let payload = mem::take(&mut buf.payload);
+        let mut rest = &payload[..];
+        loop {
+            let len = if rest.is_empty() {
+                0
+            } else {
+                self.buffers.reserve(rest.len()).ok_or(SocketError::Closed)?
+            };
+            let (chunk, tail) = rest.split_at(len);
+            rest = tail;
+            if rest.is_empty() {
+                buf.payload = chunk.into();
+                return self.queue(buf);
+            }
+            self.queue(PacketBuffer::new(chunk))?;
+        }
+    }
+
+    fn queue(&self, buf: PacketBuffer) -> Result<(), SocketError> {
+        self.closer
+            .tx
+            .send((self.closer.endpoint, buf))
+            .map_err(|_| SocketError::Closed)
+    }
+}
+
 #[derive(Debug)]
 pub struct Socket {
     pub endpoint: tcp::Endpoint,
     rx: mpsc::Receiver<PacketBuffer>,
     tx: mpsc::Sender<(tcp::Endpoint, PacketBuffer)>,
+    /// Published by the interface as segments flow
+    stats: Arc<Mutex<Stats>>,
+    /// Shared with the connection, which sizes its windows by them
+    buffers: Arc<Buffers>,
+    closer: Closer,
 
     rx_buffer: Vec<u8>,
+    rx_closed: bool,
+    tx_closed: bool,
 }
 
 impl Socket {
     pub fn new(endpoint: tcp::Endpoint,
                rx: mpsc::Receiver<PacketBuffer>,
-               tx: mpsc::Sender<(tcp::Endpoint, PacketBuffer)>)
+               tx: mpsc::Sender<(tcp::Endpoint, PacketBuffer)>,
+               stats: Arc<Mutex<Stats>>,
+               buffers: Arc<Buffers>)
                -> Self {
+        let closer = Closer {
+            endpoint,
+            tx: tx.clone(),
+            linger: Linger::default(),
+        };
         Socket {
-            endpoint: endpoint,
-            rx: rx,
-            tx: tx,
+            endpoint,
+            rx,
+            tx,
+            stats,
+            buffers,
+            closer,
             rx_buffer: Vec::new(),
+            rx_closed: false,
+            tx_closed: false,
+        }
+    }
+
+    /// Shuts down the read half, the write half, or both halves of the connection.
+    ///
+    /// Shutting down the write half sends a FIN after all previously written data, while
+    /// reads continue until the peer's FIN. Once the read half is shut down, reads return
+    /// `Ok(0)` and any data still arriving is discarded.
+    pub fn shutdown(&mut self, how: Shutdown) -> Result<(), io::Error> {
+        if how != Shutdown::Read && !self.tx_closed {
+            self.send(PacketBuffer::fin(&[]))
+                .map_err(|_| io::Error::from(io::ErrorKind::NotConnected))?;
+            self.tx_closed = true;
+        }
+
+        if how != Shutdown::Write {
+            self.rx_closed = true;
+            self.rx_buffer.clear();
+            // Data delivered but not read stops counting against the interface's memory
+            while self.rx.try_recv().is_ok() {}
+            self.buffers.detach();
         }
+
+        Ok(())
+    }
+
+    /// Sets what happens to the connection when the socket is dropped or the interface is
+    /// stopped
+    pub fn set_linger(&mut self, linger: Linger) -> Result<(), io::Error> {
+        self.send(PacketBuffer::linger(linger))
+            .map_err(|_| io::Error::from(io::ErrorKind::NotConnected))?;
+        self.closer.linger = linger;
+        Ok(())
+    }
+
+    /// Aborts the connection once sent data stays unacknowledged for longer than `timeout`,
+    /// and advertises it to the peer (RFC 5482)
+    pub fn set_user_timeout(&mut self, timeout: Duration) -> Result<(), io::Error> {
+        self.send(PacketBuffer::user_timeout(timeout))
+            .map_err(|_| io::Error::from(io::ErrorKind::NotConnected))
+    }
+
+    /// Selects the congestion control of the connection, in place of the one it was opened
+    /// with
+    pub fn set_congestion_control(&mut self,
+                                  congestion_control: CongestionControl)
+                                  -> Result<(), io::Error> {
+        self.send(PacketBuffer::congestion_control(congestion_control))
+            .map_err(|_| io::Error::from(io::ErrorKind::NotConnected))
+    }
+
+    /// State and counters of the connection for troubleshooting, as of its latest
+    /// segment or timer
+    pub fn stats(&self) -> Stats {
+        *self.stats.lock().unwrap()
     }
 
     fn recv(&mut self) -> Result<PacketBuffer, SocketError> {
@@ -37,17 +179,33 @@ impl Socket {
             .map_err(|_| SocketError::Closed)
     }
 
-    pub fn to_tx_rx
-        (self)
-         -> (mpsc::Sender<(tcp::Endpoint, PacketBuffer)>, mpsc::Receiver<PacketBuffer>) {
-        (self.tx, self.rx)
+    /// Splits the socket into channel ends, the connection closing once the sender is
+    /// dropped
+    pub fn to_tx_rx(self) -> (SocketSender, mpsc::Receiver<PacketBuffer>) {
+        self.buffers.detach();
+        let sender = SocketSender {
+            buffers: self.buffers,
+            closer: self.closer,
+        };
+        (sender, self.rx)
     }
 }
 
 impl io::Write for Socket {
     fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
-        self.send(PacketBuffer::new(buf))
-            .map(|_| buf.len())
+        if self.tx_closed {
+            return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+        }
+
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // Blocks while the send buffer is full
+        let len = self.buffers
+            .reserve(buf.len())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        self.send(PacketBuffer::new(&buf[..len]))
+            .map(|_| len)
             .map_err(|err| match err {
                          SocketError::Closed => io::Error::from(io::ErrorKind::NotConnected),
                          _ => io::Error::new(io::ErrorKind::Other, "Something else"),
@@ -61,35 +219,32 @@ impl io::Write for Socket {
 
 impl io::Read for Socket {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
-        let mut read_len = 0;
-
-        // Read from buffer if there is any
-        if !self.rx_buffer.is_empty() {
-            let len = self.rx_buffer.len();
-            let end = cmp::min(buf.len(), len);
-            read_len += end;
+        // Only block for more data when nothing is buffered and the peer hasn't sent FIN
+        while self.rx_buffer.is_empty() && !self.rx_closed {
+            let recv = self.recv()
+                .map_err(|err| match err {
+                             SocketError::Closed => io::Error::from(io::ErrorKind::NotConnected),
+                             _ => io::Error::other("Something else"),
+                         })?;
+            match recv.error {
+                Some(SocketError::Timeout) => {
+                    return Err(io::Error::from(io::ErrorKind::TimedOut));
+                }
+                Some(_) => return Err(io::Error::from(io::ErrorKind::ConnectionAborted)),
+                None => (),
+            }
 
-            let bytes: Vec<_> = self.rx_buffer.drain(..end).collect();
-            buf.copy_from_slice(bytes.as_slice());
+            self.rx_buffer.extend_from_slice(&recv.payload);
+            if recv.fin {
+                self.rx_closed = true;
+            }
         }
 
-        self.recv()
-            .map(|recv| {
-                let mut buf = &mut buf[read_len..];
-                let len = cmp::min(recv.payload.len(), buf.len());
-                let mut buf = &mut buf[..len];
-                buf.copy_from_slice(&recv.payload[..len]);
+        let len = cmp::min(buf.len(), self.rx_buffer.len());
+        buf[..len].copy_from_slice(&self.rx_buffer[..len]);
+        self.rx_buffer.drain(..len);
+        self.buffers.on_read(len);
 
-                if len > buf.len() {
-                    self.rx_buffer
-                        .extend_from_slice(&recv.payload[buf.len()..]);
-                }
-
-                read_len + buf.len()
-            })
-            .map_err(|err| match err {
-                         SocketError::Closed => io::Error::from(io::ErrorKind::NotConnected),
-                         _ => io::Error::new(io::ErrorKind::Other, "Something else"),
-                     })
+        Ok(len)
     }
 }
@@ -0,0 +1,53 @@
+use std::time::Duration;
+
+use super::SocketState;
+
+/// Snapshot of the state and counters of a connection, like Linux' `tcp_info`
+#[derive(Clone, Copy, Debug)]
+pub struct Stats {
+    pub state: SocketState,
+    /// Smoothed round-trip time, none before the first sample
+    pub srtt: Option<Duration>,
+    pub rttvar: Duration,
+    pub rto: Duration,
+    pub cwnd: usize,
+    pub ssthresh: usize,
+    /// Send window announced by the peer
+    pub snd_wnd: usize,
+    /// Receive window we announce
+    pub rcv_wnd: usize,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub segments_sent: u64,
+    pub segments_received: u64,
+    /// Segments sent again after being presumed lost
+    pub retransmits: u64,
+    /// Segments received beyond RCV.NXT, which are dropped for lack of reassembly
+    pub out_of_order: u64,
+    pub dup_acks: u64,
+    /// Retransmission timeouts found spurious by F-RTO or Eifel
+    pub spurious_rtos: u64,
+}
+
+impl Stats {
+    pub fn new(state: SocketState) -> Self {
+        Stats {
+            state,
+            srtt: None,
+            rttvar: Duration::from_secs(0),
+            rto: Duration::from_secs(0),
+            cwnd: 0,
+            ssthresh: 0,
+            snd_wnd: 0,
+            rcv_wnd: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            segments_sent: 0,
+            segments_received: 0,
+            retransmits: 0,
+            out_of_order: 0,
+            dup_acks: 0,
+            spurious_rtos: 0,
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use std::cmp;
+use std::time::{Duration, Instant};
+
+use tcp;
+
+/// Bounds on a user timeout taken from the peer (L_LIMIT and U_LIMIT, RFC 5482 section 3.1),
+/// the lower one as the minimum of RFC 1122 section 4.2.3.5
+const MIN_TIMEOUT: Duration = Duration::from_secs(100);
+const MAX_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Largest value of the 15 bit timeout field
+const MAX_FIELD: u64 = 0x7fff;
+
+/// How long sent data may stay unacknowledged before the connection is aborted, and the
+/// User Timeout Option advertising it (RFC 5482)
+#[derive(Debug)]
+pub struct UserTimeout {
+    /// Set locally (USER_TIMEOUT)
+    local: Option<Duration>,
+    /// The peer's advertised timeout may take over ("changeable"), only when none was
+    /// set for the interface or the socket
+    changeable: bool,
+    /// Advertised by the peer (ADV_UTO)
+    remote: Option<Duration>,
+}
+
+impl UserTimeout {
+    pub fn new(local: Option<Duration>) -> Self {
+        UserTimeout {
+            local,
+            changeable: local.is_none(),
+            remote: None,
+        }
+    }
+
+    /// Sets the timeout of the socket, which then stays regardless of the peer's
+    pub fn set(&mut self, timeout: Duration) {
+        self.local = Some(timeout);
+        self.changeable = false;
+    }
+
+    pub fn on_option(&mut self, minutes: bool, timeout: u16) {
+        let secs = if minutes { timeout as u64 * 60 } else { timeout as u64 };
+        self.remote = Some(Duration::from_secs(secs));
+    }
+
+    /// Timeout in effect, none to retransmit forever
+    pub fn timeout(&self) -> Option<Duration> {
+        match (self.changeable, self.remote) {
+            (true, Some(remote)) => {
+                let timeout = cmp::max(remote, self.local.unwrap_or(remote));
+                Some(cmp::min(cmp::max(timeout, MIN_TIMEOUT), MAX_TIMEOUT))
+            }
+            _ => self.local,
+        }
+    }
+
+    /// Whether data unacknowledged since `since` has been so for too long
+    pub fn expired(&self, since: Option<Instant>, now: Instant) -> bool {
+        match (self.timeout(), since) {
+            (Some(timeout), Some(since)) => now.duration_since(since) >= timeout,
+            _ => false,
+        }
+    }
+
+    /// Option advertising our timeout, in seconds unless it takes minutes to fit
+    pub fn option(&self) -> Option<tcp::TcpOption<'static>> {
+        let secs = self.local?.as_secs();
+        let (minutes, timeout) = if secs <= MAX_FIELD {
+            (false, secs)
+        } else {
+            (true, cmp::min(secs / 60, MAX_FIELD))
+        };
+        Some(tcp::TcpOption::UserTimeout {
+                 minutes,
+                 timeout: timeout as u16,
+             })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeout_without_remote() {
+        assert_eq!(UserTimeout::new(None).timeout(), None);
+        let uto = UserTimeout::new(Some(Duration::from_secs(5)));
+        assert_eq!(uto.timeout(), Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn timeout_with_smaller_remote() {
+        // A timeout set locally stays, however short
+        let mut uto = UserTimeout::new(Some(Duration::from_secs(5)));
+        uto.on_option(false, 1);
+        assert_eq!(uto.timeout(), Some(Duration::from_secs(5)));
+
+        // Without one, the peer's is taken over but no lower than MIN_TIMEOUT
+        let mut uto = UserTimeout::new(None);
+        uto.on_option(false, 1);
+        assert_eq!(uto.timeout(), Some(MIN_TIMEOUT));
+    }
+
+    #[test]
+    fn timeout_with_larger_remote() {
+        let mut uto = UserTimeout::new(Some(Duration::from_secs(5)));
+        uto.on_option(true, 10);
+        assert_eq!(uto.timeout(), Some(Duration::from_secs(5)));
+
+        let mut uto = UserTimeout::new(None);
+        uto.on_option(true, 10);
+        assert_eq!(uto.timeout(), Some(Duration::from_secs(600)));
+        uto.on_option(true, MAX_FIELD as u16);
+        assert_eq!(uto.timeout(), Some(MAX_TIMEOUT));
+
+        // Setting one for the socket overrides the peer's from then on
+        uto.set(Duration::from_secs(30));
+        assert_eq!(uto.timeout(), Some(Duration::from_secs(30)));
+    }
+}
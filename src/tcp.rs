@@ -1,9 +1,27 @@
 use super::error::Error;
 use super::ipv4;
+use super::mptcp::MptcpOption;
+use super::tcp_ao;
 
 use std::fmt;
 
 use byteorder::{ByteOrder, NetworkEndian};
+use md5::{Digest, Md5};
+
+const OPT_EOL: u8 = 0;
+const OPT_NOP: u8 = 1;
+const OPT_MSS: u8 = 2;
+const OPT_WS: u8 = 3;
+const OPT_SACK_PERMITTED: u8 = 4;
+const OPT_SACK: u8 = 5;
+const OPT_TIMESTAMPS: u8 = 8;
+const OPT_MD5_SIGNATURE: u8 = 19;
+const OPT_USER_TIMEOUT: u8 = 28;
+const OPT_AUTHENTICATION: u8 = 29;
+const OPT_MPTCP: u8 = 30;
+
+/// Maximum length of the options area, bounded by the 4-bit data offset
+const MAX_OPTIONS_LEN: usize = 40;
 
 #[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
 pub struct Endpoint {
@@ -14,12 +32,23 @@ pub struct Endpoint {
 impl Endpoint {
     pub fn new(addr: ipv4::Address, port: u16) -> Self {
         Endpoint {
-            addr: addr,
-            port: port,
+            addr,
+            port,
         }
     }
 }
 
+/// Sequence number comparison modulo 2^32 (RFC 793 section 3.3)
+#[inline]
+pub fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+#[inline]
+pub fn seq_le(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) <= 0
+}
+
 pub struct Packet<T: AsRef<[u8]>> {
     buffer: T,
 }
@@ -38,15 +67,28 @@ mod field {
 }
 
 impl<T: AsRef<[u8]>> Packet<T> {
+    /// Wraps a received segment, checking that its header and options are all there
     pub fn new(buffer: T) -> Result<Self, Error> {
         let len = buffer.as_ref().len();
         if len < field::URGENT.end {
             Err(Error::Truncated)
         } else {
-            Ok(Packet { buffer: buffer })
+            let packet = Packet { buffer };
+            if (packet.data_offset() as usize) < field::URGENT.end {
+                Err(Error::Malformed)
+            } else if len < packet.data_offset() as usize {
+                Err(Error::Truncated)
+            } else {
+                Ok(packet)
+            }
         }
     }
 
+    /// Wraps a buffer a segment is about to be written into, see `Repr::emit`
+    pub fn new_unchecked(buffer: T) -> Self {
+        Packet { buffer }
+    }
+
     #[inline]
     pub fn src_port(&self) -> u16 {
         let buf = self.buffer.as_ref();
@@ -131,6 +173,12 @@ impl<T: AsRef<[u8]>> Packet<T> {
         buf[field::OFF_FLG.end - 1] & 0x01 != 0
     }
 
+    #[inline]
+    pub fn flags(&self) -> Flags {
+        let buf = self.buffer.as_ref();
+        Flags::from_bits(NetworkEndian::read_u16(&buf[field::OFF_FLG]))
+    }
+
     #[inline]
     pub fn window_size(&self) -> u16 {
         let buf = self.buffer.as_ref();
@@ -149,6 +197,71 @@ impl<T: AsRef<[u8]>> Packet<T> {
         NetworkEndian::read_u16(&buf[field::URGENT])
     }
 
+    /// MD5 digest of the segment under `key` (RFC 2385 section 2.0)
+    pub fn md5_digest(&self,
+                      src_addr: &ipv4::Address,
+                      dst_addr: &ipv4::Address,
+                      key: &[u8])
+                      -> [u8; 16] {
+        let buf = self.buffer.as_ref();
+        let mut len = [0; 2];
+        NetworkEndian::write_u16(&mut len, buf.len() as u16);
+
+        let mut md5 = Md5::new();
+        md5.update(src_addr.as_bytes());
+        md5.update(dst_addr.as_bytes());
+        md5.update([0, ipv4::Protocol::Tcp.into()]);
+        md5.update(len);
+        // Header without options, the checksum taken as zero
+        md5.update(&buf[..field::CHECKSUM.start]);
+        md5.update([0, 0]);
+        md5.update(&buf[field::URGENT]);
+        md5.update(&buf[self.data_offset() as usize..]);
+        md5.update(key);
+
+        let mut digest = [0; 16];
+        digest.copy_from_slice(&md5.finalize());
+        digest
+    }
+
+    /// TCP-AO MAC of the segment under `key` (RFC 5925 section 5.1)
+    ///
+    /// The MAC field of the Authentication option is taken as zero, so the result can be
+    /// compared against it or written into it.
+    pub fn ao_mac(&self,
+                  src_addr: &ipv4::Address,
+                  dst_addr: &ipv4::Address,
+                  key: &tcp_ao::TrafficKey,
+                  sne: u32)
+                  -> [u8; tcp_ao::MAC_LEN] {
+        let buf = self.buffer.as_ref();
+        let header_len = self.data_offset() as usize;
+        let mut prefix = [0; 16];
+        NetworkEndian::write_u32(&mut prefix[..4], sne);
+        prefix[4..8].copy_from_slice(src_addr.as_bytes());
+        prefix[8..12].copy_from_slice(dst_addr.as_bytes());
+        prefix[13] = ipv4::Protocol::Tcp.into();
+        NetworkEndian::write_u16(&mut prefix[14..], buf.len() as u16);
+
+        let mut header = buf[..header_len].to_vec();
+        header[field::CHECKSUM].copy_from_slice(&[0, 0]);
+        let mut offset = field::URGENT.end;
+        while offset < header_len {
+            match TcpOption::parse(&buf[offset..header_len]) {
+                Ok((TcpOption::Eol, _)) | Err(_) => break,
+                Ok((TcpOption::Authentication { .. }, len)) => {
+                    for byte in &mut header[offset + 4..offset + len] {
+                        *byte = 0;
+                    }
+                    break;
+                }
+                Ok((_, len)) => offset += len,
+            }
+        }
+
+        key.mac(&[&prefix, &header, &buf[header_len..]])
+    }
+
     pub fn checksum_valid(&self, src_addr: &ipv4::Address, dst_addr: &ipv4::Address) -> bool {
         use ipv4::checksum;
         let buf = self.buffer.as_ref();
@@ -165,6 +278,17 @@ impl<'a, T: AsRef<[u8]> + ?Sized> Packet<&'a T> {
         let buf = self.buffer.as_ref();
         &buf[len..]
     }
+
+    #[inline]
+    pub fn options(&self) -> &'a [u8] {
+        let len = (self.data_offset()) as usize;
+        let buf = self.buffer.as_ref();
+        &buf[field::URGENT.end..len]
+    }
+
+    pub fn option_iter(&self) -> TcpOptionIter<'a> {
+        TcpOptionIter::new(self.options())
+    }
 }
 
 impl<T: AsRef<[u8]> + AsMut<[u8]>> Packet<T> {
@@ -296,6 +420,15 @@ impl<T: AsRef<[u8]> + AsMut<[u8]>> Packet<T> {
         }
     }
 
+    /// Replaces all flags, keeping the data offset and clearing the reserved bits
+    #[inline]
+    pub fn set_flags(&mut self, flags: Flags) {
+        let offset = self.data_offset();
+        let buf = self.buffer.as_mut();
+        let bits = (((offset / 4) as u16) << 12) | flags.bits();
+        NetworkEndian::write_u16(&mut buf[field::OFF_FLG], bits);
+    }
+
     #[inline]
     pub fn set_window_size(&mut self, value: u16) {
         let mut buf = self.buffer.as_mut();
@@ -339,8 +472,9 @@ impl<'a, T: AsRef<[u8]> + AsMut<[u8]> + ?Sized> Packet<&'a mut T> {
 
     #[inline]
     pub fn options(&mut self) -> &mut [u8] {
+        let len = (self.data_offset()) as usize;
         let mut buf = self.buffer.as_mut();
-        &mut buf[field::URGENT.end..]
+        &mut buf[field::URGENT.end..len]
     }
 }
 
@@ -364,6 +498,356 @@ impl<T: AsRef<[u8]>> fmt::Debug for Packet<T> {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TcpOption<'a> {
+    Eol,
+    Nop,
+    Mss(u16),
+    WindowScale(u8),
+    SackPermitted,
+    Sack([Option<(u32, u32)>; 4]),
+    Timestamps { tsval: u32, tsecr: u32 },
+    /// TCP MD5 signature (RFC 2385), filled in by `Repr::emit` when given a key
+    Md5Signature([u8; 16]),
+    /// User Timeout Option (RFC 5482), in minutes rather than seconds if `minutes`
+    UserTimeout { minutes: bool, timeout: u16 },
+    /// TCP Authentication Option (RFC 5925), the MAC filled in by `Repr::emit`
+    Authentication {
+        key_id: u8,
+        rnext_key_id: u8,
+        mac: &'a [u8],
+    },
+    /// Multipath TCP (RFC 8684)
+    Mptcp(MptcpOption),
+    Unknown { kind: u8, data: &'a [u8] },
+}
+
+impl<'a> TcpOption<'a> {
+    /// Parses a single option, returning it along with the number of bytes it occupied
+    pub fn parse(buffer: &'a [u8]) -> Result<(TcpOption<'a>, usize), Error> {
+        let kind = match buffer.first() {
+            Some(&kind) => kind,
+            None => return Err(Error::Truncated),
+        };
+        match kind {
+            OPT_EOL => return Ok((TcpOption::Eol, 1)),
+            OPT_NOP => return Ok((TcpOption::Nop, 1)),
+            _ => (),
+        }
+
+        let len = match buffer.get(1) {
+            Some(&len) => len as usize,
+            None => return Err(Error::Truncated),
+        };
+        if len < 2 {
+            return Err(Error::Malformed);
+        }
+        if len > buffer.len() {
+            return Err(Error::Truncated);
+        }
+
+        let data = &buffer[2..len];
+        let option = match (kind, len) {
+            (OPT_MSS, 4) => TcpOption::Mss(NetworkEndian::read_u16(data)),
+            (OPT_WS, 3) => TcpOption::WindowScale(data[0]),
+            (OPT_SACK_PERMITTED, 2) => TcpOption::SackPermitted,
+            (OPT_SACK, 10) | (OPT_SACK, 18) | (OPT_SACK, 26) | (OPT_SACK, 34) => {
+                let mut blocks = [None; 4];
+                for (block, range) in blocks.iter_mut().zip(data.chunks(8)) {
+                    *block = Some((NetworkEndian::read_u32(&range[..4]),
+                                   NetworkEndian::read_u32(&range[4..])));
+                }
+                TcpOption::Sack(blocks)
+            }
+            (OPT_TIMESTAMPS, 10) => {
+                TcpOption::Timestamps {
+                    tsval: NetworkEndian::read_u32(&data[..4]),
+                    tsecr: NetworkEndian::read_u32(&data[4..]),
+                }
+            }
+            (OPT_MD5_SIGNATURE, 18) => {
+                let mut digest = [0; 16];
+                digest.copy_from_slice(data);
+                TcpOption::Md5Signature(digest)
+            }
+            (OPT_USER_TIMEOUT, 4) => {
+                let field = NetworkEndian::read_u16(data);
+                TcpOption::UserTimeout {
+                    minutes: field & 0x8000 != 0,
+                    timeout: field & 0x7fff,
+                }
+            }
+            (OPT_AUTHENTICATION, len) if len >= 4 => {
+                TcpOption::Authentication {
+                    key_id: data[0],
+                    rnext_key_id: data[1],
+                    mac: &data[2..],
+                }
+            }
+            (OPT_MPTCP, _) => {
+                match MptcpOption::parse(data)? {
+                    Some(option) => TcpOption::Mptcp(option),
+                    None => {
+                        TcpOption::Unknown {
+                            kind,
+                            data,
+                        }
+                    }
+                }
+            }
+            (OPT_MSS, _) |
+            (OPT_WS, _) |
+            (OPT_SACK_PERMITTED, _) |
+            (OPT_SACK, _) |
+            (OPT_TIMESTAMPS, _) |
+            (OPT_MD5_SIGNATURE, _) |
+            (OPT_AUTHENTICATION, _) => return Err(Error::Malformed),
+            (kind, _) => {
+                TcpOption::Unknown {
+                    kind,
+                    data,
+                }
+            }
+        };
+
+        Ok((option, len))
+    }
+
+    pub fn buffer_len(&self) -> usize {
+        match *self {
+            TcpOption::Eol | TcpOption::Nop => 1,
+            TcpOption::Mss(_) => 4,
+            TcpOption::WindowScale(_) => 3,
+            TcpOption::SackPermitted => 2,
+            TcpOption::Sack(ref blocks) => 2 + blocks.iter().filter(|b| b.is_some()).count() * 8,
+            TcpOption::Timestamps { .. } => 10,
+            TcpOption::Md5Signature(_) => 18,
+            TcpOption::UserTimeout { .. } => 4,
+            TcpOption::Authentication { mac, .. } => 4 + mac.len(),
+            TcpOption::Mptcp(ref option) => option.buffer_len(),
+            TcpOption::Unknown { data, .. } => 2 + data.len(),
+        }
+    }
+
+    pub fn kind(&self) -> u8 {
+        match *self {
+            TcpOption::Eol => OPT_EOL,
+            TcpOption::Nop => OPT_NOP,
+            TcpOption::Mss(_) => OPT_MSS,
+            TcpOption::WindowScale(_) => OPT_WS,
+            TcpOption::SackPermitted => OPT_SACK_PERMITTED,
+            TcpOption::Sack(_) => OPT_SACK,
+            TcpOption::Timestamps { .. } => OPT_TIMESTAMPS,
+            TcpOption::Md5Signature(_) => OPT_MD5_SIGNATURE,
+            TcpOption::UserTimeout { .. } => OPT_USER_TIMEOUT,
+            TcpOption::Authentication { .. } => OPT_AUTHENTICATION,
+            TcpOption::Mptcp(_) => OPT_MPTCP,
+            TcpOption::Unknown { kind, .. } => kind,
+        }
+    }
+
+    /// Writes the option to the start of `buffer`, returning the number of bytes written
+    pub fn emit(&self, buffer: &mut [u8]) -> usize {
+        let len = self.buffer_len();
+        buffer[0] = self.kind();
+        if len == 1 {
+            return len;
+        }
+
+        buffer[1] = len as u8;
+        let data = &mut buffer[2..len];
+        match *self {
+            TcpOption::Mss(mss) => NetworkEndian::write_u16(data, mss),
+            TcpOption::WindowScale(shift) => data[0] = shift,
+            TcpOption::Sack(ref blocks) => {
+                for (range, block) in data.chunks_mut(8).zip(blocks.iter().flatten()) {
+                    NetworkEndian::write_u32(&mut range[..4], block.0);
+                    NetworkEndian::write_u32(&mut range[4..], block.1);
+                }
+            }
+            TcpOption::Timestamps { tsval, tsecr } => {
+                NetworkEndian::write_u32(&mut data[..4], tsval);
+                NetworkEndian::write_u32(&mut data[4..], tsecr);
+            }
+            TcpOption::Md5Signature(ref digest) => data.copy_from_slice(digest),
+            TcpOption::UserTimeout { minutes, timeout } => {
+                NetworkEndian::write_u16(data, (minutes as u16) << 15 | timeout & 0x7fff)
+            }
+            TcpOption::Authentication {
+                key_id,
+                rnext_key_id,
+                mac,
+            } => {
+                data[0] = key_id;
+                data[1] = rnext_key_id;
+                data[2..].copy_from_slice(mac);
+            }
+            TcpOption::Mptcp(ref option) => option.emit(data),
+            TcpOption::Unknown { data: payload, .. } => data.copy_from_slice(payload),
+            _ => (),
+        }
+
+        len
+    }
+}
+
+/// Iterator over the options of a received segment
+///
+/// Iteration stops after an End of Option List or the first malformed option.
+pub struct TcpOptionIter<'a> {
+    buffer: &'a [u8],
+}
+
+impl<'a> TcpOptionIter<'a> {
+    pub fn new(buffer: &'a [u8]) -> Self {
+        TcpOptionIter { buffer }
+    }
+}
+
+impl<'a> Iterator for TcpOptionIter<'a> {
+    type Item = Result<TcpOption<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.is_empty() {
+            return None;
+        }
+
+        match TcpOption::parse(self.buffer) {
+            Ok((TcpOption::Eol, _)) => {
+                // Everything after the end of the list is padding
+                self.buffer = &[];
+                Some(Ok(TcpOption::Eol))
+            }
+            Ok((option, len)) => {
+                self.buffer = &self.buffer[len..];
+                Some(Ok(option))
+            }
+            Err(error) => {
+                self.buffer = &[];
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Eq, Hash, PartialEq)]
+pub struct Flags(u16);
+
+impl Flags {
+    pub const FIN: Flags = Flags(0x001);
+    pub const SYN: Flags = Flags(0x002);
+    pub const RST: Flags = Flags(0x004);
+    pub const PSH: Flags = Flags(0x008);
+    pub const ACK: Flags = Flags(0x010);
+    pub const URG: Flags = Flags(0x020);
+    pub const ECE: Flags = Flags(0x040);
+    pub const CWR: Flags = Flags(0x080);
+    pub const NS: Flags = Flags(0x100);
+
+    pub fn empty() -> Self {
+        Flags(0)
+    }
+
+    /// Flags from the 9 flag bits as laid out on the wire, NS being the most significant
+    pub fn from_bits(bits: u16) -> Self {
+        Flags(bits & 0x1FF)
+    }
+
+    pub fn bits(&self) -> u16 {
+        self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(&self, other: Flags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: Flags) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: Flags) {
+        self.0 &= !other.0;
+    }
+
+    pub fn set(&mut self, other: Flags, value: bool) {
+        if value {
+            self.insert(other);
+        } else {
+            self.remove(other);
+        }
+    }
+
+    pub fn fin(&self) -> bool {
+        self.contains(Flags::FIN)
+    }
+
+    pub fn syn(&self) -> bool {
+        self.contains(Flags::SYN)
+    }
+
+    pub fn rst(&self) -> bool {
+        self.contains(Flags::RST)
+    }
+
+    pub fn psh(&self) -> bool {
+        self.contains(Flags::PSH)
+    }
+
+    pub fn ack(&self) -> bool {
+        self.contains(Flags::ACK)
+    }
+
+    pub fn urg(&self) -> bool {
+        self.contains(Flags::URG)
+    }
+
+    pub fn ece(&self) -> bool {
+        self.contains(Flags::ECE)
+    }
+
+    pub fn cwr(&self) -> bool {
+        self.contains(Flags::CWR)
+    }
+
+    pub fn ns(&self) -> bool {
+        self.contains(Flags::NS)
+    }
+}
+
+impl std::ops::BitOr for Flags {
+    type Output = Flags;
+
+    fn bitor(self, other: Flags) -> Flags {
+        Flags(self.0 | other.0)
+    }
+}
+
+impl fmt::Debug for Flags {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        const NAMES: [&str; 9] = ["FIN", "SYN", "RST", "PSH", "ACK", "URG", "ECE", "CWR", "NS"];
+
+        let mut first = true;
+        for (bit, name) in NAMES.iter().enumerate() {
+            if self.0 & (1 << bit) != 0 {
+                if !first {
+                    fmt.write_str("|")?;
+                }
+                fmt.write_str(name)?;
+                first = false;
+            }
+        }
+        if first {
+            fmt.write_str("(empty)")?;
+        }
+        Ok(())
+    }
+}
+
+/// The control flag that drives the connection state machine for a segment
 #[derive(Debug, PartialEq)]
 pub enum Control {
     None,
@@ -372,20 +856,69 @@ pub enum Control {
     Rst,
 }
 
+/// Key authenticating the segments of a connection
+#[derive(Clone, Copy, Debug)]
+pub enum SigningKey<'k> {
+    /// TCP MD5 signature (RFC 2385)
+    Md5(&'k [u8]),
+    /// TCP Authentication Option (RFC 5925)
+    Ao {
+        key: &'k tcp_ao::TrafficKey,
+        /// Sequence number extension of the segment
+        sne: u32,
+        key_id: u8,
+        rnext_key_id: u8,
+    },
+}
+
+impl<'k> SigningKey<'k> {
+    /// Option to include in `Repr::options` to carry the signature
+    pub fn option(&self) -> TcpOption<'static> {
+        match *self {
+            SigningKey::Md5(_) => TcpOption::Md5Signature([0; 16]),
+            SigningKey::Ao {
+                key_id,
+                rnext_key_id,
+                ..
+            } => {
+                TcpOption::Authentication {
+                    key_id,
+                    rnext_key_id,
+                    mac: &[0; tcp_ao::MAC_LEN],
+                }
+            }
+        }
+    }
+}
+
+/// Constant time comparison of signatures
+fn signature_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
 #[derive(Debug)]
 pub struct Repr<'a> {
     pub src_port: u16,
     pub dst_port: u16,
     pub seq: u32,
-    pub ack: Option<u32>,
-    pub control: Control,
+    /// Acknowledgment number, only meaningful when the ACK flag is set
+    pub ack_num: u32,
+    pub flags: Flags,
+    pub window: u16,
+    /// Urgent pointer, only meaningful when the URG flag is set
+    pub urgent: u16,
+    pub options: Vec<TcpOption<'a>>,
     pub payload: &'a [u8],
 }
 
 impl<'a> Repr<'a> {
+    /// Parses a segment, checking its MD5 signature or TCP-AO MAC against `key`
+    ///
+    /// Segments must be signed exactly when a key is given.
     pub fn parse<T: ?Sized>(packet: &Packet<&'a T>,
                             src_addr: &ipv4::Address,
-                            dst_addr: &ipv4::Address)
+                            dst_addr: &ipv4::Address,
+                            key: Option<SigningKey>)
                             -> Result<Self, Error>
         where T: AsRef<[u8]>
     {
@@ -395,65 +928,510 @@ impl<'a> Repr<'a> {
         if packet.dst_port() == 0 {
             return Err(Error::Malformed);
         }
+        if (packet.data_offset() as usize) < field::URGENT.end {
+            return Err(Error::Malformed);
+        }
 
         // if !packet.checksum_valid(src_addr, dst_addr) {
         //    return Err(Error::Checksum);
         // }
 
-        let control = if packet.flag_syn() {
-            Control::Syn
-        } else if packet.flag_fin() {
-            Control::Fin
-        } else if packet.flag_rst() {
-            Control::Rst
-        } else {
-            Control::None
-        };
+        let mut options = Vec::new();
+        let mut options_len = 0;
+        for option in packet.option_iter() {
+            let option = option?;
+            options_len += option.buffer_len();
+            options.push(option);
+        }
+        // Keep zero padding beyond the next 4 byte boundary so the data offset survives
+        let padded_len = options_len.div_ceil(4) * 4;
+        for _ in padded_len..packet.options().len() {
+            options.push(TcpOption::Eol);
+        }
 
-        let ack_num = if packet.flag_ack() {
-            Some(packet.ack_num())
-        } else {
-            None
+        let signature = options
+            .iter()
+            .cloned()
+            .find(|option| {
+                      matches!(*option,
+                               TcpOption::Md5Signature(_) | TcpOption::Authentication { .. })
+                  });
+        let valid = match (key, signature) {
+            (Some(SigningKey::Md5(key)), Some(TcpOption::Md5Signature(digest))) => {
+                signature_eq(&packet.md5_digest(src_addr, dst_addr, key), &digest)
+            }
+            (Some(SigningKey::Ao { key, sne, key_id, .. }),
+             Some(TcpOption::Authentication { key_id: received, mac, .. })) => {
+                received == key_id &&
+                signature_eq(&packet.ao_mac(src_addr, dst_addr, key, sne), mac)
+            }
+            (None, None) => true,
+            _ => false,
         };
+        if !valid {
+            return Err(Error::Signature);
+        }
 
         Ok(Repr {
                src_port: packet.src_port(),
                dst_port: packet.dst_port(),
                seq: packet.seq_num(),
-               ack: ack_num,
-               control: control,
+               ack_num: packet.ack_num(),
+               flags: packet.flags(),
+               window: packet.window_size(),
+               urgent: packet.urgent(),
+               options,
                payload: packet.payload(),
            })
     }
 
+    /// Acknowledgment number if the ACK flag is set
+    pub fn ack(&self) -> Option<u32> {
+        if self.flags.ack() {
+            Some(self.ack_num)
+        } else {
+            None
+        }
+    }
+
+    pub fn control(&self) -> Control {
+        if self.flags.syn() {
+            Control::Syn
+        } else if self.flags.fin() {
+            Control::Fin
+        } else if self.flags.rst() {
+            Control::Rst
+        } else {
+            Control::None
+        }
+    }
+
+    /// Sequence space occupied by the segment, SYN and FIN counting as one each
+    pub fn segment_len(&self) -> usize {
+        self.payload.len() + self.flags.syn() as usize + self.flags.fin() as usize
+    }
+
+    /// Maximum segment size announced by the peer, if any
+    pub fn max_seg_size(&self) -> Option<u16> {
+        self.options
+            .iter()
+            .filter_map(|option| match *option {
+                            TcpOption::Mss(mss) => Some(mss),
+                            _ => None,
+                        })
+            .next()
+    }
+
+    /// Window scale shift advertised by the peer (RFC 7323), if any
+    pub fn window_scale(&self) -> Option<u8> {
+        self.options
+            .iter()
+            .filter_map(|option| match *option {
+                            TcpOption::WindowScale(shift) => Some(shift),
+                            _ => None,
+                        })
+            .next()
+    }
+
+    /// User timeout advertised by the peer (RFC 5482) as minutes flag and timeout, if any
+    pub fn user_timeout(&self) -> Option<(bool, u16)> {
+        self.options
+            .iter()
+            .filter_map(|option| match *option {
+                            TcpOption::UserTimeout { minutes, timeout } => Some((minutes, timeout)),
+                            _ => None,
+                        })
+            .next()
+    }
+
+    /// Timestamp value and echo reply (RFC 7323), if any
+    pub fn timestamps(&self) -> Option<(u32, u32)> {
+        self.options
+            .iter()
+            .filter_map(|option| match *option {
+                            TcpOption::Timestamps { tsval, tsecr } => Some((tsval, tsecr)),
+                            _ => None,
+                        })
+            .next()
+    }
+
+    pub fn options_len(&self) -> usize {
+        self.options.iter().map(|option| option.buffer_len()).sum()
+    }
+
+    /// Drops options until they fit the option space, SACK blocks first, the oldest of
+    /// them before the others, then the user timeout, and returns whether they fit
+    ///
+    /// Timestamps are never dropped, as once negotiated they have to go on every segment
+    /// (RFC 7323 section 3.2).
+    pub fn fit_options(&mut self) -> bool {
+        while self.options_len() > MAX_OPTIONS_LEN {
+            let sack = self.options
+                .iter_mut()
+                .filter_map(|option| match *option {
+                                TcpOption::Sack(ref mut blocks) if blocks[1].is_some() => {
+                                    Some(blocks)
+                                }
+                                _ => None,
+                            })
+                .next();
+            if let Some(blocks) = sack {
+                let oldest = blocks.iter().rposition(Option::is_some).unwrap();
+                blocks[oldest] = None;
+                continue;
+            }
+
+            let shed = [OPT_SACK, OPT_USER_TIMEOUT]
+                .iter()
+                .filter_map(|&kind| {
+                                self.options
+                                    .iter()
+                                    .position(|option| option.kind() == kind)
+                            })
+                .next();
+            match shed {
+                Some(index) => {
+                    self.options.remove(index);
+                }
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Length of the header including options, padded to a multiple of 4
     pub fn header_len(&self) -> usize {
-        field::URGENT.end
+        field::URGENT.end + self.options_len().div_ceil(4) * 4
     }
 
+    /// Writes the segment, signing it with `key` into the option from `SigningKey::option`
+    ///
+    /// Fails if the options don't fit the data offset, see `fit_options`.
     pub fn emit<T: ?Sized>(&self,
                            packet: &mut Packet<&mut T>,
                            src_addr: &ipv4::Address,
-                           dst_addr: &ipv4::Address)
+                           dst_addr: &ipv4::Address,
+                           key: Option<SigningKey>)
+                           -> Result<(), Error>
         where T: AsRef<[u8]> + AsMut<[u8]>
     {
+        if self.options_len() > MAX_OPTIONS_LEN {
+            return Err(Error::Malformed);
+        }
+
         packet.set_src_port(self.src_port);
         packet.set_dst_port(self.dst_port);
         packet.set_seq_num(self.seq);
-        packet.set_ack_num(self.ack.unwrap_or(0));
-        packet.set_window_size(43690);
+        packet.set_ack_num(self.ack_num);
+        packet.set_window_size(self.window);
         packet.set_data_offset(self.header_len() as u8);
-        packet.clear_flags();
-        match self.control {
-            Control::None => (),
-            Control::Syn => packet.set_flag_syn(true),
-            Control::Fin => packet.set_flag_fin(true),
-            Control::Rst => packet.set_flag_rst(true),
-        };
-        if self.ack.is_some() {
-            packet.set_flag_ack(true);
+        packet.set_flags(self.flags);
+        packet.set_urgent(self.urgent);
+
+        let mut signature = None;
+        {
+            let options = packet.options();
+            let mut offset = 0;
+            for option in &self.options {
+                match *option {
+                    TcpOption::Md5Signature(_) => signature = Some(offset + 2..offset + 18),
+                    TcpOption::Authentication { mac, .. } => {
+                        signature = Some(offset + 4..offset + 4 + mac.len())
+                    }
+                    _ => (),
+                }
+                offset += option.emit(&mut options[offset..]);
+            }
+            // Pad with End of Option List bytes
+            for byte in &mut options[offset..] {
+                *byte = 0;
+            }
         }
 
         packet.payload().copy_from_slice(self.payload);
+        if let Some(key) = key {
+            debug_assert!(signature.is_some(), "signed segment without a signature option");
+            if let Some(range) = signature {
+                match key {
+                    SigningKey::Md5(key) => {
+                        let digest = packet.md5_digest(src_addr, dst_addr, key);
+                        packet.options()[range].copy_from_slice(&digest);
+                    }
+                    SigningKey::Ao { key, sne, .. } => {
+                        let mac = packet.ao_mac(src_addr, dst_addr, key, sne);
+                        packet.options()[range].copy_from_slice(&mac);
+                    }
+                }
+            }
+        }
         packet.fill_checksum(src_addr, dst_addr);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mptcp::{DataSeq, JOIN_ACK_HMAC_LEN, Mapping};
+
+    fn round_trip(option: TcpOption) {
+        let mut buffer = [0; MAX_OPTIONS_LEN];
+        let len = option.emit(&mut buffer);
+        assert_eq!(len, option.buffer_len());
+        assert_eq!(TcpOption::parse(&buffer[..len]).unwrap(), (option, len));
+    }
+
+    #[test]
+    fn option_round_trip() {
+        round_trip(TcpOption::Eol);
+        round_trip(TcpOption::Nop);
+        round_trip(TcpOption::Mss(1460));
+        round_trip(TcpOption::WindowScale(7));
+        round_trip(TcpOption::SackPermitted);
+        round_trip(TcpOption::Timestamps {
+                       tsval: 0x01020304,
+                       tsecr: 0xfffffffe,
+                   });
+        round_trip(TcpOption::Md5Signature([0xa5; 16]));
+        round_trip(TcpOption::UserTimeout {
+                       minutes: true,
+                       timeout: 0x7fff,
+                   });
+        round_trip(TcpOption::UserTimeout {
+                       minutes: false,
+                       timeout: 30,
+                   });
+        round_trip(TcpOption::Authentication {
+                       key_id: 1,
+                       rnext_key_id: 2,
+                       mac: &[0x5a; tcp_ao::MAC_LEN],
+                   });
+        round_trip(TcpOption::Unknown {
+                       kind: 253,
+                       data: &[1, 2, 3],
+                   });
+    }
+
+    /// Emits `repr`, parses it back and checks that emitting the result gives the same bytes
+    fn segment_round_trip(repr: Repr) {
+        let src = ipv4::Address::from_bytes(&[10, 0, 0, 1]);
+        let dst = ipv4::Address::from_bytes(&[10, 0, 0, 2]);
+        let mut buffer = vec![0; repr.header_len() + repr.payload.len()];
+        repr.emit(&mut Packet::new_unchecked(&mut buffer[..]), &src, &dst, None).unwrap();
+
+        let packet = Packet::new(&buffer[..]).unwrap();
+        let parsed = Repr::parse(&packet, &src, &dst, None).unwrap();
+        assert_eq!((parsed.src_port, parsed.dst_port), (repr.src_port, repr.dst_port));
+        assert_eq!((parsed.seq, parsed.ack_num), (repr.seq, repr.ack_num));
+        assert_eq!(parsed.flags, repr.flags);
+        assert_eq!((parsed.window, parsed.urgent), (repr.window, repr.urgent));
+        assert_eq!(parsed.options, repr.options);
+        assert_eq!(parsed.payload, repr.payload);
+
+        let mut emitted = vec![0; parsed.header_len() + parsed.payload.len()];
+        parsed.emit(&mut Packet::new_unchecked(&mut emitted[..]), &src, &dst, None).unwrap();
+        assert_eq!(emitted, buffer);
+    }
+
+    fn segment<'a>(flags: Flags, urgent: u16, payload: &'a [u8]) -> Repr<'a> {
+        Repr {
+            src_port: 1000,
+            dst_port: 80,
+            seq: 0x01020304,
+            ack_num: if flags.ack() { 0x05060708 } else { 0 },
+            flags,
+            window: 512,
+            urgent,
+            options: Vec::new(),
+            payload,
+        }
+    }
+
+    #[test]
+    fn syn_fin_round_trip() {
+        let mut repr = segment(Flags::SYN | Flags::FIN, 0, &[]);
+        repr.options = vec![TcpOption::Mss(1460), TcpOption::Nop, TcpOption::WindowScale(7)];
+        segment_round_trip(repr);
+    }
+
+    #[test]
+    fn fin_psh_data_round_trip() {
+        segment_round_trip(segment(Flags::FIN | Flags::PSH | Flags::ACK, 0, b"last words"));
+    }
+
+    #[test]
+    fn urgent_round_trip() {
+        segment_round_trip(segment(Flags::URG | Flags::ACK, 3, b"abcdef"));
+        // The urgent pointer is carried even when URG isn't set
+        segment_round_trip(segment(Flags::ACK, 0xbeef, b"x"));
+    }
+
+    #[test]
+    fn ecn_round_trip() {
+        segment_round_trip(segment(Flags::SYN | Flags::ECE | Flags::CWR, 0, &[]));
+        segment_round_trip(segment(Flags::ECE | Flags::ACK, 0, &[]));
+        segment_round_trip(segment(Flags::CWR | Flags::NS | Flags::ACK, 0, b"data"));
+    }
+
+    #[test]
+    fn sack_round_trip() {
+        let mut blocks = [None; 4];
+        for count in 0..4 {
+            blocks[count] = Some((1000 * count as u32, 1000 * count as u32 + 500));
+            round_trip(TcpOption::Sack(blocks));
+        }
+    }
+
+    #[test]
+    fn mptcp_round_trip() {
+        round_trip(TcpOption::Mptcp(MptcpOption::Capable {
+                                        version: 1,
+                                        flags: 0x01,
+                                        sender_key: Some(0x0102030405060708),
+                                        receiver_key: Some(0x1112131415161718),
+                                    }));
+        round_trip(TcpOption::Mptcp(MptcpOption::JoinSyn {
+                                        backup: true,
+                                        address_id: 3,
+                                        token: 0xdeadbeef,
+                                        nonce: 42,
+                                    }));
+        round_trip(TcpOption::Mptcp(MptcpOption::JoinSynAck {
+                                        backup: false,
+                                        address_id: 0,
+                                        hmac: 0x0102030405060708,
+                                        nonce: 7,
+                                    }));
+        round_trip(TcpOption::Mptcp(MptcpOption::JoinAck { hmac: [0x33; JOIN_ACK_HMAC_LEN] }));
+        round_trip(TcpOption::Mptcp(MptcpOption::AddAddr {
+                                        echo: false,
+                                        address_id: 2,
+                                        addr: ipv4::Address::from_bytes(&[10, 0, 0, 2]),
+                                        port: Some(8080),
+                                        hmac: Some(0x0102030405060708),
+                                    }));
+        round_trip(TcpOption::Mptcp(MptcpOption::Dss {
+                                        data_ack: Some(DataSeq::Low(7)),
+                                        mapping: Some(Mapping {
+                                                          data_seq: DataSeq::Full(1 << 40),
+                                                          subflow_seq: 1,
+                                                          len: 1400,
+                                                      }),
+                                        data_fin: false,
+                                    }));
+    }
+
+    #[test]
+    fn md5_signature() {
+        let src = ipv4::Address::from_bytes(&[10, 0, 0, 1]);
+        let dst = ipv4::Address::from_bytes(&[10, 0, 0, 2]);
+        let payload = b"hello";
+        let repr = Repr {
+            src_port: 1000,
+            dst_port: 179,
+            seq: 0x01020304,
+            ack_num: 0x05060708,
+            flags: Flags::ACK | Flags::PSH,
+            window: 1024,
+            urgent: 0,
+            options: vec![TcpOption::Timestamps { tsval: 1, tsecr: 2 },
+                          TcpOption::Md5Signature([0; 16])],
+            payload,
+        };
+        let mut buffer = vec![0; repr.header_len() + payload.len()];
+        repr.emit(&mut Packet::new_unchecked(&mut buffer[..]),
+                  &src,
+                  &dst,
+                  Some(SigningKey::Md5(b"secret")))
+            .unwrap();
+
+        // Pseudo-header, header without options and with a zero checksum, data and key,
+        // in that order (RFC 2385 section 2.0)
+        let mut expected = Md5::new();
+        expected.update([10, 0, 0, 1, 10, 0, 0, 2, 0, 6]);
+        expected.update([0, buffer.len() as u8]);
+        expected.update(&buffer[..16]);
+        expected.update([0, 0]);
+        expected.update(&buffer[18..20]);
+        expected.update(payload);
+        expected.update(b"secret");
+        let signature = buffer[repr.header_len() - 16..repr.header_len()].to_vec();
+        assert_eq!(signature, expected.finalize().to_vec());
+
+        let packet = Packet::new(&buffer[..]).unwrap();
+        let parsed = Repr::parse(&packet, &src, &dst, Some(SigningKey::Md5(b"secret")));
+        assert_eq!(parsed.unwrap().payload, payload);
+        let parsed = Repr::parse(&packet, &src, &dst, Some(SigningKey::Md5(b"wrong")));
+        assert!(matches!(parsed, Err(Error::Signature)));
+        assert!(matches!(Repr::parse(&packet, &src, &dst, None), Err(Error::Signature)));
+    }
+
+    #[test]
+    fn short_data_offset() {
+        // A data offset of 16 bytes would have the options end before they start
+        let mut buffer = [0; 24];
+        buffer[field::OFF_FLG.start] = 4 << 4;
+        assert!(matches!(Packet::new(&buffer[..]), Err(Error::Malformed)));
+        buffer[field::OFF_FLG.start] = 0;
+        assert!(matches!(Packet::new(&buffer[..]), Err(Error::Malformed)));
+        buffer[field::OFF_FLG.start] = 7 << 4;
+        assert!(matches!(Packet::new(&buffer[..]), Err(Error::Truncated)));
+        buffer[field::OFF_FLG.start] = 5 << 4;
+        assert_eq!(Packet::new(&buffer[..]).unwrap().option_iter().count(), 0);
+    }
+
+    #[test]
+    fn fit_options_sheds_sack_blocks_first() {
+        let blocks = [Some((1, 2)), Some((3, 4)), Some((5, 6)), Some((7, 8))];
+        let mut repr = Repr {
+            src_port: 1,
+            dst_port: 2,
+            seq: 0,
+            ack_num: 0,
+            flags: Flags::ACK,
+            window: 0,
+            urgent: 0,
+            options: vec![TcpOption::Timestamps { tsval: 1, tsecr: 2 },
+                          TcpOption::Sack(blocks),
+                          TcpOption::Md5Signature([0; 16])],
+            payload: &[],
+        };
+        assert!(repr.fit_options());
+        assert_eq!(repr.options[1], TcpOption::Sack([Some((1, 2)), None, None, None]));
+
+        repr.options.push(TcpOption::UserTimeout {
+                              minutes: false,
+                              timeout: 30,
+                          });
+        assert!(repr.fit_options());
+        assert!(repr.options.iter().all(|option| option.kind() != OPT_SACK));
+        assert!(repr.options.iter().any(|option| option.kind() == OPT_TIMESTAMPS));
+    }
+
+    #[test]
+    fn fit_options_keeps_timestamps() {
+        let mut repr = Repr {
+            src_port: 1,
+            dst_port: 2,
+            seq: 0,
+            ack_num: 0,
+            flags: Flags::ACK,
+            window: 0,
+            urgent: 0,
+            options: vec![TcpOption::Timestamps { tsval: 1, tsecr: 2 },
+                          TcpOption::UserTimeout {
+                              minutes: false,
+                              timeout: 30,
+                          },
+                          TcpOption::Md5Signature([0; 16]),
+                          TcpOption::Unknown {
+                              kind: 253,
+                              data: &[0; 11],
+                          }],
+            payload: &[],
+        };
+        assert!(!repr.fit_options());
+        assert_eq!(repr.options.len(), 3);
+        assert_eq!(repr.timestamps(), Some((1, 2)));
     }
 }
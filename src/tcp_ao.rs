@@ -0,0 +1,189 @@
+use super::tcp;
+
+use aes::Aes128;
+use byteorder::{ByteOrder, NetworkEndian};
+use cmac::Cmac;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+/// Length of the truncated MACs of both algorithms (RFC 5926 section 3.2)
+pub const MAC_LEN: usize = 12;
+
+const KDF_LABEL: &[u8] = b"TCP-AO";
+
+/// MAC and key derivation algorithm of a master key (RFC 5926)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    /// HMAC-SHA-1-96 with KDF_HMAC_SHA1
+    HmacSha1,
+    /// AES-128-CMAC-96 with KDF_AES_128_CMAC
+    AesCmac,
+}
+
+/// Master Key Tuple (RFC 5925 section 3.1) without its connection identifier
+///
+/// TCP options are always included in the MAC.
+#[derive(Clone, Debug)]
+pub struct MasterKey {
+    /// KeyID sent in segments signed with this key
+    pub send_id: u8,
+    /// KeyID expected in segments the peer signed with this key
+    pub recv_id: u8,
+    pub algorithm: Algorithm,
+    pub key: Vec<u8>,
+}
+
+impl MasterKey {
+    pub fn new(send_id: u8, recv_id: u8, algorithm: Algorithm, key: &[u8]) -> Self {
+        MasterKey {
+            send_id,
+            recv_id,
+            algorithm,
+            key: key.to_vec(),
+        }
+    }
+}
+
+/// Key computing the MACs of one direction of a connection
+#[derive(Clone, Debug)]
+pub struct TrafficKey {
+    algorithm: Algorithm,
+    key: Vec<u8>,
+}
+
+impl TrafficKey {
+    /// Derives the traffic key for segments from `src` to `dst` (RFC 5926 section 3.1.1)
+    ///
+    /// SYN segments use a `dst_isn` of zero since the receiver's ISN is not known yet.
+    pub fn derive(master: &MasterKey,
+                  src: tcp::Endpoint,
+                  dst: tcp::Endpoint,
+                  src_isn: u32,
+                  dst_isn: u32)
+                  -> Self {
+        let output_bits: u16 = match master.algorithm {
+            Algorithm::HmacSha1 => 160,
+            Algorithm::AesCmac => 128,
+        };
+
+        // i || Label || Context || Output_Length
+        let mut input = [0; 1 + 6 + 20 + 2];
+        input[0] = 1;
+        input[1..7].copy_from_slice(KDF_LABEL);
+        input[7..11].copy_from_slice(src.addr.as_bytes());
+        input[11..15].copy_from_slice(dst.addr.as_bytes());
+        NetworkEndian::write_u16(&mut input[15..17], src.port);
+        NetworkEndian::write_u16(&mut input[17..19], dst.port);
+        NetworkEndian::write_u32(&mut input[19..23], src_isn);
+        NetworkEndian::write_u32(&mut input[23..27], dst_isn);
+        NetworkEndian::write_u16(&mut input[27..29], output_bits);
+
+        let key = match master.algorithm {
+            Algorithm::HmacSha1 => hmac_sha1(&master.key, &[&input]),
+            Algorithm::AesCmac => aes_cmac_prf(&master.key, &[&input]),
+        };
+
+        TrafficKey {
+            algorithm: master.algorithm,
+            key,
+        }
+    }
+
+    /// Truncated MAC over the concatenation of `data`
+    pub fn mac(&self, data: &[&[u8]]) -> [u8; MAC_LEN] {
+        let full = match self.algorithm {
+            Algorithm::HmacSha1 => hmac_sha1(&self.key, data),
+            Algorithm::AesCmac => aes_cmac(&self.key, data),
+        };
+        let mut mac = [0; MAC_LEN];
+        mac.copy_from_slice(&full[..MAC_LEN]);
+        mac
+    }
+}
+
+fn hmac_sha1(key: &[u8], data: &[&[u8]]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    for part in data {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// AES-CMAC-PRF-128 (RFC 4615), condensing keys of other lengths than 128 bits first
+fn aes_cmac_prf(key: &[u8], data: &[&[u8]]) -> Vec<u8> {
+    if key.len() == 16 {
+        aes_cmac(key, data)
+    } else {
+        aes_cmac(&aes_cmac(&[0; 16], &[key]), data)
+    }
+}
+
+fn aes_cmac(key: &[u8], data: &[&[u8]]) -> Vec<u8> {
+    let mut mac = Cmac::<Aes128>::new_from_slice(key).expect("AES-128 key of 16 bytes");
+    for part in data {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ipv4;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn endpoints() -> (tcp::Endpoint, tcp::Endpoint) {
+        (tcp::Endpoint::new(ipv4::Address::from_bytes(&[10, 0, 0, 1]), 1000),
+         tcp::Endpoint::new(ipv4::Address::from_bytes(&[10, 0, 0, 2]), 179))
+    }
+
+    #[test]
+    fn hmac_sha1_rfc2202() {
+        assert_eq!(hmac_sha1(&[0x0b; 20], &[b"Hi", b" There"]),
+                   hex("b617318655057264e28bc0b6fb378c8ef146be00"));
+    }
+
+    #[test]
+    fn aes_cmac_rfc4493() {
+        let key = hex("2b7e151628aed2a6abf7158809cf4f3c");
+        assert_eq!(aes_cmac(&key, &[]), hex("bb1d6929e95937287fa37d129b756746"));
+        assert_eq!(aes_cmac(&key, &[&hex("6bc1bee22e409f96e93d7e117393172a")]),
+                   hex("070a16b46b4d4144f79bdd9dd04a287c"));
+    }
+
+    #[test]
+    fn aes_cmac_prf_rfc4615() {
+        let message: Vec<u8> = (0..20).collect();
+        let key: Vec<u8> = (0..16).collect();
+        assert_eq!(aes_cmac_prf(&key, &[&message]), hex("980ae87b5f4c9c5214f5b6a8455e4c2d"));
+        let key: Vec<u8> = (0..10).collect();
+        assert_eq!(aes_cmac_prf(&key, &[&message]), hex("290d9e112edb09ee141fcf64c0b72f3d"));
+    }
+
+    #[test]
+    fn traffic_keys_and_macs() {
+        let (src, dst) = endpoints();
+        // Over i || Label || Context || Output_Length (RFC 5926 section 3.1.1)
+        let master = MasterKey::new(1, 1, Algorithm::HmacSha1, b"testvector");
+        let key = TrafficKey::derive(&master, src, dst, 0x11223344, 0x55667788);
+        assert_eq!(key.key, hex("c923a62294fadcff39de27e1b9a0da52126e0625"));
+        assert_eq!(key.mac(&[b"seg", b"ment"]).to_vec(), hex("bcd1d8863e39108796d109f3"));
+
+        let master = MasterKey::new(1, 1, Algorithm::AesCmac, b"testvector");
+        let key = TrafficKey::derive(&master, src, dst, 0x11223344, 0x55667788);
+        assert_eq!(key.key, hex("512d45fd1a07b54ad8383f49bddb1b18"));
+        assert_eq!(key.mac(&[b"segment"]).to_vec(), hex("b92b9afc1f9e148a0d3917ba"));
+
+        // Each direction and each pair of ISNs has its own key
+        let reverse = TrafficKey::derive(&master, dst, src, 0x55667788, 0x11223344);
+        assert_ne!(reverse.key, key.key);
+        let syn = TrafficKey::derive(&master, src, dst, 0x11223344, 0);
+        assert_ne!(syn.key, key.key);
+    }
+}